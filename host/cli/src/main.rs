@@ -0,0 +1,317 @@
+//! Talks to a running kernel over its second serial port.
+//!
+//! QEMU has to expose COM2 as a unix socket or a pipe, e.g.
+//!
+//! ```text
+//! -serial stdio -serial unix:/tmp/barefuzz.sock,server,nowait
+//! -serial stdio -serial pipe:/tmp/barefuzz     (with /tmp/barefuzz.in and .out made by mkfifo)
+//! ```
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use barefuzz_protocol::{crash_kind, CrashReport, Decoder, Message};
+
+const USAGE: &str = "\
+usage: barefuzz-host <connection> <command> [args...]
+
+connections:
+    unix:PATH           a unix socket, as made by QEMU's -serial unix:PATH,server
+    pipe:PATH           PATH.in and PATH.out, as used by QEMU's -serial pipe:PATH
+    PATH                anything that can be opened for reading and writing, like a pty
+
+commands:
+    ping                check that the kernel is listening
+    stats               print fuzzing statistics
+    seed FILE...        add inputs to the corpus
+    token TOKEN...      add tokens to the mutators' dictionaries
+    dict FILE...        add the tokens in AFL-style .dict files to the dictionaries
+    run FILE [ARG...]   start a static x86_64 ELF executable in user mode, with FILE as argv[0]
+    crashes DIR         save the inputs for every crash bucket to DIR
+    corpus DIR [FROM]   save the corpus, from entry FROM on, to DIR
+    watch DIR           save crashes to DIR as they are found, until interrupted";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+struct Link {
+    reader: BufReader<Box<dyn Read>>,
+    writer: Box<dyn Write>,
+    decoder: Decoder,
+    /// Where to save crashes the kernel reports while we're waiting for something else.
+    crash_dir: Option<PathBuf>,
+}
+
+impl Link {
+    fn open(connection: &str) -> Result<Self> {
+        let (reader, writer): (Box<dyn Read>, Box<dyn Write>) =
+            if let Some(path) = connection.strip_prefix("unix:") {
+                let stream = UnixStream::connect(path)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            } else if let Some(path) = connection.strip_prefix("pipe:") {
+                // QEMU reads from .in and writes to .out
+                let writer = OpenOptions::new()
+                    .write(true)
+                    .open(format!("{}.in", path))?;
+                let reader = File::open(format!("{}.out", path))?;
+                (Box::new(reader), Box::new(writer))
+            } else {
+                let file = OpenOptions::new().read(true).write(true).open(connection)?;
+                (Box::new(file.try_clone()?), Box::new(file))
+            };
+
+        Ok(Self::new(reader, writer))
+    }
+
+    fn new(reader: Box<dyn Read>, writer: Box<dyn Write>) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+            decoder: Decoder::new(),
+            crash_dir: None,
+        }
+    }
+
+    fn send(&mut self, message: &Message) -> Result<()> {
+        self.writer.write_all(&message.encode())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Waits for the next message, skipping frames that didn't make it through intact.
+    fn receive_any(&mut self) -> Result<Message> {
+        let mut byte = [0];
+        loop {
+            if self.reader.read(&mut byte)? == 0 {
+                return Err("connection closed".into());
+            }
+            match self.decoder.push(byte[0]) {
+                Some(Ok(message)) => return Ok(message),
+                Some(Err(error)) => eprintln!("dropped a frame: {}", error),
+                None => {}
+            }
+        }
+    }
+
+    /// Waits for the next reply, handling any crashes the kernel reports in the meantime.
+    fn receive(&mut self) -> Result<Message> {
+        loop {
+            match self.receive_any()? {
+                Message::Crash(crash) => self.new_crash(&crash)?,
+                Message::Error(error) => return Err(format!("kernel: {}", error).into()),
+                message => return Ok(message),
+            }
+        }
+    }
+
+    fn new_crash(&mut self, crash: &CrashReport) -> Result<()> {
+        print_crash(crash);
+        if let Some(dir) = &self.crash_dir {
+            save_crash(dir, crash)?;
+        }
+        Ok(())
+    }
+}
+
+fn print_crash(crash: &CrashReport) {
+    println!(
+        "crash {:016x}: {} at {:#x}, {} hits, {} byte input",
+        crash.signature,
+        crash_kind::name(crash.kind),
+        crash.pc,
+        crash.hits,
+        crash.input.len()
+    );
+    if crash.kind == crash_kind::PAGE_FAULT {
+        println!(
+            "    accessing {:#x}, error code {:#x}",
+            crash.address, crash.error_code
+        );
+    }
+    if !crash.message.is_empty() {
+        println!("    {}", crash.message);
+    }
+    for (depth, frame) in crash.frames.iter().enumerate() {
+        println!("    #{:<2} {:#x}", depth, frame);
+    }
+}
+
+fn save_crash(dir: &Path, crash: &CrashReport) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("crash-{:016x}", crash.signature));
+    fs::write(&path, &crash.input)?;
+    println!("    saved to {}", path.display());
+    if !crash.reproducer.is_empty() {
+        let path = path.with_extension("min");
+        fs::write(&path, &crash.reproducer)?;
+        println!(
+            "    minimised to {} bytes in {}",
+            crash.reproducer.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn expect_done(link: &mut Link, mut each: impl FnMut(Message) -> Result<()>) -> Result<()> {
+    loop {
+        match link.receive()? {
+            Message::Done => return Ok(()),
+            message => each(message)?,
+        }
+    }
+}
+
+/// Saves the crashes the kernel sends in reply to `GetCrashes` to `dir`, and returns how many
+/// there were.
+fn save_crashes(link: &mut Link, dir: &Path) -> Result<usize> {
+    link.crash_dir = Some(dir.to_path_buf());
+    let mut count = 0;
+    loop {
+        match link.receive_any()? {
+            Message::Crash(crash) => {
+                link.new_crash(&crash)?;
+                count += 1;
+            }
+            Message::Done => return Ok(count),
+            Message::Error(error) => return Err(format!("kernel: {}", error).into()),
+            message => return Err(format!("unexpected reply {:?}", message).into()),
+        }
+    }
+}
+
+fn run(connection: &str, command: &str, args: &[String]) -> Result<()> {
+    let mut link = Link::open(connection)?;
+    match (command, args) {
+        ("ping", []) => {
+            link.send(&Message::Ping)?;
+            match link.receive()? {
+                Message::Pong => println!("pong"),
+                message => return Err(format!("unexpected reply {:?}", message).into()),
+            }
+        }
+        ("stats", []) => {
+            link.send(&Message::GetStats)?;
+            match link.receive()? {
+                Message::Stats(stats) => {
+                    println!("execs:       {}", stats.execs);
+                    println!("corpus:      {}", stats.corpus_len);
+                    println!("edges:       {}", stats.edges);
+                    println!("crashes:     {}", stats.crashes);
+                    println!("crash hits:  {}", stats.crash_hits);
+                }
+                message => return Err(format!("unexpected reply {:?}", message).into()),
+            }
+        }
+        ("seed", files) if !files.is_empty() => {
+            for file in files {
+                link.send(&Message::AddSeed(fs::read(file)?))?;
+            }
+            println!("sent {} seeds", files.len());
+        }
+        ("token", tokens) if !tokens.is_empty() => {
+            for token in tokens {
+                link.send(&Message::AddToken(token.as_bytes().to_vec()))?;
+            }
+            println!("sent {} tokens", tokens.len());
+        }
+        ("dict", files) if !files.is_empty() => {
+            for file in files {
+                link.send(&Message::AddDictionary(fs::read(file)?))?;
+                match link
+                    .receive()
+                    .map_err(|error| format!("{}: {}", file, error))?
+                {
+                    Message::Done => println!("loaded {}", file),
+                    message => return Err(format!("unexpected reply {:?}", message).into()),
+                }
+            }
+        }
+        ("run", [file, ..]) => {
+            link.send(&Message::RunProgram {
+                image: fs::read(file)?,
+                args: args.to_vec(),
+            })?;
+            match link
+                .receive()
+                .map_err(|error| format!("{}: {}", file, error))?
+            {
+                Message::Done => println!("started {}", file),
+                message => return Err(format!("unexpected reply {:?}", message).into()),
+            }
+        }
+        ("crashes", [dir]) => {
+            link.send(&Message::GetCrashes)?;
+            let count = save_crashes(&mut link, Path::new(dir))?;
+            println!("{} crash buckets", count);
+        }
+        ("corpus", [dir, rest @ ..]) if rest.len() <= 1 => {
+            let from = match rest {
+                [from] => from.parse()?,
+                _ => 0,
+            };
+            fs::create_dir_all(dir)?;
+            link.send(&Message::GetCorpus { from })?;
+            let mut count = 0;
+            expect_done(&mut link, |message| {
+                if let Message::CorpusEntry { index, input } = message {
+                    fs::write(Path::new(dir).join(format!("id-{:06}", index)), input)?;
+                    count += 1;
+                }
+                Ok(())
+            })?;
+            println!("saved {} entries to {}", count, dir);
+        }
+        ("watch", [dir]) => {
+            link.crash_dir = Some(PathBuf::from(dir));
+            loop {
+                match link.receive_any()? {
+                    Message::Crash(crash) => link.new_crash(&crash)?,
+                    message => eprintln!("ignoring {:?}", message),
+                }
+            }
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    if let Err(error) = run(&args[0], &args[1], &args[2..]) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn crashes_get_saved() {
+        let crash = CrashReport {
+            signature: 0x1234,
+            input: b"boom".to_vec(),
+            ..CrashReport::default()
+        };
+        let mut frames = Message::Crash(crash).encode();
+        frames.extend(Message::Done.encode());
+        let mut link = Link::new(Box::new(Cursor::new(frames)), Box::new(io::sink()));
+
+        let dir = std::env::temp_dir().join(format!("barefuzz-crashes-{}", process::id()));
+        let count = save_crashes(&mut link, &dir).unwrap();
+        let saved = fs::read(dir.join("crash-0000000000001234"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(saved.unwrap(), b"boom");
+    }
+}
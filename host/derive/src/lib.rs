@@ -0,0 +1,116 @@
+//! `#[derive(Arbitrary)]` for the kernel's `fuzz::arbitrary::Arbitrary` trait.
+//!
+//! Structs build their fields in order.  Enums pick a variant with `Unstructured::choose_index`,
+//! then build its fields.  Type parameters get an `Arbitrary` bound.
+//!
+//! The generated code names the trait as `::barefuzz::fuzz::arbitrary`, so the kernel crate
+//! refers to itself as `barefuzz` to use the derive internally.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields};
+
+#[proc_macro_derive(Arbitrary)]
+pub fn derive_arbitrary(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream> {
+    let krate = quote!(::barefuzz::fuzz::arbitrary);
+    for param in input.generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::barefuzz::fuzz::arbitrary::Arbitrary));
+    }
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let construct = construct(quote!(Self), &data.fields);
+            quote!(::core::result::Result::Ok(#construct))
+        }
+        Data::Enum(data) => {
+            if data.variants.is_empty() {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "can't derive Arbitrary for an enum without variants",
+                ));
+            }
+            let count = data.variants.len();
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let name = &variant.ident;
+                let construct = construct(quote!(Self::#name), &variant.fields);
+                quote!(#index => #construct,)
+            });
+            quote! {
+                ::core::result::Result::Ok(match u.choose_index(#count)? {
+                    #(#arms)*
+                    _ => ::core::unreachable!(),
+                })
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span(),
+                "can't derive Arbitrary for a union",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::Arbitrary for #name #type_generics #where_clause {
+            fn arbitrary(u: &mut #krate::Unstructured) -> #krate::Result<Self> {
+                #body
+            }
+        }
+    })
+}
+
+/// An expression building `path` with every field read from `u`, in order.
+fn construct(path: TokenStream, fields: &Fields) -> TokenStream {
+    let value = quote!(::barefuzz::fuzz::arbitrary::Arbitrary::arbitrary(u)?);
+    match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #value),* })
+        }
+        Fields::Unnamed(fields) => {
+            let values = fields.unnamed.iter().map(|_| &value);
+            quote!(#path(#(#values),*))
+        }
+        Fields::Unit => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(input: &str) -> String {
+        match expand(syn::parse_str(input).unwrap()) {
+            Ok(tokens) => tokens.to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn expands_structs_and_enums() {
+        let expanded = expand_str("struct Pair<T> { a: T, b: u8 }");
+        assert!(expanded.contains("impl < T : :: barefuzz :: fuzz :: arbitrary :: Arbitrary >"));
+        assert!(expanded.contains("Self { a :"));
+
+        let expanded = expand_str("enum Op { Nop, Close(u8), Write { fd: u8 } }");
+        assert!(expanded.contains("choose_index (3usize)"));
+        assert!(expanded.contains("2usize => Self :: Write { fd :"));
+    }
+
+    #[test]
+    fn rejects_empty_enums() {
+        assert!(expand_str("enum Never {}").contains("without variants"));
+    }
+}
@@ -0,0 +1,507 @@
+//! The framed protocol spoken between the kernel and the host tool over the second serial port.
+//!
+//! Every message is sent as one frame:
+//!
+//! ```text
+//! magic "BF" | kind: u8 | length: u32 | payload: [u8; length] | crc32: u32
+//! ```
+//!
+//! Integers are little endian, and the CRC (IEEE, as used by zlib) covers the kind, length and
+//! payload.  Variable-length fields inside a payload are prefixed with their length as a `u32`.
+//!
+//! Both sides use this crate, so it's `no_std` and only needs `alloc`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const MAGIC: [u8; 2] = *b"BF";
+/// Magic, kind and length.
+pub const HEADER_LEN: usize = 7;
+pub const TRAILER_LEN: usize = 4;
+/// Frames claiming a longer payload than this are rejected rather than buffered.
+pub const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+/// Crash kinds, as sent in `CrashReport::kind`.
+pub mod crash_kind {
+    pub const PANIC: u8 = 0;
+    pub const DIVIDE_ERROR: u8 = 1;
+    pub const INVALID_OPCODE: u8 = 2;
+    pub const GENERAL_PROTECTION: u8 = 3;
+    pub const STACK_SEGMENT: u8 = 4;
+    pub const SEGMENT_NOT_PRESENT: u8 = 5;
+    pub const PAGE_FAULT: u8 = 6;
+    /// A guest program reported a crash itself, through the crash hypercall.
+    pub const GUEST: u8 = 7;
+
+    pub fn name(kind: u8) -> &'static str {
+        match kind {
+            PANIC => "panic",
+            DIVIDE_ERROR => "divide error",
+            INVALID_OPCODE => "invalid opcode",
+            GENERAL_PROTECTION => "general protection fault",
+            STACK_SEGMENT => "stack segment fault",
+            SEGMENT_NOT_PRESENT => "segment not present",
+            PAGE_FAULT => "page fault",
+            GUEST => "guest crash",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The payload ended before everything in it was read.
+    Truncated,
+    /// The payload had bytes left over after everything in it was read.
+    TrailingBytes,
+    TooLong(usize),
+    BadChecksum {
+        expected: u32,
+        actual: u32,
+    },
+    UnknownKind(u8),
+    BadUtf8,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "payload is truncated"),
+            Error::TrailingBytes => write!(f, "payload has trailing bytes"),
+            Error::TooLong(len) => write!(f, "payload of {} bytes is too long", len),
+            Error::BadChecksum { expected, actual } => write!(
+                f,
+                "bad checksum: expected {:#010x}, got {:#010x}",
+                expected, actual
+            ),
+            Error::UnknownKind(kind) => write!(f, "unknown message kind {:#04x}", kind),
+            Error::BadUtf8 => write!(f, "string isn't valid UTF-8"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub execs: u64,
+    pub corpus_len: u32,
+    pub edges: u32,
+    /// Unique crash buckets.
+    pub crashes: u32,
+    /// Crashes in all buckets, including duplicates.
+    pub crash_hits: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrashReport {
+    pub signature: u64,
+    /// One of `crash_kind`.
+    pub kind: u8,
+    /// The faulting address, for page faults.
+    pub address: u64,
+    pub error_code: u64,
+    pub pc: u64,
+    pub hits: u64,
+    pub frames: Vec<u64>,
+    pub message: String,
+    /// The first input that crashed this way.
+    pub input: Vec<u8>,
+    /// The minimised input, or nothing if it hasn't been minimised.
+    pub reproducer: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    // host to kernel
+    Ping,
+    /// An input to add to the corpus, if it reaches anything new.
+    AddSeed(Vec<u8>),
+    /// A token for the mutators' dictionaries.
+    AddToken(Vec<u8>),
+    GetStats,
+    /// Asks for a `Crash` for every bucket, followed by `Done`.
+    GetCrashes,
+    /// Asks for a `CorpusEntry` for every entry from index `from` on, followed by `Done`.
+    GetCorpus {
+        from: u32,
+    },
+    /// The contents of an AFL-style `.dict` file, whose tokens go into the mutators'
+    /// dictionaries.  Answered with `Done`, or `Error` if it doesn't parse.
+    AddDictionary(Vec<u8>),
+    /// A statically linked x86_64 ELF executable to start as a task in user mode, with `args` as
+    /// its whole `argv`.  Answered with `Done`, or `Error` if it can't be loaded.
+    RunProgram {
+        image: Vec<u8>,
+        args: Vec<String>,
+    },
+
+    // kernel to host
+    Pong,
+    Stats(Stats),
+    /// Sent for every new crash bucket as it's found, and in reply to `GetCrashes`.
+    Crash(CrashReport),
+    CorpusEntry {
+        index: u32,
+        input: Vec<u8>,
+    },
+    Done,
+    /// A message from the host couldn't be handled.
+    Error(String),
+}
+
+impl Message {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::Ping => 0x01,
+            Message::AddSeed(_) => 0x02,
+            Message::AddToken(_) => 0x03,
+            Message::GetStats => 0x04,
+            Message::GetCrashes => 0x05,
+            Message::GetCorpus { .. } => 0x06,
+            Message::AddDictionary(_) => 0x07,
+            Message::RunProgram { .. } => 0x08,
+            Message::Pong => 0x81,
+            Message::Stats(_) => 0x82,
+            Message::Crash(_) => 0x83,
+            Message::CorpusEntry { .. } => 0x84,
+            Message::Done => 0x85,
+            Message::Error(_) => 0x86,
+        }
+    }
+
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Ping
+            | Message::GetStats
+            | Message::GetCrashes
+            | Message::Pong
+            | Message::Done => {}
+            Message::AddSeed(bytes) | Message::AddToken(bytes) | Message::AddDictionary(bytes) => {
+                put_bytes(out, bytes)
+            }
+            Message::GetCorpus { from } => put_u32(out, *from),
+            Message::RunProgram { image, args } => {
+                put_bytes(out, image);
+                put_u32(out, args.len() as u32);
+                for arg in args {
+                    put_bytes(out, arg.as_bytes());
+                }
+            }
+            Message::Stats(stats) => {
+                put_u64(out, stats.execs);
+                put_u32(out, stats.corpus_len);
+                put_u32(out, stats.edges);
+                put_u32(out, stats.crashes);
+                put_u64(out, stats.crash_hits);
+            }
+            Message::Crash(crash) => {
+                put_u64(out, crash.signature);
+                out.push(crash.kind);
+                put_u64(out, crash.address);
+                put_u64(out, crash.error_code);
+                put_u64(out, crash.pc);
+                put_u64(out, crash.hits);
+                put_u32(out, crash.frames.len() as u32);
+                for &frame in &crash.frames {
+                    put_u64(out, frame);
+                }
+                put_bytes(out, crash.message.as_bytes());
+                put_bytes(out, &crash.input);
+                put_bytes(out, &crash.reproducer);
+            }
+            Message::CorpusEntry { index, input } => {
+                put_u32(out, *index);
+                put_bytes(out, input);
+            }
+            Message::Error(message) => put_bytes(out, message.as_bytes()),
+        }
+    }
+
+    fn decode_payload(kind: u8, payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader(payload);
+        let message = match kind {
+            0x01 => Message::Ping,
+            0x02 => Message::AddSeed(reader.bytes()?),
+            0x03 => Message::AddToken(reader.bytes()?),
+            0x04 => Message::GetStats,
+            0x05 => Message::GetCrashes,
+            0x06 => Message::GetCorpus {
+                from: reader.u32()?,
+            },
+            0x07 => Message::AddDictionary(reader.bytes()?),
+            0x08 => {
+                let image = reader.bytes()?;
+                let count = reader.u32()? as usize;
+                // don't trust the count for the allocation
+                let mut args = Vec::with_capacity(count.min(reader.0.len() / 4));
+                for _ in 0..count {
+                    args.push(reader.string()?);
+                }
+                Message::RunProgram { image, args }
+            }
+            0x81 => Message::Pong,
+            0x82 => Message::Stats(Stats {
+                execs: reader.u64()?,
+                corpus_len: reader.u32()?,
+                edges: reader.u32()?,
+                crashes: reader.u32()?,
+                crash_hits: reader.u64()?,
+            }),
+            0x83 => {
+                let mut crash = CrashReport {
+                    signature: reader.u64()?,
+                    kind: reader.u8()?,
+                    address: reader.u64()?,
+                    error_code: reader.u64()?,
+                    pc: reader.u64()?,
+                    hits: reader.u64()?,
+                    ..CrashReport::default()
+                };
+                let frames = reader.u32()? as usize;
+                // don't trust the count for the allocation
+                crash.frames = Vec::with_capacity(frames.min(reader.0.len() / 8));
+                for _ in 0..frames {
+                    crash.frames.push(reader.u64()?);
+                }
+                crash.message = reader.string()?;
+                crash.input = reader.bytes()?;
+                crash.reproducer = reader.bytes()?;
+                Message::Crash(crash)
+            }
+            0x84 => Message::CorpusEntry {
+                index: reader.u32()?,
+                input: reader.bytes()?,
+            },
+            0x85 => Message::Done,
+            0x86 => Message::Error(reader.string()?),
+            _ => return Err(Error::UnknownKind(kind)),
+        };
+        if !reader.0.is_empty() {
+            return Err(Error::TrailingBytes);
+        }
+        Ok(message)
+    }
+
+    /// Encodes the message as a complete frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::from(MAGIC);
+        frame.push(self.kind());
+        put_u32(&mut frame, 0);
+        self.encode_payload(&mut frame);
+
+        let len = (frame.len() - HEADER_LEN) as u32;
+        frame[3..HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32(&frame[MAGIC.len()..]);
+        put_u32(&mut frame, crc);
+        frame
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::BadUtf8)
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Reassembles frames from a byte stream, one byte at a time.
+///
+/// Bytes that can't start a frame are skipped, so a decoder that starts listening in the middle of
+/// a frame, or after garbage on the line, picks up again at the next one.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn payload_len(&self) -> usize {
+        u32::from_le_bytes(self.buf[3..HEADER_LEN].try_into().unwrap()) as usize
+    }
+
+    /// Feeds in the next byte.  Returns a message, or why a frame was rejected, once a whole frame
+    /// has been received.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Message, Error>> {
+        if self.buf.len() < MAGIC.len() && byte != MAGIC[self.buf.len()] {
+            self.buf.clear();
+            // the byte that broke the magic may start the next one
+            if byte == MAGIC[0] {
+                self.buf.push(byte);
+            }
+            return None;
+        }
+        self.buf.push(byte);
+
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+        let len = self.payload_len();
+        if len > MAX_PAYLOAD_LEN {
+            self.buf.clear();
+            return Some(Err(Error::TooLong(len)));
+        }
+        if self.buf.len() < HEADER_LEN + len + TRAILER_LEN {
+            return None;
+        }
+
+        let (body, trailer) = self.buf[MAGIC.len()..].split_at(HEADER_LEN - MAGIC.len() + len);
+        let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+        let actual = crc32(body);
+        let result = if expected != actual {
+            Err(Error::BadChecksum { expected, actual })
+        } else {
+            Message::decode_payload(self.buf[2], &body[HEADER_LEN - MAGIC.len()..])
+        };
+        self.buf.clear();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn decode_all(bytes: &[u8]) -> Vec<Result<Message, Error>> {
+        let mut decoder = Decoder::new();
+        bytes.iter().filter_map(|&b| decoder.push(b)).collect()
+    }
+
+    #[test]
+    fn crc_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            Message::Ping,
+            Message::AddSeed(b"seed".to_vec()),
+            Message::GetCorpus { from: 7 },
+            Message::AddDictionary(b"magic=\"MAGIC\"\n".to_vec()),
+            Message::RunProgram {
+                image: b"\x7fELF".to_vec(),
+                args: vec![String::from("target"), String::from("-v")],
+            },
+            Message::Stats(Stats {
+                execs: 1 << 40,
+                corpus_len: 3,
+                edges: 99,
+                crashes: 1,
+                crash_hits: 12,
+            }),
+            Message::Crash(CrashReport {
+                signature: 0xdead_beef,
+                kind: crash_kind::PAGE_FAULT,
+                address: 0x1000,
+                error_code: 2,
+                pc: 0x20_1234,
+                hits: 1,
+                frames: vec![0x20_1235, 0x20_5678],
+                message: String::from("oops"),
+                input: vec![0, 1, 2],
+                reproducer: vec![1],
+            }),
+            Message::Error(String::from("no")),
+        ];
+        let stream: Vec<u8> = messages.iter().flat_map(Message::encode).collect();
+        let decoded: Vec<_> = decode_all(&stream)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn garbage_between_frames_is_skipped() {
+        let mut stream = b"BBoot messages\n".to_vec();
+        stream.extend(Message::Pong.encode());
+        stream.extend(b"B");
+        stream.extend(Message::Done.encode());
+        let decoded = decode_all(&stream);
+        assert_eq!(decoded, vec![Ok(Message::Pong), Ok(Message::Done)]);
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let mut frame = Message::AddToken(b"token".to_vec()).encode();
+        frame[HEADER_LEN + 4] ^= 1;
+        assert!(matches!(
+            decode_all(&frame)[..],
+            [Err(Error::BadChecksum { .. })]
+        ));
+    }
+}
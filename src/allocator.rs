@@ -1,57 +1,214 @@
 use crate::concurrency::mutex::{Mutex, MutexGuard};
+use crate::memory::mapping::{self, Frames};
+use crate::memory::{vma, KernelMemory, KERNEL_MEMORY};
 use crate::{println_immediate, LOCKS};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use fixed_size_block::FixedSizeBlockAllocator;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
 pub mod bump;
+pub mod fault_injection;
 pub mod fixed_size_block;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod linked_list;
+pub mod slab;
 
-pub const HEAP_START: *mut u8 = 0x_4444_4444_0000 as *mut u8;
-pub const HEAP_SIZE: usize = 4096 * 800; // 800 pages seems reasonable
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-slab"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-slab"),
+    all(feature = "alloc-fixed-block", feature = "alloc-slab"),
+))]
+compile_error!("only one of the alloc-* features can be enabled;  use --no-default-features to switch away from alloc-fixed-block");
+
+/// The allocator backing the kernel heap, picked with the `alloc-*` features.
+#[cfg(feature = "alloc-bump")]
+pub type HeapAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+pub type HeapAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+pub type HeapAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-slab")]
+pub type HeapAllocator = slab::SlabAllocator;
+
+/// 1 GiB aligned, so that the heap can be mapped with large pages.
+pub const HEAP_START: *mut u8 = 0x_4444_4000_0000 as *mut u8;
+/// Two 2 MiB pages, so that every chunk the heap grows by starts on a large page boundary.
+pub const HEAP_SIZE: usize = 2 * HEAP_GROWTH_STEP;
+/// Default ceiling for how far the heap may grow past `HEAP_SIZE`.  The whole range is reserved
+/// with the VMA manager.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+/// The heap is grown by multiples of this, so that lots of small allocations don't each have to go
+/// through the page tables, and so that it can be mapped with large pages.  One 2 MiB page.
+const HEAP_GROWTH_STEP: usize = 2 * 1024 * 1024;
+
+/// How many bytes starting at `HEAP_START` are currently mapped.
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+pub static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new());
 
 #[global_allocator]
-pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        };
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+/// Maps the initial heap and hands it to `ALLOCATOR`.
+///
+/// Requires `memory::init_kernel_memory` to have been called, since the same mapper and frame
+/// allocator are used later to grow the heap.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    {
+        let mut memory = KERNEL_MEMORY
+            .get()
+            .expect("kernel memory not initialised")
+            .lock_without_interrupts();
+        let mapped = map_heap_pages(&mut memory, 0, HEAP_SIZE)?;
+        if mapped < HEAP_SIZE {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        #[cfg(feature = "kasan")]
+        kasan::map_shadow(&mut memory, HEAP_SIZE)?;
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START as usize, HEAP_SIZE);
     }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
 
     LOCKS.lock().push(&ALLOCATOR.inner.semaphore);
+    vma::reserve(VirtAddr::new(HEAP_START as u64), HEAP_MAX_SIZE as u64, "heap")
+        .expect("heap overlaps another region");
     println_immediate!("Initialised heap");
 
     Ok(())
 }
 
+/// Maps `size` bytes of heap starting `offset` bytes past `HEAP_START`, with large pages where
+/// possible.
+///
+/// Returns how many bytes were mapped before running out of frames, which may be less than `size`.
+fn map_heap_pages(
+    memory: &mut KernelMemory,
+    offset: usize,
+    size: usize,
+) -> Result<usize, MapToError<Size4KiB>> {
+    let start = VirtAddr::new(HEAP_START as u64 + offset as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    match mapping::map_range(memory, start, size as u64, Frames::Fresh, flags)? {
+        0 => Err(MapToError::FrameAllocationFailed),
+        mapped => Ok(mapped as usize),
+    }
+}
+
+/// The number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::SeqCst)
+}
+
+/// Sets how large the heap may grow, in bytes.  Can't be used to shrink an already mapped heap, or
+/// to grow it past `HEAP_MAX_SIZE`, which is all the address space it has.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.clamp(heap_size(), HEAP_MAX_SIZE), Ordering::SeqCst);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::SeqCst)
+}
+
+/// Extends the heap so that an allocation of `layout` has a chance to succeed.
+///
+/// `observed_size` is the heap size the failed allocation saw;  if someone else has grown the heap
+/// since then, this just returns `true` so that the allocation is retried first.
+fn grow_heap(layout: Layout, observed_size: usize) -> bool {
+    without_interrupts(|| {
+        let mut memory = match KERNEL_MEMORY.get() {
+            Some(memory) => memory.lock_without_interrupts(),
+            None => return false,
+        };
+        let mapped = HEAP_MAPPED.load(Ordering::SeqCst);
+        if mapped != observed_size {
+            return true;
+        }
+
+        let available = HEAP_LIMIT.load(Ordering::SeqCst).saturating_sub(mapped);
+        let required = match layout.size().checked_add(layout.align()) {
+            Some(required) => align_up(required, 4096),
+            None => return false,
+        };
+        if required > available {
+            return false;
+        }
+        let grow_by = align_up(required, HEAP_GROWTH_STEP).min(available);
+
+        let grown = match map_heap_pages(&mut memory, mapped, grow_by) {
+            Ok(grown) => grown,
+            Err(_) => return false,
+        };
+        #[cfg(feature = "kasan")]
+        if kasan::map_shadow(&mut memory, mapped + grown).is_err() {
+            // leave the heap as it was, so that the next try starts from the same place
+            let start = VirtAddr::new(HEAP_START as u64 + mapped as u64);
+            mapping::unmap_range(&mut memory, start, grown as u64, true).ok();
+            return false;
+        }
+
+        unsafe {
+            ALLOCATOR.lock().extend(grown);
+        }
+        HEAP_MAPPED.store(mapped + grown, Ordering::SeqCst);
+        true
+    })
+}
+
+/// Allocates from `ALLOCATOR`, mapping more heap pages when it runs out instead of failing straight
+/// away.  Only fails once the heap has reached `heap_limit()` or physical memory is exhausted.
+unsafe fn backend_alloc(layout: Layout) -> *mut u8 {
+    loop {
+        let observed_size = heap_size();
+        let ptr = ALLOCATOR.alloc(layout);
+        if !ptr.is_null() || !grow_heap(layout, observed_size) {
+            return ptr;
+        }
+    }
+}
+
+unsafe fn backend_dealloc(ptr: *mut u8, layout: Layout) {
+    ALLOCATOR.dealloc(ptr, layout)
+}
+
+/// The kernel's `#[global_allocator]`.
+///
+/// Fails allocations picked by `fault_injection`, then goes through the address sanitizer when
+/// the `kasan` feature is on, and otherwise straight to `backend_alloc`.
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault_injection::should_fail(&layout) {
+            return null_mut();
+        }
+
+        #[cfg(feature = "kasan")]
+        return kasan::alloc(layout);
+        #[cfg(not(feature = "kasan"))]
+        backend_alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        return kasan::dealloc(ptr, layout);
+        #[cfg(not(feature = "kasan"))]
+        backend_dealloc(ptr, layout)
+    }
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -76,8 +233,10 @@ impl<A> Locked<A> {
         }
     }
 
+    /// Locks the allocator, with interrupts off while it's locked, since handlers for interrupts
+    /// from user mode allocate.
     pub fn lock(&self) -> MutexGuard<A> {
-        self.inner.lock()
+        self.inner.lock_without_interrupts()
     }
 }
 
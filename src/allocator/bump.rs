@@ -29,6 +29,13 @@ impl BumpAllocator {
         self.heap_end = heap_start.saturating_add(heap_size);
         self.next = heap_start;
     }
+
+    /// Extends the heap by `by` bytes, directly after its current end.
+    ///
+    /// The caller must guarantee that the memory is mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.heap_end = self.heap_end.saturating_add(by);
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
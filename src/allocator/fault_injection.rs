@@ -0,0 +1,225 @@
+//! Makes heap allocations fail on purpose, to exercise out-of-memory paths.
+//!
+//! A `FaultConfig` can be installed globally or for a single task;  the task's own configuration
+//! wins over the global one.  Each scope keeps its own allocation counter and RNG, so runs with the
+//! same seed fail the same allocations.  Allocations made by interrupt handlers are neither
+//! counted nor failed:  they belong to whichever task the interrupt happened to land in, and
+//! failing one would panic with the handler's locks held.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::concurrency::mutex::Mutex;
+use crate::interrupts;
+use crate::rng::Rng;
+use crate::task::executor::{self, current_task};
+use crate::task::TaskId;
+
+/// The most tasks which can have their own configuration at the same time.
+const MAX_TASK_SCOPES: usize = 16;
+
+/// When allocations should fail.  Any condition which matches makes the allocation fail.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultConfig {
+    /// Fail the nth allocation (counting from 1) made after the configuration was installed.
+    pub fail_nth: Option<u64>,
+    /// Fail each allocation with this probability, in parts per million.
+    pub probability_ppm: Option<u32>,
+    /// Fail every allocation larger than this many bytes.
+    pub size_threshold: Option<usize>,
+    /// Seed for the RNG behind `probability_ppm`.
+    pub seed: u64,
+}
+
+#[derive(Clone)]
+struct Scope {
+    config: FaultConfig,
+    allocations: u64,
+    injected: u64,
+    rng: Rng,
+}
+
+impl Scope {
+    fn new(config: FaultConfig) -> Self {
+        Self {
+            config,
+            allocations: 0,
+            injected: 0,
+            rng: Rng::new(config.seed),
+        }
+    }
+
+    fn should_fail(&mut self, layout: &Layout) -> bool {
+        self.allocations += 1;
+
+        let nth = self.config.fail_nth == Some(self.allocations);
+        let too_large =
+            matches!(self.config.size_threshold, Some(threshold) if layout.size() > threshold);
+        let unlucky = match self.config.probability_ppm {
+            Some(ppm) => self.rng.chance_ppm(ppm),
+            None => false,
+        };
+
+        let fail = nth || too_large || unlucky;
+        if fail {
+            self.injected += 1;
+        }
+        fail
+    }
+}
+
+struct Scopes {
+    global: Option<Scope>,
+    tasks: [Option<(TaskId, Scope)>; MAX_TASK_SCOPES],
+}
+
+impl Scopes {
+    fn is_empty(&self) -> bool {
+        self.global.is_none() && self.tasks.iter().all(Option::is_none)
+    }
+
+    /// The scope which applies to allocations made by `task`.
+    fn scope_for(&mut self, task: Option<TaskId>) -> Option<&mut Scope> {
+        let Scopes { global, tasks } = self;
+        let task_scope = task.and_then(|task| {
+            tasks
+                .iter_mut()
+                .flatten()
+                .find(|(id, _)| *id == task)
+                .map(|(_, scope)| scope)
+        });
+        task_scope.or(global.as_mut())
+    }
+}
+
+const NO_SCOPE: Option<(TaskId, Scope)> = None;
+
+static SCOPES: Mutex<Scopes> = Mutex::new(Scopes {
+    global: None,
+    tasks: [NO_SCOPE; MAX_TASK_SCOPES],
+});
+
+/// Set whenever any scope is configured, so that the common case doesn't need the lock.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Drops the configuration of tasks when they end.  Must be called after `executor::init`.
+pub fn init() {
+    executor::register_exit_hook(|task| set_for_task(task, None));
+}
+
+/// Installs (or with `None`, removes) the configuration used by tasks without their own.
+pub fn set_global(config: Option<FaultConfig>) {
+    without_interrupts(|| {
+        let mut scopes = SCOPES.lock();
+        scopes.global = config.map(Scope::new);
+        ACTIVE.store(!scopes.is_empty(), Ordering::SeqCst);
+    })
+}
+
+/// Installs (or with `None`, removes) a configuration which only applies to allocations made by
+/// `task`.
+///
+/// Panics if `MAX_TASK_SCOPES` tasks already have a configuration.
+pub fn set_for_task(task: TaskId, config: Option<FaultConfig>) {
+    without_interrupts(|| {
+        let mut scopes = SCOPES.lock();
+        let existing = scopes
+            .tasks
+            .iter()
+            .position(|scope| matches!(scope, Some((id, _)) if *id == task));
+
+        match (existing, config) {
+            (Some(index), Some(config)) => scopes.tasks[index] = Some((task, Scope::new(config))),
+            (Some(index), None) => scopes.tasks[index] = None,
+            (None, Some(config)) => {
+                let slot = scopes
+                    .tasks
+                    .iter_mut()
+                    .find(|scope| scope.is_none())
+                    .expect("too many tasks with fault injection configured");
+                *slot = Some((task, Scope::new(config)));
+            }
+            (None, None) => {}
+        }
+        ACTIVE.store(!scopes.is_empty(), Ordering::SeqCst);
+    })
+}
+
+/// Like `set_for_task`, for the calling task.  Falls back to the global scope when called from
+/// outside a task.
+pub fn set_for_current_task(config: Option<FaultConfig>) {
+    match current_task() {
+        Some(task) => set_for_task(task, config),
+        None => set_global(config),
+    }
+}
+
+/// How many allocations have been made and how many were failed, in the scope that applies to
+/// `task` (or the global scope).
+pub fn counters(task: Option<TaskId>) -> Option<(u64, u64)> {
+    without_interrupts(|| {
+        let mut scopes = SCOPES.lock();
+        let scope = scopes.scope_for(task)?;
+        Some((scope.allocations, scope.injected))
+    })
+}
+
+/// Decides whether the allocation of `layout` should be failed.  Called by the global allocator.
+pub(super) fn should_fail(layout: &Layout) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) || interrupts::in_interrupt() {
+        return false;
+    }
+
+    without_interrupts(|| match SCOPES.lock().scope_for(current_task()) {
+        Some(scope) => scope.should_fail(layout),
+        None => false,
+    })
+}
+
+#[test_case]
+fn test_fail_nth() {
+    let mut scope = Scope::new(FaultConfig {
+        fail_nth: Some(3),
+        ..FaultConfig::default()
+    });
+    let layout = Layout::new::<u64>();
+    for n in 1..=5 {
+        assert_eq!(scope.should_fail(&layout), n == 3);
+    }
+    assert_eq!((scope.allocations, scope.injected), (5, 1));
+}
+
+#[test_case]
+fn test_fail_with_probability() {
+    let config = FaultConfig {
+        probability_ppm: Some(500_000),
+        seed: 42,
+        ..FaultConfig::default()
+    };
+    let layout = Layout::new::<u64>();
+    let mut a = Scope::new(config);
+    let mut b = Scope::new(config);
+    for _ in 0..1000 {
+        assert_eq!(a.should_fail(&layout), b.should_fail(&layout));
+    }
+    assert!(a.injected > 0 && a.injected < 1000);
+
+    let mut never = Scope::new(FaultConfig {
+        probability_ppm: Some(0),
+        ..config
+    });
+    assert!((0..1000).all(|_| !never.should_fail(&layout)));
+}
+
+#[test_case]
+fn test_fail_above_size_threshold() {
+    let mut scope = Scope::new(FaultConfig {
+        size_threshold: Some(64),
+        ..FaultConfig::default()
+    });
+    assert!(!scope.should_fail(&Layout::from_size_align(64, 8).unwrap()));
+    assert!(scope.should_fail(&Layout::from_size_align(65, 8).unwrap()));
+    assert!(!scope.should_fail(&Layout::from_size_align(1, 1).unwrap()));
+}
@@ -45,8 +45,15 @@ impl FixedSizeBlockAllocator {
     /// This function is unsafe because the caller must guarantee that the given
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
-    pub unsafe fn init(&mut self, heap_start: *mut u8, heap_size: usize) {
-        self.fallback_allocator.init(heap_start as usize, heap_size);
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Extends the heap by `by` bytes, directly after its current end.
+    ///
+    /// The caller must guarantee that the memory is mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
     }
 
     /// Allocates using the fallback allocator.
@@ -0,0 +1,468 @@
+//! Kernel address sanitizer for the heap.
+//!
+//! With the `kasan` feature, every allocation gets a redzone on either side, freed blocks sit in a
+//! quarantine for a while before being reused, and one shadow byte per 8 heap bytes records which
+//! bytes may be touched.  Code built with
+//!
+//! ```text
+//! -Zsanitizer=kernel-address
+//! -Cllvm-args=-asan-instrumentation-with-call-threshold=0
+//! -Cllvm-args=-asan-stack=0 -Cllvm-args=-asan-globals=0
+//! ```
+//!
+//! calls the `__asan_*` hooks below on every load and store, and out-of-bounds accesses or
+//! use-after-free are reported along with where the block was allocated (and freed).
+//!
+//! The shadow uses the usual ASan encoding:  0 means the whole granule is accessible, 1 to 7 means
+//! only that many leading bytes are, and anything with the top bit set is poisoned.
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{align_up, backend_alloc, backend_dealloc, HEAP_START};
+use crate::concurrency::mutex::Mutex;
+use crate::memory::mapping::{self, Frames};
+use crate::memory::KernelMemory;
+use crate::{backtrace, eprintln};
+
+/// Has room for the shadow of `HEAP_MAX_SIZE` bytes below `HEAP_START`.
+const SHADOW_START: usize = 0x_4443_0000_0000;
+const SHADOW_SCALE: usize = 8;
+
+const HEAP_LEFT_REDZONE: u8 = 0xFA;
+const HEAP_RIGHT_REDZONE: u8 = 0xFB;
+const HEAP_FREED: u8 = 0xFD;
+
+const RIGHT_REDZONE_SIZE: usize = 32;
+const SITE_FRAMES: usize = 4;
+const HEADER_MAGIC: u64 = 0xA5A5_CA5A_0000_0001;
+
+const QUARANTINE_ENTRIES: usize = 4096;
+const QUARANTINE_BYTES: usize = 4 * 1024 * 1024;
+
+/// How far back a report searches for the start of the allocation an address belongs to.
+const MAX_REPORT_SCAN: usize = 1024 * 1024;
+
+/// Sits directly in front of the user's block, inside the left redzone.
+#[repr(C)]
+struct AllocHeader {
+    magic: u64,
+    size: usize,
+    alloc_site: [usize; SITE_FRAMES],
+    free_site: [usize; SITE_FRAMES],
+}
+
+const HEADER_SIZE: usize = mem::size_of::<AllocHeader>();
+
+/// Bytes of the heap which have shadow mapped for them.
+static SHADOWED: AtomicUsize = AtomicUsize::new(0);
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+struct Quarantine {
+    entries: [(usize, usize, usize); QUARANTINE_ENTRIES],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    entries: [(0, 0, 0); QUARANTINE_ENTRIES],
+    head: 0,
+    len: 0,
+    bytes: 0,
+});
+
+#[no_sanitize(address)]
+fn shadow_for(addr: usize) -> *mut u8 {
+    (SHADOW_START + (addr - HEAP_START as usize) / SHADOW_SCALE) as *mut u8
+}
+
+/// Sets the shadow of `[start, start + size)` to `value`.  `start` must be granule aligned, and
+/// `size` is rounded up to the next granule.
+#[no_sanitize(address)]
+unsafe fn poison(start: usize, size: usize, value: u8) {
+    let granules = align_up(size, SHADOW_SCALE) / SHADOW_SCALE;
+    ptr::write_bytes(shadow_for(start), value, granules);
+}
+
+/// Marks `[start, start + size)` accessible, with a partial granule at the end if needed.
+#[no_sanitize(address)]
+unsafe fn unpoison(start: usize, size: usize) {
+    ptr::write_bytes(shadow_for(start), 0, size / SHADOW_SCALE);
+    if size % SHADOW_SCALE != 0 {
+        *shadow_for(start + size - size % SHADOW_SCALE) = (size % SHADOW_SCALE) as u8;
+    }
+}
+
+/// Maps and clears the shadow for the first `heap_mapped` bytes of the heap.
+///
+/// Called by the heap whenever it grows, with the kernel memory lock held.
+pub(super) fn map_shadow(
+    memory: &mut KernelMemory,
+    heap_mapped: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    let shadowed = SHADOWED.load(Ordering::SeqCst);
+    let mapped_end = align_up(shadowed / SHADOW_SCALE, 4096);
+    let required_end = align_up(heap_mapped / SHADOW_SCALE, 4096);
+
+    if required_end > mapped_end {
+        let start = VirtAddr::new((SHADOW_START + mapped_end) as u64);
+        let size = (required_end - mapped_end) as u64;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match mapping::map_range(memory, start, size, Frames::Zeroed, flags) {
+            Ok(mapped) if mapped == size => {}
+            result => {
+                // unmap whatever did get mapped, so that the next try starts from the same place
+                mapping::unmap_range(memory, start, size, true).ok();
+                return Err(result.err().unwrap_or(MapToError::FrameAllocationFailed));
+            }
+        }
+    }
+
+    SHADOWED.store(heap_mapped, Ordering::SeqCst);
+    Ok(())
+}
+
+#[no_sanitize(address)]
+fn is_shadowed(addr: usize, size: usize) -> bool {
+    let heap_start = HEAP_START as usize;
+    addr >= heap_start && addr.saturating_add(size) <= heap_start + SHADOWED.load(Ordering::Relaxed)
+}
+
+fn header_layout(layout: &Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(SHADOW_SCALE);
+    let left_redzone = align_up(HEADER_SIZE, align);
+    let size = left_redzone
+        .checked_add(align_up(layout.size(), SHADOW_SCALE))?
+        .checked_add(RIGHT_REDZONE_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, left_redzone))
+}
+
+#[no_sanitize(address)]
+pub(super) unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let (backend_layout, left_redzone) = match header_layout(&layout) {
+        Some(layouts) => layouts,
+        None => return ptr::null_mut(),
+    };
+
+    let backend = backend_alloc(backend_layout);
+    if backend.is_null() {
+        return backend;
+    }
+
+    let user = backend.add(left_redzone);
+    let header = user.sub(HEADER_SIZE) as *mut AllocHeader;
+    let mut alloc_site = [0; SITE_FRAMES];
+    backtrace::capture(2, &mut alloc_site);
+    header.write(AllocHeader {
+        magic: HEADER_MAGIC,
+        size: layout.size(),
+        alloc_site,
+        free_site: [0; SITE_FRAMES],
+    });
+
+    if is_shadowed(backend as usize, backend_layout.size()) {
+        let user_end = align_up(user as usize + layout.size(), SHADOW_SCALE);
+        poison(backend as usize, left_redzone, HEAP_LEFT_REDZONE);
+        unpoison(user as usize, layout.size());
+        poison(
+            user_end,
+            backend as usize + backend_layout.size() - user_end,
+            HEAP_RIGHT_REDZONE,
+        );
+    }
+
+    user
+}
+
+/// Checks that `ptr` is a live allocation of `layout`'s size, returning what is wrong otherwise.
+#[no_sanitize(address)]
+unsafe fn check_free(ptr: *mut u8, layout: &Layout) -> Result<(), &'static str> {
+    let header = &*(ptr.sub(HEADER_SIZE) as *const AllocHeader);
+    if header.magic != HEADER_MAGIC {
+        return Err("free of a pointer that wasn't allocated by the heap");
+    }
+    if is_shadowed(ptr as usize, 1) && *shadow_for(ptr as usize) == HEAP_FREED {
+        return Err("double free");
+    }
+    if header.size != layout.size() {
+        return Err("free with a different size than the allocation");
+    }
+    Ok(())
+}
+
+#[no_sanitize(address)]
+pub(super) unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    if let Err(problem) = check_free(ptr, &layout) {
+        report_bad_free(ptr as usize, problem);
+    }
+
+    let header = &mut *(ptr.sub(HEADER_SIZE) as *mut AllocHeader);
+    backtrace::capture(2, &mut header.free_site);
+    let (backend_layout, left_redzone) = header_layout(&layout).unwrap();
+    let backend = ptr.sub(left_redzone);
+    if is_shadowed(ptr as usize, layout.size()) {
+        poison(ptr as usize, layout.size(), HEAP_FREED);
+    }
+
+    without_interrupts(|| quarantine(backend as usize, backend_layout));
+}
+
+/// Holds on to a freed block, releasing the oldest ones back to the heap once the quarantine is
+/// full.
+#[no_sanitize(address)]
+unsafe fn quarantine(backend: usize, backend_layout: Layout) {
+    let mut quarantine = QUARANTINE.lock();
+    let tail = (quarantine.head + quarantine.len) % QUARANTINE_ENTRIES;
+    quarantine.entries[tail] = (backend, backend_layout.size(), backend_layout.align());
+    quarantine.len += 1;
+    quarantine.bytes += backend_layout.size();
+
+    while quarantine.len == QUARANTINE_ENTRIES || quarantine.bytes > QUARANTINE_BYTES {
+        let (backend, size, align) = quarantine.entries[quarantine.head];
+        quarantine.head = (quarantine.head + 1) % QUARANTINE_ENTRIES;
+        quarantine.len -= 1;
+        quarantine.bytes -= size;
+
+        // forget the header too, so that freeing the block again is still caught once the
+        // shadow no longer says it is freed
+        let header = backend + align_up(HEADER_SIZE, align) - HEADER_SIZE;
+        (*(header as *mut AllocHeader)).magic = 0;
+        if is_shadowed(backend, size) {
+            poison(backend, size, 0);
+        }
+        backend_dealloc(
+            backend as *mut u8,
+            Layout::from_size_align_unchecked(size, align),
+        );
+    }
+}
+
+/// Returns `true` if the `size` bytes at `addr` may be accessed.
+#[no_sanitize(address)]
+fn access_ok(addr: usize, size: usize) -> bool {
+    let end = addr + size;
+    let mut granule = addr & !(SHADOW_SCALE - 1);
+    while granule < end {
+        let shadow = unsafe { *shadow_for(granule) };
+        if shadow != 0 {
+            let accessible_end = granule + shadow as usize;
+            if shadow & 0x80 != 0 || end.min(granule + SHADOW_SCALE) > accessible_end {
+                return false;
+            }
+        }
+        granule += SHADOW_SCALE;
+    }
+    true
+}
+
+#[no_sanitize(address)]
+fn check_access(addr: usize, size: usize, is_write: bool) {
+    if size == 0 || !is_shadowed(addr, size) || REPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    if !access_ok(addr, size) {
+        report_access(addr, size, is_write);
+    }
+}
+
+/// Finds the header of the allocation `addr` lies in (or in a redzone of), using the shadow.
+#[no_sanitize(address)]
+unsafe fn find_header(addr: usize) -> Option<&'static AllocHeader> {
+    let heap_start = HEAP_START as usize;
+    let lowest = addr.saturating_sub(MAX_REPORT_SCAN).max(heap_start);
+    let mut granule = addr & !(SHADOW_SCALE - 1);
+
+    if *shadow_for(granule) == HEAP_LEFT_REDZONE {
+        // underflow:  the block starts after the redzone
+        let highest = (addr + MAX_REPORT_SCAN).min(heap_start + SHADOWED.load(Ordering::Relaxed));
+        while granule < highest && *shadow_for(granule) == HEAP_LEFT_REDZONE {
+            granule += SHADOW_SCALE;
+        }
+    } else {
+        // overflow or use after free:  the block starts after the closest left redzone before it
+        while granule > lowest && *shadow_for(granule - SHADOW_SCALE) != HEAP_LEFT_REDZONE {
+            granule -= SHADOW_SCALE;
+        }
+    }
+
+    let header = &*((granule - HEADER_SIZE) as *const AllocHeader);
+    if granule - HEADER_SIZE >= lowest && header.magic == HEADER_MAGIC {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+#[no_sanitize(address)]
+fn print_site(name: &str, site: &[usize]) {
+    eprintln!("  {}:", name);
+    for ip in site.iter().take_while(|&&ip| ip != 0) {
+        eprintln!("    {:#x}", ip);
+    }
+}
+
+#[no_sanitize(address)]
+fn report_access(addr: usize, size: usize, is_write: bool) -> ! {
+    REPORTING.store(true, Ordering::SeqCst);
+
+    let shadow = unsafe { *shadow_for(addr & !(SHADOW_SCALE - 1)) };
+    let kind = match shadow {
+        HEAP_LEFT_REDZONE => "heap-buffer-underflow",
+        HEAP_FREED => "heap-use-after-free",
+        _ => "heap-buffer-overflow",
+    };
+
+    eprintln!(
+        "KASAN: {} on {} of size {} at {:#x}",
+        kind,
+        if is_write { "write" } else { "read" },
+        size,
+        addr
+    );
+    let mut access_site = [0; 8];
+    let frames = backtrace::capture(2, &mut access_site);
+    print_site("accessed from", &access_site[..frames]);
+
+    if let Some(header) = unsafe { find_header(addr) } {
+        let start = header as *const AllocHeader as usize + HEADER_SIZE;
+        eprintln!(
+            "  address is {} bytes into a {} byte block at {:#x}",
+            addr as isize - start as isize,
+            header.size,
+            start
+        );
+        print_site("allocated at", &header.alloc_site);
+        if shadow == HEAP_FREED {
+            print_site("freed at", &header.free_site);
+        }
+    }
+
+    REPORTING.store(false, Ordering::SeqCst);
+    panic!("KASAN: {} at {:#x}", kind, addr);
+}
+
+#[no_sanitize(address)]
+fn report_bad_free(addr: usize, problem: &str) -> ! {
+    REPORTING.store(true, Ordering::SeqCst);
+    eprintln!("KASAN: {} at {:#x}", problem, addr);
+
+    let mut free_site = [0; 8];
+    let frames = backtrace::capture(2, &mut free_site);
+    print_site("freed from", &free_site[..frames]);
+
+    let header = unsafe { &*((addr - HEADER_SIZE) as *const AllocHeader) };
+    if header.magic == HEADER_MAGIC {
+        print_site("allocated at", &header.alloc_site);
+        print_site("first freed at", &header.free_site);
+    }
+
+    REPORTING.store(false, Ordering::SeqCst);
+    panic!("KASAN: {} at {:#x}", problem, addr);
+}
+
+macro_rules! asan_callbacks {
+    ($($load:ident, $store:ident, $size:expr;)*) => {
+        $(
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $load(addr: usize) {
+                check_access(addr, $size, false);
+            }
+
+            #[no_mangle]
+            #[no_sanitize(address)]
+            pub extern "C" fn $store(addr: usize) {
+                check_access(addr, $size, true);
+            }
+        )*
+    };
+}
+
+asan_callbacks! {
+    __asan_load1, __asan_store1, 1;
+    __asan_load2, __asan_store2, 2;
+    __asan_load4, __asan_store4, 4;
+    __asan_load8, __asan_store8, 8;
+    __asan_load16, __asan_store16, 16;
+    __asan_load1_noabort, __asan_store1_noabort, 1;
+    __asan_load2_noabort, __asan_store2_noabort, 2;
+    __asan_load4_noabort, __asan_store4_noabort, 4;
+    __asan_load8_noabort, __asan_store8_noabort, 8;
+    __asan_load16_noabort, __asan_store16_noabort, 16;
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    check_access(addr, size, false);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    check_access(addr, size, true);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check_access(addr, size, false);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check_access(addr, size, true);
+}
+
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+#[no_mangle]
+pub extern "C" fn __asan_register_globals(_globals: usize, _count: usize) {}
+
+#[no_mangle]
+pub extern "C" fn __asan_unregister_globals(_globals: usize, _count: usize) {}
+
+/// Returns how many heap bytes the quarantine is currently holding back.
+pub fn quarantined_bytes() -> usize {
+    QUARANTINE.lock().bytes
+}
+
+#[test_case]
+fn test_bad_accesses_detected() {
+    unsafe {
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let block = alloc(layout);
+        assert!(access_ok(block as usize, 20));
+        assert!(!access_ok(block as usize + 20, 1));
+        assert!(!access_ok(block as usize - 1, 1));
+
+        dealloc(block, layout);
+        assert!(!access_ok(block as usize, 1));
+        assert_eq!(*shadow_for(block as usize), HEAP_FREED);
+    }
+}
+
+#[test_case]
+fn test_bad_frees_detected() {
+    unsafe {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let block = alloc(layout);
+        assert_eq!(
+            check_free(block, &Layout::from_size_align(32, 8).unwrap()),
+            Err("free with a different size than the allocation")
+        );
+        assert_eq!(check_free(block, &layout), Ok(()));
+
+        dealloc(block, layout);
+        assert_eq!(check_free(block, &layout), Err("double free"));
+    }
+}
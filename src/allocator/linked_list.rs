@@ -23,6 +23,7 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_end: usize,
 }
 
 impl LinkedListAllocator {
@@ -30,6 +31,7 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
         }
     }
 
@@ -40,6 +42,15 @@ impl LinkedListAllocator {
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /// Extends the heap by `by` bytes, directly after its current end.
+    ///
+    /// The caller must guarantee that the memory is mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.add_free_region(self.heap_end, by);
+        self.heap_end += by;
     }
 
     /// Adds the given memory region to the front of the list.
@@ -0,0 +1,542 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::Locked;
+
+/// Every slab is one page, aligned to its size so that the header can be found from any block.
+const SLAB_SIZE: usize = 4096;
+const SLAB_MAGIC: u64 = 0x5EAB_A110_C8ED_F00D;
+
+/// The size classes served from slabs.  Anything larger goes straight to the fallback allocator.
+///
+/// Like `fixed_size_block::BLOCK_SIZES`, these must be powers of two since they double as the
+/// block alignment.
+const SIZE_CLASSES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
+const CLASS_COUNT: usize = SIZE_CLASSES.len();
+
+/// Enough bits to track every block in a slab of the smallest class.
+const BITMAP_WORDS: usize = SLAB_SIZE / 8 / 64;
+
+fn class_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&s| s >= required_block_size)
+}
+
+#[repr(C)]
+struct SlabHeader {
+    magic: u64,
+    class: usize,
+    in_use: usize,
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    allocated: [u64; BITMAP_WORDS],
+}
+
+impl SlabHeader {
+    /// Offset of the first block;  blocks are aligned to their size.
+    fn first_block_offset(class: usize) -> usize {
+        super::align_up(mem::size_of::<SlabHeader>(), SIZE_CLASSES[class])
+    }
+
+    fn capacity(class: usize) -> usize {
+        (SLAB_SIZE - Self::first_block_offset(class)) / SIZE_CLASSES[class]
+    }
+
+    fn containing(ptr: *mut u8) -> *mut SlabHeader {
+        (ptr as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader
+    }
+
+    fn block_ptr(&mut self, index: usize) -> *mut u8 {
+        let base = self as *mut SlabHeader as usize;
+        (base + Self::first_block_offset(self.class) + index * SIZE_CLASSES[self.class]) as *mut u8
+    }
+
+    /// Returns the index of the block `ptr` points at, or `None` if it doesn't point to the start
+    /// of a block in this slab.
+    fn block_index(&self, ptr: *mut u8) -> Option<usize> {
+        let offset = (ptr as usize).checked_sub(self as *const SlabHeader as usize)?;
+        let block_offset = offset.checked_sub(Self::first_block_offset(self.class))?;
+        let block_size = SIZE_CLASSES[self.class];
+        let index = block_offset / block_size;
+        if block_offset % block_size != 0 || index >= Self::capacity(self.class) {
+            return None;
+        }
+        Some(index)
+    }
+
+    fn is_allocated(&self, index: usize) -> bool {
+        self.allocated[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Marks the first free block as allocated and returns its index.
+    fn take_free_block(&mut self) -> Option<usize> {
+        let capacity = Self::capacity(self.class);
+        for (word_index, word) in self.allocated.iter_mut().enumerate() {
+            let bit = word.trailing_ones() as usize;
+            let index = word_index * 64 + bit;
+            if bit < 64 && index < capacity {
+                *word |= 1 << bit;
+                self.in_use += 1;
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn release_block(&mut self, index: usize) {
+        self.allocated[index / 64] &= !(1 << (index % 64));
+        self.in_use -= 1;
+    }
+}
+
+/// Allocation counters for one size class.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClassStats {
+    pub block_size: usize,
+    /// Blocks currently handed out.
+    pub live: usize,
+    /// The highest `live` has ever been.
+    pub peak: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Slab pages currently owned by the class.
+    pub slabs: usize,
+}
+
+/// Counters live outside the allocator lock so that allocations served from a magazine are
+/// counted too.
+struct ClassCounters {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicU64,
+    frees: AtomicU64,
+    slabs: AtomicUsize,
+}
+
+impl ClassCounters {
+    const fn new() -> Self {
+        Self {
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            frees: AtomicU64::new(0),
+            slabs: AtomicUsize::new(0),
+        }
+    }
+
+    fn on_alloc(&self) {
+        let live = self.live.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_free(&self) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Index `CLASS_COUNT` holds the counters for allocations that went to the fallback allocator.
+static COUNTERS: [ClassCounters; CLASS_COUNT + 1] = {
+    const EMPTY: ClassCounters = ClassCounters::new();
+    [EMPTY; CLASS_COUNT + 1]
+};
+
+/// Returns the counters of every size class, followed by those of the large (fallback)
+/// allocations, whose `block_size` is 0.
+pub fn stats() -> [ClassStats; CLASS_COUNT + 1] {
+    let mut stats = [ClassStats::default(); CLASS_COUNT + 1];
+    for (index, (stats, counters)) in stats.iter_mut().zip(COUNTERS.iter()).enumerate() {
+        *stats = ClassStats {
+            block_size: SIZE_CLASSES.get(index).copied().unwrap_or(0),
+            live: counters.live.load(Ordering::Relaxed),
+            peak: counters.peak.load(Ordering::Relaxed),
+            allocations: counters.allocations.load(Ordering::Relaxed),
+            frees: counters.frees.load(Ordering::Relaxed),
+            slabs: counters.slabs.load(Ordering::Relaxed),
+        };
+    }
+    stats
+}
+
+fn report_bad_free(ptr: *mut u8, layout: &Layout, problem: &str) -> ! {
+    panic!(
+        "slab allocator: {} (ptr {:p}, size {}, align {})",
+        problem,
+        ptr,
+        layout.size(),
+        layout.align()
+    );
+}
+
+pub struct SlabAllocator {
+    /// Per class, a doubly linked list of slabs which have at least one free block.
+    partial: [*mut SlabHeader; CLASS_COUNT],
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    /// Creates an empty SlabAllocator.
+    pub const fn new() -> Self {
+        SlabAllocator {
+            partial: [ptr::null_mut(); CLASS_COUNT],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Extends the heap by `by` bytes, directly after its current end.
+    ///
+    /// The caller must guarantee that the memory is mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn new_slab(&mut self, class: usize) -> *mut SlabHeader {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab = self.fallback_alloc(layout) as *mut SlabHeader;
+        if slab.is_null() {
+            return slab;
+        }
+
+        slab.write(SlabHeader {
+            magic: SLAB_MAGIC,
+            class,
+            in_use: 0,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            allocated: [0; BITMAP_WORDS],
+        });
+        COUNTERS[class].slabs.fetch_add(1, Ordering::Relaxed);
+        slab
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut SlabHeader) {
+        let class = (*slab).class;
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial[class];
+        if let Some(head) = self.partial[class].as_mut() {
+            head.prev = slab;
+        }
+        self.partial[class] = slab;
+    }
+
+    unsafe fn unlink_partial(&mut self, slab: *mut SlabHeader) {
+        let class = (*slab).class;
+        match (*slab).prev.as_mut() {
+            Some(prev) => prev.next = (*slab).next,
+            None => self.partial[class] = (*slab).next,
+        }
+        if let Some(next) = (*slab).next.as_mut() {
+            next.prev = (*slab).prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
+    /// Takes a block of the given class, without touching the counters.
+    unsafe fn alloc_block(&mut self, class: usize) -> *mut u8 {
+        let mut slab = self.partial[class];
+        if slab.is_null() {
+            slab = self.new_slab(class);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.push_partial(slab);
+        }
+
+        let index = (*slab)
+            .take_free_block()
+            .expect("slab on the partial list has no free blocks");
+        if (*slab).in_use == SlabHeader::capacity(class) {
+            self.unlink_partial(slab);
+        }
+        (*slab).block_ptr(index)
+    }
+
+    /// Returns a block to its slab, without touching the counters.  The block must already have
+    /// been validated with `check_free`.
+    unsafe fn free_block(&mut self, ptr: *mut u8) {
+        let slab = SlabHeader::containing(ptr);
+        let class = (*slab).class;
+        let index = (*slab).block_index(ptr).unwrap();
+        let was_full = (*slab).in_use == SlabHeader::capacity(class);
+        (*slab).release_block(index);
+
+        if was_full {
+            self.push_partial(slab);
+        }
+
+        // keep one empty slab around per class, so that alternating alloc/free doesn't keep
+        // going back to the fallback allocator
+        let only_slab = self.partial[class] == slab && (*slab).next.is_null();
+        if (*slab).in_use == 0 && !only_slab {
+            self.unlink_partial(slab);
+            (*slab).magic = 0;
+            let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+            self.fallback_allocator
+                .deallocate(NonNull::new_unchecked(slab as *mut u8), layout);
+            COUNTERS[class].slabs.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Checks that `ptr` is a block of the class `layout` belongs to that its slab has handed out,
+    /// and returns what's wrong otherwise.
+    ///
+    /// A block sitting in a magazine counts as handed out, so freeing it again is only caught by
+    /// the magazine check in `dealloc`.
+    unsafe fn check_free(ptr: *mut u8, class: usize) -> Result<(), &'static str> {
+        let slab = &*SlabHeader::containing(ptr);
+        if slab.magic != SLAB_MAGIC {
+            return Err("freed with a layout it wasn't allocated with");
+        }
+        if slab.class != class {
+            return Err("freed with a layout of the wrong size class");
+        }
+        match slab.block_index(ptr) {
+            Some(index) if slab.is_allocated(index) => Ok(()),
+            Some(_) => Err("double free"),
+            None => Err("pointer doesn't point to a block"),
+        }
+    }
+
+    /// Checks that a pointer freed with a large layout doesn't actually belong to a slab.
+    ///
+    /// That's all that's checked:  the fallback allocator keeps no record of its allocations, so a
+    /// large allocation freed twice, or with a different large layout, goes unnoticed.
+    unsafe fn check_large_free(ptr: *mut u8) -> Result<(), &'static str> {
+        let slab = &*SlabHeader::containing(ptr);
+        match slab.magic == SLAB_MAGIC && slab.block_index(ptr).is_some() {
+            true => Err("slab block freed with a layout it wasn't allocated with"),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "slab-magazines")]
+mod magazines {
+    //! Small per-CPU stacks of free blocks in front of the slabs, so that the common case doesn't
+    //! need the allocator lock.  Must only be touched with interrupts disabled.
+
+    use core::ptr;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{SlabAllocator, CLASS_COUNT};
+    use crate::allocator::Locked;
+
+    const MAX_CPUS: usize = 8;
+    const MAGAZINE_SIZE: usize = 32;
+
+    #[derive(Clone, Copy)]
+    struct Magazine {
+        len: usize,
+        blocks: [*mut u8; MAGAZINE_SIZE],
+    }
+
+    const EMPTY: Magazine = Magazine {
+        len: 0,
+        blocks: [ptr::null_mut(); MAGAZINE_SIZE],
+    };
+
+    static mut MAGAZINES: [[Magazine; CLASS_COUNT]; MAX_CPUS] = [[EMPTY; CLASS_COUNT]; MAX_CPUS];
+
+    /// The boot CPU's index, or `NO_CPU` until it's first needed.
+    static CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+    const NO_CPU: usize = usize::MAX;
+
+    /// The kernel only runs on the boot CPU, so its index is looked up once:  `cpuid` is slow,
+    /// especially under KVM, where it exits to the hypervisor.
+    fn current_cpu() -> usize {
+        match CPU.load(Ordering::Relaxed) {
+            NO_CPU => {
+                // initial APIC ID
+                let ebx = unsafe { core::arch::x86_64::__cpuid(1).ebx };
+                let cpu = (ebx >> 24) as usize % MAX_CPUS;
+                CPU.store(cpu, Ordering::Relaxed);
+                cpu
+            }
+            cpu => cpu,
+        }
+    }
+
+    unsafe fn magazine(class: usize) -> &'static mut Magazine {
+        &mut (*ptr::addr_of_mut!(MAGAZINES))[current_cpu()][class]
+    }
+
+    /// Fills `magazine` up to half full with blocks of class `class` from the slabs.
+    unsafe fn refill(magazine: &mut Magazine, slabs: &mut SlabAllocator, class: usize) {
+        while magazine.len < MAGAZINE_SIZE / 2 {
+            let block = slabs.alloc_block(class);
+            if block.is_null() {
+                break;
+            }
+            magazine.blocks[magazine.len] = block;
+            magazine.len += 1;
+        }
+    }
+
+    /// Gives blocks from `magazine` back to their slabs until it's half full.
+    unsafe fn drain(magazine: &mut Magazine, slabs: &mut SlabAllocator) {
+        while magazine.len > MAGAZINE_SIZE / 2 {
+            magazine.len -= 1;
+            slabs.free_block(magazine.blocks[magazine.len]);
+        }
+    }
+
+    pub(super) unsafe fn alloc(allocator: &Locked<SlabAllocator>, class: usize) -> *mut u8 {
+        let magazine = magazine(class);
+        if magazine.len == 0 {
+            refill(magazine, &mut allocator.lock(), class);
+        }
+
+        if magazine.len == 0 {
+            return ptr::null_mut();
+        }
+        magazine.len -= 1;
+        magazine.blocks[magazine.len]
+    }
+
+    /// Returns `true` if `ptr` is already sitting in this CPU's magazine.  Another CPU's magazine
+    /// isn't looked at, so a block freed twice on different CPUs goes unnoticed.
+    pub(super) unsafe fn contains(class: usize, ptr: *mut u8) -> bool {
+        let magazine = magazine(class);
+        magazine.blocks[..magazine.len].contains(&ptr)
+    }
+
+    pub(super) unsafe fn free(allocator: &Locked<SlabAllocator>, class: usize, ptr: *mut u8) {
+        let magazine = magazine(class);
+        if magazine.len == MAGAZINE_SIZE {
+            drain(magazine, &mut allocator.lock());
+        }
+        magazine.blocks[magazine.len] = ptr;
+        magazine.len += 1;
+    }
+
+    #[test_case]
+    fn test_magazine_refill_and_drain() {
+        unsafe {
+            let mut slabs = super::test_allocator();
+            let mut magazine = EMPTY;
+            refill(&mut magazine, &mut slabs, 0);
+            assert_eq!(magazine.len, MAGAZINE_SIZE / 2);
+
+            while magazine.len < MAGAZINE_SIZE {
+                magazine.blocks[magazine.len] = slabs.alloc_block(0);
+                magazine.len += 1;
+            }
+            let kept = magazine.blocks[0];
+            let drained = magazine.blocks[MAGAZINE_SIZE - 1];
+            drain(&mut magazine, &mut slabs);
+            assert_eq!(magazine.len, MAGAZINE_SIZE / 2);
+            assert_eq!(SlabAllocator::check_free(kept, 0), Ok(()));
+            assert_eq!(SlabAllocator::check_free(drained, 0), Err("double free"));
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let (ptr, counters) = match class_index(&layout) {
+                #[cfg(feature = "slab-magazines")]
+                Some(class) => (magazines::alloc(self, class), &COUNTERS[class]),
+                #[cfg(not(feature = "slab-magazines"))]
+                Some(class) => (self.lock().alloc_block(class), &COUNTERS[class]),
+                None => (self.lock().fallback_alloc(layout), &COUNTERS[CLASS_COUNT]),
+            };
+
+            if !ptr.is_null() {
+                counters.on_alloc();
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| match class_index(&layout) {
+            Some(class) => {
+                if let Err(problem) = SlabAllocator::check_free(ptr, class) {
+                    report_bad_free(ptr, &layout, problem);
+                }
+                #[cfg(feature = "slab-magazines")]
+                {
+                    if magazines::contains(class, ptr) {
+                        report_bad_free(ptr, &layout, "double free");
+                    }
+                    magazines::free(self, class, ptr);
+                }
+                #[cfg(not(feature = "slab-magazines"))]
+                self.lock().free_block(ptr);
+                COUNTERS[class].on_free();
+            }
+            None => {
+                if let Err(problem) = SlabAllocator::check_large_free(ptr) {
+                    report_bad_free(ptr, &layout, problem);
+                }
+                let ptr = NonNull::new(ptr).unwrap();
+                self.lock().fallback_allocator.deallocate(ptr, layout);
+                COUNTERS[CLASS_COUNT].on_free();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct TestHeap([u8; 8 * SLAB_SIZE]);
+
+#[cfg(test)]
+static mut TEST_HEAP: TestHeap = TestHeap([0; 8 * SLAB_SIZE]);
+
+/// A slab allocator of its own, over a zeroed `TEST_HEAP`.
+#[cfg(test)]
+unsafe fn test_allocator() -> SlabAllocator {
+    let heap = ptr::addr_of_mut!(TEST_HEAP);
+    ptr::write_bytes(heap, 0, 1);
+    let mut slabs = SlabAllocator::new();
+    slabs.init(heap as usize, mem::size_of::<TestHeap>());
+    slabs
+}
+
+#[test_case]
+fn test_bad_frees_detected() {
+    unsafe {
+        let mut slabs = test_allocator();
+        let small = class_index(&Layout::new::<u64>()).unwrap();
+        let large = class_index(&Layout::new::<[u64; 8]>()).unwrap();
+        let block = slabs.alloc_block(small);
+        assert_eq!(
+            SlabAllocator::check_free(block, large),
+            Err("freed with a layout of the wrong size class")
+        );
+        assert_eq!(SlabAllocator::check_free(block, small), Ok(()));
+        slabs.free_block(block);
+        assert_eq!(SlabAllocator::check_free(block, small), Err("double free"));
+
+        let big = slabs.fallback_alloc(Layout::from_size_align(2 * SLAB_SIZE, 8).unwrap());
+        assert_eq!(SlabAllocator::check_large_free(big), Ok(()));
+        assert!(SlabAllocator::check_large_free(block).is_err());
+    }
+}
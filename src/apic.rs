@@ -0,0 +1,90 @@
+//! Just enough of the local APIC for `deterministic` mode:  delivering the performance counter's
+//! overflow interrupt.
+//!
+//! Device interrupts still come from the PIC, through the local APIC's LINT0 pin, which the
+//! firmware leaves in virtual wire mode and `init` doesn't touch.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{fmt, ptr};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::memory::vma::{self, VmaError};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+const EOI: u64 = 0xb0;
+const SPURIOUS_VECTOR: u64 = 0xf0;
+const LVT_PERFORMANCE_COUNTER: u64 = 0x340;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const SPURIOUS_INTERRUPT_VECTOR: u32 = 0xff;
+/// Set in an LVT entry by the APIC when it delivers a performance counter interrupt, and cleared
+/// to let the next one through.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Where the registers are mapped, or 0 before `init`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID says there's no local APIC, or the firmware turned it off.
+    Missing,
+    Map(VmaError),
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApicError::Missing => write!(f, "there's no local APIC"),
+            ApicError::Map(error) => write!(f, "couldn't map the local APIC: {:?}", error),
+        }
+    }
+}
+
+/// Maps the local APIC's registers and software-enables it.
+pub fn init() -> Result<(), ApicError> {
+    if unsafe { __cpuid(1) }.edx & (1 << 9) == 0 {
+        return Err(ApicError::Missing);
+    }
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if base & APIC_BASE_ENABLE == 0 {
+        return Err(ApicError::Missing);
+    }
+
+    let registers = vma::map_mmio(
+        PhysAddr::new(base & 0x000f_ffff_ffff_f000),
+        4096,
+        "local apic",
+    )
+    .map_err(ApicError::Map)?;
+    BASE.store(registers.as_u64(), Ordering::SeqCst);
+    unsafe { write(SPURIOUS_VECTOR, SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR) };
+    Ok(())
+}
+
+unsafe fn read(offset: u64) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + offset) as *const u32)
+}
+
+unsafe fn write(offset: u64, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u32, value)
+}
+
+/// Delivers the performance counter's overflow interrupt on `vector`.
+pub fn set_performance_counter_vector(vector: u8) {
+    unsafe { write(LVT_PERFORMANCE_COUNTER, vector as u32) };
+}
+
+/// Acknowledges a performance counter interrupt, which lets the next one through.
+pub fn end_performance_counter_interrupt() {
+    unsafe {
+        let entry = read(LVT_PERFORMANCE_COUNTER);
+        write(LVT_PERFORMANCE_COUNTER, entry & !LVT_MASKED);
+        write(EOI, 0);
+    }
+}
@@ -0,0 +1,55 @@
+use core::ffi::c_void;
+
+use unwinding::abi::{_Unwind_Backtrace, _Unwind_GetIP, UnwindContext, UnwindReasonCode};
+
+struct Capture<'a> {
+    skip: usize,
+    frames: &'a mut [usize],
+    len: usize,
+    stop_at: usize,
+}
+
+extern "C" fn trace_frame(ctx: &UnwindContext<'_>, arg: *mut c_void) -> UnwindReasonCode {
+    let capture = unsafe { &mut *(arg as *mut Capture) };
+    if capture.skip > 0 {
+        capture.skip -= 1;
+        return UnwindReasonCode::NO_REASON;
+    }
+
+    let ip = _Unwind_GetIP(ctx);
+    if capture.len == capture.frames.len() || ip == capture.stop_at {
+        return UnwindReasonCode::NORMAL_STOP;
+    }
+    capture.frames[capture.len] = ip;
+    capture.len += 1;
+    UnwindReasonCode::NO_REASON
+}
+
+/// Walks the current stack with the `.eh_frame` unwinder, writing return addresses into `frames`.
+///
+/// The innermost `skip` frames (starting with `capture` itself) are left out.  Returns how many
+/// frames were written.  Doesn't allocate, so it's safe to call from the allocator and from
+/// exception handlers.
+#[inline(never)]
+pub fn capture(skip: usize, frames: &mut [usize]) -> usize {
+    walk(skip, frames, 0)
+}
+
+/// Like `capture`, but stops at the frame returning to `stop_at`, leaving it and everything
+/// outside it out.
+#[inline(never)]
+pub fn capture_until(skip: usize, frames: &mut [usize], stop_at: usize) -> usize {
+    walk(skip, frames, stop_at)
+}
+
+#[inline(always)]
+fn walk(skip: usize, frames: &mut [usize], stop_at: usize) -> usize {
+    let mut capture = Capture {
+        skip,
+        frames,
+        len: 0,
+        stop_at,
+    };
+    _Unwind_Backtrace(trace_frame, &mut capture as *mut Capture as *mut c_void);
+    capture.len
+}
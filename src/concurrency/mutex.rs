@@ -1,6 +1,8 @@
 use crate::concurrency::semaphore::{Semaphore, SemaphoreGuard};
 use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
 
 pub struct Mutex<T> {
     pub semaphore: Semaphore,
@@ -28,8 +30,24 @@ impl<T> Mutex<T> {
         let guard = self.semaphore.acquire(1);
 
         MutexGuard {
-            _inner: guard,
+            inner: ManuallyDrop::new(guard),
             reference: unsafe { &mut *self.datum.get() },
+            enable_interrupts: false,
+        }
+    }
+
+    /// Like `lock`, but with interrupts disabled until the guard is dropped, so that whoever holds
+    /// the lock can't be preempted.  For locks that interrupt handlers take:  a handler waiting for
+    /// a task it interrupted would wait forever.
+    pub fn lock_without_interrupts(&self) -> MutexGuard<T> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        let guard = self.semaphore.acquire(1);
+
+        MutexGuard {
+            inner: ManuallyDrop::new(guard),
+            reference: unsafe { &mut *self.datum.get() },
+            enable_interrupts,
         }
     }
 
@@ -39,15 +57,27 @@ impl<T> Mutex<T> {
 
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
         Some(MutexGuard {
-            _inner: self.semaphore.try_acquire(1)?,
+            inner: ManuallyDrop::new(self.semaphore.try_acquire(1)?),
             reference: unsafe { &mut *self.datum.get() },
+            enable_interrupts: false,
         })
     }
 }
 
 pub struct MutexGuard<'a, T> {
-    _inner: SemaphoreGuard<'a>,
+    inner: ManuallyDrop<SemaphoreGuard<'a>>,
     reference: &'a mut T,
+    /// Whether dropping the guard turns interrupts back on, after unlocking.
+    enable_interrupts: bool,
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.inner) };
+        if self.enable_interrupts {
+            interrupts::enable();
+        }
+    }
 }
 
 impl<'a, T> Deref for MutexGuard<'a, T> {
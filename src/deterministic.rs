@@ -0,0 +1,302 @@
+//! Deterministic mode, in which runs with the same seed and input go exactly the same way, task
+//! switches included.
+//!
+//! Normally tasks are switched on PIT ticks, which land wherever the host's timing puts them, and
+//! time comes from the TSC.  In deterministic mode the PIC's lines are all masked, and tasks are
+//! switched every `QUANTUM` retired instructions instead, counted by general-purpose performance
+//! counter 0, whose overflow interrupt comes through the local APIC.  `time::ticks` counts retired
+//! instructions too.  Programs in user mode that read the TSC get the same count:  CR4.TSD makes
+//! `rdtsc` and `rdtscp` fault there, and the general protection fault handler calls
+//! `emulate_rdtsc`.
+//!
+//! The overflow interrupt arrives a varying number of instructions late, so the counter is set to
+//! overflow `SKID_MARGIN` instructions early and the handler single-steps the rest of the way,
+//! counting the steps.  The instructions the trampoline and handler run before the counter stops
+//! are counted as well, but there are always as many of them, so the task is switched at the same
+//! instruction every run.  A task isn't switched while it has interrupts disabled, which a timer
+//! interrupt couldn't do either:  stepping carries on until it enables them.
+//!
+//! This needs an Intel CPU with architectural performance monitoring, which in QEMU means KVM and
+//! `-cpu host`, since TCG doesn't emulate the counters.  It's turned on by the
+//! `opt/barefuzz/deterministic` fw_cfg file.  What the host sends over serial arrives whenever it
+//! arrives, and `guest` programs run in kernel mode, where `rdtsc` reads the real TSC, so neither
+//! is covered.
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::SeqCst;
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::registers::rflags::RFlags;
+
+use crate::apic::{self, ApicError};
+use crate::interrupts::{InterruptFrame, StandardContext, PICS};
+use crate::task::executor;
+use crate::{eprintln, fw_cfg, time, INITIALISED};
+
+/// The interrupt the performance counter raises when it overflows.
+pub const PERFORMANCE_COUNTER_VECTOR: u8 = 0x40;
+
+/// How many instructions a task runs between switches.
+pub const QUANTUM: u64 = 10_000_000;
+/// How many instructions early the counter overflows, which has to cover however late the
+/// interrupt arrives plus the instructions before the handler stops the counter.
+const SKID_MARGIN: u64 = 256;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+// IA32_PERFEVTSEL0:  instructions retired, in both rings, interrupting on overflow
+const INSTRUCTIONS_RETIRED: u64 = 0xc0;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The bits the counter has.
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+/// Instructions retired up to when the counter was last started.
+static RETIRED: AtomicU64 = AtomicU64::new(0);
+/// What the counter was last started at.
+static COUNTER_START: AtomicU64 = AtomicU64::new(0);
+/// Whether the counter is running, rather than stopped for single-stepping.
+static COUNTING: AtomicBool = AtomicBool::new(false);
+/// Whether the debug exception handler is stepping towards `NEXT_SWITCH`.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+/// The instruction count to switch tasks at.
+static NEXT_SWITCH: AtomicU64 = AtomicU64::new(0);
+static OVERSHOT: AtomicBool = AtomicBool::new(false);
+/// How many instructions too late the first late switch came, until `report_overshoot` prints it.
+static OVERSHOOT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum Unsupported {
+    /// CPUID leaf 0xa reports no general-purpose performance counters.
+    NoPerformanceCounters,
+    /// The counters can't count instructions retired.
+    NoInstructionsRetired,
+    Apic(ApicError),
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsupported::NoPerformanceCounters => {
+                write!(
+                    f,
+                    "the CPU has no performance counters (is KVM on, with -cpu host?)"
+                )
+            }
+            Unsupported::NoInstructionsRetired => {
+                write!(
+                    f,
+                    "the performance counters can't count instructions retired"
+                )
+            }
+            Unsupported::Apic(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Whether the `opt/barefuzz/deterministic` fw_cfg file asks for deterministic mode.
+pub fn requested() -> bool {
+    fw_cfg::read_file("opt/barefuzz/deterministic").is_some()
+}
+
+/// Whether deterministic mode is on.
+pub fn enabled() -> bool {
+    ENABLED.load(SeqCst)
+}
+
+/// Turns deterministic mode on, from now until the kernel stops.  Needs the heap, for mapping the
+/// local APIC, and should come before anything measures time.
+pub fn enable() -> Result<(), Unsupported> {
+    let leaf = unsafe { __cpuid(0xa) };
+    let (version, counters, width) = (
+        leaf.eax & 0xff,
+        (leaf.eax >> 8) & 0xff,
+        (leaf.eax >> 16) & 0xff,
+    );
+    if version < 2 || counters == 0 {
+        return Err(Unsupported::NoPerformanceCounters);
+    }
+    // EBX has a bit set for each architectural event that isn't available, within the first
+    // EAX[31:24] of them.  Instructions retired is bit 1
+    if (leaf.eax >> 24) < 2 || leaf.ebx & (1 << 1) != 0 {
+        return Err(Unsupported::NoInstructionsRetired);
+    }
+    apic::init().map_err(Unsupported::Apic)?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        apic::set_performance_counter_vector(PERFORMANCE_COUNTER_VECTOR);
+        unsafe {
+            PICS.lock().disable();
+            Cr4::update(|flags| flags.insert(Cr4Flags::TIMESTAMP_DISABLE));
+            Msr::new(IA32_PERF_GLOBAL_CTRL).write(0);
+            Msr::new(IA32_PERFEVTSEL0)
+                .write(INSTRUCTIONS_RETIRED | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+        }
+        COUNTER_MASK.store((1 << width) - 1, SeqCst);
+        NEXT_SWITCH.store(QUANTUM, SeqCst);
+        ENABLED.store(true, SeqCst);
+        time::use_virtual_clock();
+        start_counter(QUANTUM - SKID_MARGIN);
+    });
+    Ok(())
+}
+
+/// Starts the counter, set to overflow after `instructions` more.
+fn start_counter(instructions: u64) {
+    let start = instructions.wrapping_neg() & COUNTER_MASK.load(SeqCst);
+    COUNTER_START.store(start, SeqCst);
+    COUNTING.store(true, SeqCst);
+    unsafe {
+        // only the low 32 bits can be written, and are sign-extended, which suits a negative count
+        Msr::new(IA32_PMC0).write(start);
+        Msr::new(IA32_PERF_GLOBAL_CTRL).write(1);
+    }
+}
+
+/// How far the counter has got since it was started.
+fn counted() -> u64 {
+    let now = unsafe { Msr::new(IA32_PMC0).read() };
+    now.wrapping_sub(COUNTER_START.load(SeqCst)) & COUNTER_MASK.load(SeqCst)
+}
+
+/// Stops the counter, adding what it counted to `RETIRED`.
+fn stop_counter() {
+    unsafe { Msr::new(IA32_PERF_GLOBAL_CTRL).write(0) };
+    RETIRED.fetch_add(counted(), SeqCst);
+    COUNTING.store(false, SeqCst);
+}
+
+/// Instructions retired since deterministic mode was turned on, the ticks of `time`'s virtual
+/// clock.
+pub fn instructions_retired() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let retired = RETIRED.load(SeqCst);
+        match COUNTING.load(SeqCst) {
+            true => retired + counted(),
+            false => retired,
+        }
+    })
+}
+
+/// Prints a warning if a task switch came late since the last call, which means the run may not
+/// repeat.  For a task to call now and then, since the interrupt handler can't print.
+pub fn report_overshoot() {
+    match OVERSHOOT.swap(0, SeqCst) {
+        0 => {}
+        late_by => eprintln!(
+            "Deterministic mode: the performance counter interrupt came {} instructions too late, \
+             so this run may not repeat",
+            late_by
+        ),
+    }
+}
+
+pub extern "C" fn performance_counter_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) {
+    stop_counter();
+    unsafe { Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1) };
+    apic::end_performance_counter_interrupt();
+
+    let retired = RETIRED.load(SeqCst);
+    let next = NEXT_SWITCH.load(SeqCst);
+    // printing could wait forever on a lock the interrupted task holds
+    if retired > next && !OVERSHOT.swap(true, SeqCst) {
+        OVERSHOOT.store(retired - next, SeqCst);
+    }
+    step_towards_switch(interrupt_frame, ctx);
+}
+
+pub extern "C" fn debug_handler(interrupt_frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    if !STEPPING.load(SeqCst) {
+        // a trap flag left behind, e.g. by a `popf`
+        interrupt_frame.cpu_flags.remove(RFlags::TRAP_FLAG);
+        return;
+    }
+    RETIRED.fetch_add(1, SeqCst);
+    step_towards_switch(interrupt_frame, ctx);
+}
+
+/// Switches tasks if the count has reached `NEXT_SWITCH` and the interrupted code has interrupts
+/// enabled, and otherwise single-steps it one more instruction.
+fn step_towards_switch(interrupt_frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let retired = RETIRED.load(SeqCst);
+    if retired < NEXT_SWITCH.load(SeqCst)
+        || !interrupt_frame.cpu_flags.contains(RFlags::INTERRUPT_FLAG)
+    {
+        interrupt_frame.cpu_flags.insert(RFlags::TRAP_FLAG);
+        STEPPING.store(true, SeqCst);
+        return;
+    }
+
+    // the task's saved context mustn't step when it's resumed
+    interrupt_frame.cpu_flags.remove(RFlags::TRAP_FLAG);
+    STEPPING.store(false, SeqCst);
+    NEXT_SWITCH.store(retired + QUANTUM, SeqCst);
+    if INITIALISED.load(SeqCst) {
+        executor::preempt(interrupt_frame, ctx);
+    }
+    start_counter(QUANTUM - SKID_MARGIN);
+}
+
+/// The length of the instruction at the start of `code` if it's `rdtsc` or `rdtscp`, and whether
+/// it's `rdtscp`.
+fn decode_rdtsc(code: &[u8]) -> Option<(u64, bool)> {
+    match code {
+        [0x0f, 0x31, ..] => Some((2, false)),
+        [0x0f, 0x01, 0xf9, ..] => Some((3, true)),
+        _ => None,
+    }
+}
+
+/// Emulates `rdtsc` or `rdtscp` with the virtual clock, if that's what made a program in user mode
+/// fault.  Returns whether it did.
+pub fn emulate_rdtsc(interrupt_frame: &mut InterruptFrame, ctx: &mut StandardContext) -> bool {
+    if !enabled() || !interrupt_frame.from_user_mode() {
+        return false;
+    }
+
+    // the faulting instruction was fetched, so as much of it as we look at is mapped
+    let rip = interrupt_frame.instruction_pointer as *const u8;
+    let mut code = [0; 3];
+    for (i, byte) in code.iter_mut().enumerate() {
+        *byte = unsafe { rip.add(i).read_volatile() };
+        // only read on while it could still be one of them
+        if [0x0f, 0x01].get(i) != Some(byte) {
+            break;
+        }
+    }
+    let (len, rdtscp) = match decode_rdtsc(&code) {
+        Some(decoded) => decoded,
+        None => return false,
+    };
+
+    let ticks = time::ticks();
+    ctx.rax = ticks as u32 as usize;
+    ctx.rdx = (ticks >> 32) as usize;
+    if rdtscp {
+        // IA32_TSC_AUX, which is the CPU's number
+        ctx.rcx = 0;
+    }
+    interrupt_frame.instruction_pointer += len;
+    true
+}
+
+#[test_case]
+fn test_decode_rdtsc() {
+    assert_eq!(decode_rdtsc(&[0x0f, 0x31, 0x90]), Some((2, false)));
+    assert_eq!(decode_rdtsc(&[0x0f, 0x01, 0xf9]), Some((3, true)));
+    assert_eq!(decode_rdtsc(&[0x0f, 0x01, 0xd0]), None);
+    assert_eq!(decode_rdtsc(&[0x0f, 0x0b, 0x00]), None);
+}
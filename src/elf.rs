@@ -0,0 +1,328 @@
+//! Reading statically linked x86_64 ELF executables, for `task::loader`, and the kernel's own
+//! program headers, for `memory::protect`.
+//!
+//! Only what loading needs is read:  the file header, the program headers, and the `RELA`
+//! relocations of static-PIE executables.  Everything is bounds checked when the file is parsed,
+//! so the rest of the loader can trust the offsets and sizes it gets back.
+
+use alloc::vec::Vec;
+use core::{fmt, slice};
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+pub const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+const HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+const DYNAMIC_ENTRY_LEN: usize = 16;
+const RELA_LEN: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Something the headers point at lies past the end of the file.
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit little-endian x86_64 file.
+    WrongArchitecture,
+    /// Neither an executable nor a position independent one.
+    NotExecutable(u16),
+    /// The executable asks for a dynamic linker.
+    DynamicallyLinked,
+    /// A segment's sizes or addresses don't make sense.
+    BadSegment(usize),
+    /// The dynamic section describes relocations that can't be found or read.
+    BadRelocations,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::WrongArchitecture => write!(f, "not a 64-bit little-endian x86_64 file"),
+            ElfError::NotExecutable(kind) => write!(f, "ELF type {} isn't executable", kind),
+            ElfError::DynamicallyLinked => write!(f, "executable is dynamically linked"),
+            ElfError::BadSegment(index) => write!(f, "program header {} is invalid", index),
+            ElfError::BadRelocations => write!(f, "relocations are invalid"),
+        }
+    }
+}
+
+fn bytes_at(data: &[u8], offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    data.get(offset as usize..end as usize)
+        .ok_or(ElfError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// A program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,
+    /// `PF_*` bits.
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+impl Segment {
+    fn parse(header: &[u8]) -> Self {
+        Self {
+            kind: u32_at(header, 0),
+            flags: u32_at(header, 4),
+            offset: u64_at(header, 8),
+            vaddr: u64_at(header, 16),
+            file_size: u64_at(header, 32),
+            memory_size: u64_at(header, 40),
+        }
+    }
+
+    pub fn contains(&self, vaddr: u64) -> bool {
+        (self.vaddr..self.vaddr + self.memory_size).contains(&vaddr)
+    }
+}
+
+/// The program headers of an executable that is already loaded, like the kernel, read from memory
+/// rather than from its file.
+///
+/// # Safety
+///
+/// `image` must be where the file header was loaded, with the program headers loaded after it at
+/// the offset the header gives.
+pub unsafe fn loaded_segments(image: *const u8) -> Vec<Segment> {
+    let header = slice::from_raw_parts(image, HEADER_LEN);
+    let offset = u64_at(header, 32) as usize;
+    let entry_size = u16_at(header, 54) as usize;
+    let count = u16_at(header, 56) as usize;
+    let table = slice::from_raw_parts(image.add(offset), entry_size * count);
+    table.chunks_exact(entry_size).map(Segment::parse).collect()
+}
+
+/// A relocation to apply at `offset`, relative to where the executable was loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u64,
+    /// `R_X86_64_*`.
+    pub kind: u32,
+    pub addend: i64,
+}
+
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    /// Whether the executable can be loaded anywhere (`ET_DYN`), rather than only at the
+    /// addresses in its program headers.
+    pub position_independent: bool,
+    pub entry: u64,
+    /// Where the program headers are in the file, and the size of each.
+    pub program_headers_offset: u64,
+    pub program_header_size: u64,
+    pub segments: Vec<Segment>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header = bytes_at(data, 0, HEADER_LEN as u64)?;
+        if header[..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        // 64-bit, little endian, x86_64
+        if header[4] != 2 || header[5] != 1 || u16_at(header, 18) != EM_X86_64 {
+            return Err(ElfError::WrongArchitecture);
+        }
+        let position_independent = match u16_at(header, 16) {
+            ET_EXEC => false,
+            ET_DYN => true,
+            kind => return Err(ElfError::NotExecutable(kind)),
+        };
+
+        let program_headers_offset = u64_at(header, 32);
+        let entry_size = u16_at(header, 54) as u64;
+        let count = u16_at(header, 56) as u64;
+        if entry_size < PROGRAM_HEADER_LEN as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let table = bytes_at(data, program_headers_offset, entry_size * count)?;
+
+        let mut segments = Vec::with_capacity(count as usize);
+        for (index, header) in table.chunks_exact(entry_size as usize).enumerate() {
+            let segment = Segment::parse(header);
+            let fits_in_file = bytes_at(data, segment.offset, segment.file_size).is_ok();
+            let fits_in_memory = segment.vaddr.checked_add(segment.memory_size).is_some();
+            if segment.kind == PT_LOAD
+                && (!fits_in_file || !fits_in_memory || segment.file_size > segment.memory_size)
+            {
+                return Err(ElfError::BadSegment(index));
+            }
+            if segment.kind == PT_INTERP {
+                return Err(ElfError::DynamicallyLinked);
+            }
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            data,
+            position_independent,
+            entry: u64_at(header, 24),
+            program_headers_offset,
+            program_header_size: entry_size,
+            segments,
+        })
+    }
+
+    pub fn loadable(&self) -> impl Iterator<Item = &Segment> + '_ {
+        self.segments
+            .iter()
+            .filter(|segment| segment.kind == PT_LOAD)
+    }
+
+    /// The part of the file a segment is loaded from.  Past its end the segment is zeroed.
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        bytes_at(self.data, segment.offset, segment.file_size).unwrap_or_default()
+    }
+
+    /// The lowest and highest address of any loadable segment, or `None` if there aren't any.
+    pub fn address_range(&self) -> Option<(u64, u64)> {
+        let start = self.loadable().map(|segment| segment.vaddr).min()?;
+        let end = self
+            .loadable()
+            .map(|segment| segment.vaddr + segment.memory_size)
+            .max()?;
+        Some((start, end))
+    }
+
+    /// Where the program headers end up in memory, if a loadable segment covers them.
+    pub fn program_headers_vaddr(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments.iter().find(|s| s.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.loadable()
+            .find(|segment| {
+                (segment.offset..segment.offset + segment.file_size)
+                    .contains(&self.program_headers_offset)
+            })
+            .map(|segment| segment.vaddr + self.program_headers_offset - segment.offset)
+    }
+
+    /// Reads `len` bytes the file loads at `vaddr`.
+    fn bytes_at_vaddr(&self, vaddr: u64, len: u64) -> Result<&'a [u8], ElfError> {
+        let segment = self
+            .loadable()
+            .find(|segment| segment.contains(vaddr))
+            .ok_or(ElfError::BadRelocations)?;
+        let end = (vaddr - segment.vaddr).checked_add(len);
+        if end.map_or(true, |end| end > segment.file_size) {
+            return Err(ElfError::BadRelocations);
+        }
+        bytes_at(self.data, segment.offset + (vaddr - segment.vaddr), len)
+    }
+
+    /// The `RELA` relocations listed in the dynamic section, if there is one.
+    pub fn relocations(&self) -> Result<Vec<Relocation>, ElfError> {
+        let dynamic = match self.segments.iter().find(|s| s.kind == PT_DYNAMIC) {
+            Some(dynamic) => bytes_at(self.data, dynamic.offset, dynamic.file_size)?,
+            None => return Ok(Vec::new()),
+        };
+
+        let (mut table, mut size, mut entry_size) = (None, 0, RELA_LEN as u64);
+        for entry in dynamic.chunks_exact(DYNAMIC_ENTRY_LEN) {
+            let value = u64_at(entry, 8);
+            match u64_at(entry, 0) {
+                DT_NULL => break,
+                DT_RELA => table = Some(value),
+                DT_RELASZ => size = value,
+                DT_RELAENT => entry_size = value,
+                _ => {}
+            }
+        }
+        let table = match table {
+            Some(table) if entry_size >= RELA_LEN as u64 => self.bytes_at_vaddr(table, size)?,
+            Some(_) => return Err(ElfError::BadRelocations),
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(table
+            .chunks_exact(entry_size as usize)
+            .map(|rela| Relocation {
+                offset: u64_at(rela, 0),
+                kind: u64_at(rela, 8) as u32,
+                addend: u64_at(rela, 16) as i64,
+            })
+            .collect())
+    }
+}
+
+#[test_case]
+fn test_parse_static_pie() {
+    use alloc::vec;
+
+    // a header, one PT_LOAD covering the whole file, a PT_DYNAMIC, and a relocation
+    let mut file = vec![0u8; 0x200];
+    file[..4].copy_from_slice(b"\x7fELF");
+    file[4] = 2;
+    file[5] = 1;
+    file[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+    file[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    file[24..32].copy_from_slice(&0x1040u64.to_le_bytes());
+    file[32..40].copy_from_slice(&64u64.to_le_bytes());
+    file[54..56].copy_from_slice(&(PROGRAM_HEADER_LEN as u16).to_le_bytes());
+    file[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+    let mut put = |offset: usize, values: &[u64]| {
+        for (i, value) in values.iter().enumerate() {
+            file[offset + i * 8..offset + i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+        }
+    };
+    // type and flags packed into one word, then offset, vaddr, paddr, file and memory size
+    put(64, &[PT_LOAD as u64 | 5 << 32, 0, 0x1000, 0, 0x200, 0x300]);
+    put(120, &[PT_DYNAMIC as u64, 0x100, 0x1100, 0, 0x40, 0x40]);
+    put(0x100, &[DT_RELA, 0x1180, DT_RELASZ, 24, DT_NULL, 0]);
+    put(0x180, &[0x1190, R_X86_64_RELATIVE as u64, 0x1040]);
+
+    let elf = Elf::parse(&file).unwrap();
+    assert!(elf.position_independent);
+    assert_eq!(elf.entry, 0x1040);
+    assert_eq!(elf.address_range(), Some((0x1000, 0x1300)));
+    assert_eq!(elf.program_headers_vaddr(), Some(0x1040));
+    assert_eq!(
+        elf.relocations().unwrap(),
+        [Relocation {
+            offset: 0x1190,
+            kind: R_X86_64_RELATIVE,
+            addend: 0x1040
+        }]
+    );
+
+    file[0] = 0;
+    assert_eq!(Elf::parse(&file).unwrap_err(), ElfError::BadMagic);
+}
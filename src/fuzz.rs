@@ -0,0 +1,29 @@
+//! In-kernel fuzzing.
+//!
+//! Fuzz targets are registered with `fuzz_target!` and compiled into the kernel, taking raw bytes
+//! or values built by `arbitrary`.  A `guest` program can be fuzzed too, driving the loop itself
+//! through hypercalls and rewinding to a `snapshot` between inputs, and so can a static Linux
+//! executable, which `program` runs on each input.  `runner` spawns worker tasks which mutate
+//! inputs from the shared `corpus`, run the selected target on them, and keep the ones that reach
+//! new edges according to `coverage`, or new blocks according to `breakpoints` for programs that
+//! aren't instrumented.  Comparisons recorded by `cmplog` and tokens from
+//! `dictionary` let the mutator get past magic values.  Inputs that make the target panic or fault
+//! are caught and bucketed by `crash`, and new crashes are shrunk by `minimise`.  `stats` keeps the
+//! status panel up to date.  `replay` runs a single input instead, to confirm a crash.
+
+pub mod arbitrary;
+pub mod breakpoints;
+pub mod cmplog;
+pub mod corpus;
+pub mod coverage;
+pub mod crash;
+pub mod dictionary;
+pub mod guest;
+pub mod harness;
+pub mod minimise;
+pub mod mutator;
+pub mod program;
+pub mod replay;
+pub mod runner;
+pub mod snapshot;
+pub mod stats;
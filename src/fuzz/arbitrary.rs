@@ -0,0 +1,269 @@
+//! Building typed values out of fuzz inputs, for structure-aware fuzzing.
+//!
+//! Harnesses taking structured values implement `Arbitrary` for them, usually with
+//! `#[derive(Arbitrary)]` from `barefuzz-derive`, and register a target taking the type instead of
+//! `&[u8]`:
+//!
+//! ```ignore
+//! #[derive(Debug, Arbitrary)]
+//! enum Op {
+//!     Open { path: String, flags: u32 },
+//!     Write(u32, Vec<u8>),
+//!     Close(u32),
+//! }
+//!
+//! fuzz_target!(filesystem, |ops: Vec<Op>| run_ops(&ops));
+//! ```
+//!
+//! Values are read from the front of the input by an `Unstructured`.  Running out of input isn't
+//! an error:  integers read as zero, and collections stop growing, so every input decodes to
+//! something and mutations of any byte change the value.  Collections read a continuation byte
+//! before each element, which keeps the elements that follow intact when the mutator inserts or
+//! deletes bytes in one of them.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub use barefuzz_derive::Arbitrary;
+
+/// Why a value couldn't be built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The input doesn't describe a valid value of the type, e.g. for a hand-written
+    /// implementation rejecting it.
+    IncorrectFormat,
+    /// There's no input left and the type can't be empty.
+    NotEnoughData,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IncorrectFormat => write!(f, "input doesn't describe a valid value"),
+            Error::NotEnoughData => write!(f, "not enough input for a value"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The part of a fuzz input that hasn't been turned into values yet.
+#[derive(Debug)]
+pub struct Unstructured<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Unstructured<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Builds a `T` from the front of the input.
+    pub fn arbitrary<T: Arbitrary>(&mut self) -> Result<T> {
+        T::arbitrary(self)
+    }
+
+    /// Takes up to `len` bytes, fewer if there aren't that many left.
+    pub fn take(&mut self, len: usize) -> &'a [u8] {
+        let (taken, rest) = self.data.split_at(len.min(self.data.len()));
+        self.data = rest;
+        taken
+    }
+
+    /// Fills `buffer` from the input, with zeros once it runs out.
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        let taken = self.take(buffer.len());
+        buffer[..taken.len()].copy_from_slice(taken);
+        buffer[taken.len()..].fill(0);
+    }
+
+    /// Picks an index below `len`, e.g. an enum variant.  Fails if `len` is 0.
+    pub fn choose_index(&mut self, len: usize) -> Result<usize> {
+        if len == 0 {
+            return Err(Error::NotEnoughData);
+        }
+        Ok((u32::arbitrary(self)? as usize) % len)
+    }
+
+    /// Picks a length for a run of bytes, no longer than what's left.  Takes as few bytes as will
+    /// do, so that short inputs aren't all spent on lengths.
+    pub fn byte_len(&mut self) -> usize {
+        let max = self.data.len() as u64;
+        let len = if max <= u8::MAX as u64 + 1 {
+            u8::arbitrary(self).unwrap() as u64
+        } else if max <= u16::MAX as u64 + 1 {
+            u16::arbitrary(self).unwrap() as u64
+        } else {
+            u32::arbitrary(self).unwrap() as u64
+        };
+        (len % (self.data.len() as u64 + 1)) as usize
+    }
+
+    /// Reads a continuation byte, saying whether a collection has another element.  Always
+    /// false once the input runs out.
+    pub fn more(&mut self) -> bool {
+        !self.is_empty() && self.take(1)[0] & 1 == 1
+    }
+}
+
+/// A type that can be built from a fuzz input.
+pub trait Arbitrary: Sized {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self>;
+}
+
+macro_rules! impl_arbitrary_for_int {
+    ($($int:ty),*) => {
+        $(
+            impl Arbitrary for $int {
+                fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+                    let mut bytes = [0; core::mem::size_of::<$int>()];
+                    u.fill(&mut bytes);
+                    Ok(<$int>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_arbitrary_for_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl Arbitrary for () {
+    fn arbitrary(_: &mut Unstructured) -> Result<Self> {
+        Ok(())
+    }
+}
+
+impl Arbitrary for bool {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        Ok(u8::arbitrary(u)? & 1 == 1)
+    }
+}
+
+impl Arbitrary for char {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        let code = u32::arbitrary(u)? % (char::MAX as u32 + 1);
+        Ok(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Option<T> {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        Ok(match bool::arbitrary(u)? {
+            true => Some(T::arbitrary(u)?),
+            false => None,
+        })
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Box<T> {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        T::arbitrary(u).map(Box::new)
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Vec<T> {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        let mut elements = Vec::new();
+        while u.more() {
+            elements.push(T::arbitrary(u)?);
+        }
+        Ok(elements)
+    }
+}
+
+impl<T: Arbitrary, const N: usize> Arbitrary for [T; N] {
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        let mut elements = Vec::with_capacity(N);
+        for _ in 0..N {
+            elements.push(T::arbitrary(u)?);
+        }
+        Ok(elements.try_into().ok().unwrap())
+    }
+}
+
+impl Arbitrary for String {
+    /// Takes a run of bytes and keeps as much of it as is valid UTF-8.
+    fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+        let len = u.byte_len();
+        let bytes = u.take(len);
+        let valid = match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap(),
+        };
+        Ok(String::from(valid))
+    }
+}
+
+macro_rules! impl_arbitrary_for_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Arbitrary),+> Arbitrary for ($($name,)+) {
+            fn arbitrary(u: &mut Unstructured) -> Result<Self> {
+                Ok(($($name::arbitrary(u)?,)+))
+            }
+        }
+    };
+}
+
+impl_arbitrary_for_tuple!(A);
+impl_arbitrary_for_tuple!(A B);
+impl_arbitrary_for_tuple!(A B C);
+impl_arbitrary_for_tuple!(A B C D);
+impl_arbitrary_for_tuple!(A B C D E);
+impl_arbitrary_for_tuple!(A B C D E F);
+
+#[test_case]
+fn test_arbitrary_primitives() {
+    let mut u = Unstructured::new(&[0x34, 0x12, 0x01, 0xff]);
+    assert_eq!(u.arbitrary::<u16>(), Ok(0x1234));
+    assert_eq!(u.arbitrary::<bool>(), Ok(true));
+    // runs out partway, so the missing bytes are zero
+    assert_eq!(u.arbitrary::<u32>(), Ok(0xff));
+    assert_eq!(u.arbitrary::<Vec<u8>>(), Ok(Vec::new()));
+}
+
+#[test_case]
+fn test_derive_arbitrary() {
+    #[derive(Debug, PartialEq, Arbitrary)]
+    enum Op {
+        Nop,
+        Write { fd: u8, data: Vec<u8> },
+        Close(u8),
+    }
+
+    #[derive(Debug, PartialEq, Arbitrary)]
+    struct Program {
+        version: u8,
+        ops: Vec<Op>,
+    }
+
+    let input = [
+        7, // version
+        1, 1, 0, 0, 0, 3, 1, 0xaa, 1, 0xbb, 0, // Write { fd: 3, data: [0xaa, 0xbb] }
+        1, 2, 0, 0, 0, 4, // Close(4)
+        0, // no more ops
+    ];
+    let program = Unstructured::new(&input).arbitrary::<Program>().unwrap();
+    assert_eq!(
+        program,
+        Program {
+            version: 7,
+            ops: alloc::vec![
+                Op::Write {
+                    fd: 3,
+                    data: alloc::vec![0xaa, 0xbb]
+                },
+                Op::Close(4)
+            ],
+        }
+    );
+}
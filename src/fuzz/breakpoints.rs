@@ -0,0 +1,256 @@
+//! Coverage for code that isn't instrumented, from breakpoints:  what the `linux_program` target
+//! gets for the program it runs.
+//!
+//! `init` makes a table of the program's basic blocks, from the `opt/barefuzz/program-blocks`
+//! fw_cfg file if there is one, and otherwise from a linear sweep of its executable segments with
+//! `decode`.  `arm` puts an `int3` over the first byte of every block of a freshly loaded copy.
+//! When one fires, `handle_breakpoint` counts the block in the coverage map, puts the original byte
+//! back and rewinds the program to run it.  The runner calls `commit` with the map of every input
+//! it adds to the corpus, which retires the breakpoints of the blocks that input reached:  no later
+//! input can find anything new by reaching them again.  So a block costs a trap a run until an
+//! input reaching it is kept, and one first reached by an input that crashed, or was only run to
+//! minimise or replay something, still counts for the next input to reach it.
+//!
+//! Blocks are counted in the map after the kernel's own edges and counters.  Any that don't fit
+//! are dropped from the table, by address, and `init` says how many.
+//!
+//! The block file has an address per line, in hex, as linked rather than where a static-PIE
+//! executable ends up.  Blank lines and lines starting with `#` are ignored.  The sweep counts the
+//! entry point, the targets of direct jumps and calls, and the instructions after conditional
+//! branches and calls as block starts, wherever it decoded an instruction to start.  Anything that
+//! isn't code in an executable segment can throw it off, and get patched over, in which case a
+//! list from a proper disassembler is the way to go.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+
+use crate::elf::{Elf, PF_X};
+use crate::fuzz::coverage;
+use crate::interrupts::InterruptFrame;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::diagnostics::translate;
+use crate::memory::phys_to_virt;
+use crate::task::loader;
+use crate::{fw_cfg, println};
+
+use self::decode::{decode, Flow};
+
+pub mod decode;
+
+const INT3: u8 = 0xcc;
+
+struct Block {
+    /// Where the block starts in the loaded program.
+    address: u64,
+    /// The byte the breakpoint goes over.
+    original: u8,
+    /// Whether an input that reached the block is in the corpus, so it needs no breakpoint.
+    committed: AtomicBool,
+}
+
+/// The blocks, sorted by address.
+static BLOCKS: OnceCell<Vec<Block>> = OnceCell::uninit();
+
+/// Finds the basic blocks in an executable's code:  the starts of the instructions control can get
+/// to other than by running the one before.  `segments` are its executable segments, as their
+/// addresses and contents, and `entry` is its entry point.
+pub fn sweep(segments: &[(u64, &[u8])], entry: u64) -> Vec<u64> {
+    let mut instructions = BTreeSet::new();
+    let mut starts = alloc::vec![entry];
+    for &(start, code) in segments {
+        let mut at = 0;
+        while at < code.len() {
+            let address = start + at as u64;
+            let instruction = match decode(&code[at..], address) {
+                Some(instruction) => instruction,
+                // not code, or not code the decoder knows:  try the next byte
+                None => {
+                    at += 1;
+                    continue;
+                }
+            };
+            instructions.insert(address);
+            let next = address + instruction.length as u64;
+            match instruction.flow {
+                Flow::Next | Flow::Away => {}
+                Flow::Jump(target) => starts.push(target),
+                Flow::Branch(target) | Flow::Call(Some(target)) => starts.extend([target, next]),
+                Flow::Call(None) => starts.push(next),
+            }
+            at += instruction.length;
+        }
+    }
+
+    starts.retain(|start| instructions.contains(start));
+    starts.sort_unstable();
+    starts.dedup();
+    starts
+}
+
+/// Parses a list of block addresses.  Returns the number of the first line that isn't one, if
+/// there is one.
+pub fn parse_block_list(text: &str) -> Result<Vec<u64>, usize> {
+    let mut blocks = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let digits = line.strip_prefix("0x").unwrap_or(line);
+        blocks.push(u64::from_str_radix(digits, 16).map_err(|_| index + 1)?);
+    }
+    Ok(blocks)
+}
+
+/// Makes the table of blocks for the executable in `image`.  Called once, before anything is
+/// armed.
+pub fn init(image: &[u8]) {
+    // the loader reports anything wrong with the executable
+    let elf = match Elf::parse(image) {
+        Ok(elf) => elf,
+        Err(_) => return,
+    };
+    let bias = match loader::load_bias(&elf) {
+        Ok(bias) => bias,
+        Err(_) => return,
+    };
+    let segments: Vec<(u64, &[u8])> = elf
+        .loadable()
+        .filter(|segment| segment.flags & PF_X != 0)
+        .map(|segment| (segment.vaddr, elf.segment_data(segment)))
+        .collect();
+
+    let listed = fw_cfg::read_string("opt/barefuzz/program-blocks").map(|text| {
+        parse_block_list(&text).unwrap_or_else(|line| {
+            println!(
+                "Line {} of opt/barefuzz/program-blocks isn't an address, sweeping instead",
+                line
+            );
+            Vec::new()
+        })
+    });
+    let starts = match listed {
+        Some(starts) if !starts.is_empty() => starts,
+        _ => sweep(&segments, elf.entry),
+    };
+
+    let byte_at = |address: u64| {
+        segments.iter().find_map(|&(start, code)| {
+            let offset = address.checked_sub(start)?;
+            code.get(offset as usize).copied()
+        })
+    };
+    let mut blocks: Vec<Block> = starts
+        .into_iter()
+        .filter_map(|address| {
+            let original = byte_at(address)?;
+            // a block starting with a breakpoint of its own would trap forever
+            (original != INT3).then(|| Block {
+                address: bias + address,
+                original,
+                committed: AtomicBool::new(false),
+            })
+        })
+        .collect();
+    blocks.sort_unstable_by_key(|block| block.address);
+    blocks.dedup_by_key(|block| block.address);
+
+    let capacity = coverage::block_capacity();
+    if blocks.len() > capacity {
+        println!(
+            "linux_program: the coverage map only has room for {} blocks, dropping {}",
+            capacity,
+            blocks.len() - capacity
+        );
+        blocks.truncate(capacity);
+    }
+    println!(
+        "linux_program: {} blocks to cover with breakpoints",
+        blocks.len()
+    );
+    BLOCKS.init_once(|| blocks);
+}
+
+/// Sets a breakpoint on every block that isn't committed yet, in `address_space`, which has a
+/// fresh copy of the program loaded.
+pub fn arm(address_space: &AddressSpace) {
+    for block in BLOCKS.get().into_iter().flatten() {
+        if !block.committed.load(Ordering::Relaxed) {
+            address_space.write(VirtAddr::new(block.address), &[INT3]);
+        }
+    }
+}
+
+/// Stops arming the blocks `map` counts, once the input it's the map of has been added to the
+/// corpus.
+pub fn commit(map: &[u8]) {
+    for (index, block) in BLOCKS.get().into_iter().flatten().enumerate() {
+        if coverage::block_index(index).map_or(false, |index| map[index] != 0) {
+            block.committed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts the block whose breakpoint a program in user mode just hit, puts the byte the
+/// breakpoint replaced back, and rewinds the program to run it.  Returns whether it was one of
+/// `arm`'s breakpoints.
+///
+/// Called by the breakpoint handler, with the program's address space active.
+pub fn handle_breakpoint(frame: &mut InterruptFrame) -> bool {
+    if !frame.from_user_mode() {
+        return false;
+    }
+    let blocks = match BLOCKS.get() {
+        Some(blocks) => blocks,
+        None => return false,
+    };
+    // `int3` is a trap, so the program stopped just after it
+    let address = frame.instruction_pointer.wrapping_sub(1);
+    let index = match blocks.binary_search_by_key(&address, |block| block.address) {
+        Ok(index) => index,
+        Err(_) => return false,
+    };
+    // the code isn't writable in the program's own mapping, but it is in the physical one
+    let byte = match translate(VirtAddr::new(address)) {
+        Some(phys) => phys_to_virt(phys).as_mut_ptr::<u8>(),
+        None => return false,
+    };
+    if unsafe { *byte } != INT3 {
+        return false;
+    }
+
+    coverage::record_block(index);
+    unsafe { *byte = blocks[index].original };
+    frame.instruction_pointer = address;
+    true
+}
+
+#[test_case]
+fn test_sweep_finds_blocks() {
+    let code = [
+        0x85, 0xff, // 0x1000: test edi, edi
+        0x74, 0x07, // 0x1002: je 0x100b
+        0xe8, 0x03, 0x00, 0x00, 0x00, // 0x1004: call 0x100c
+        0xeb, 0xf5, // 0x1009: jmp 0x1000
+        0xc3, // 0x100b: ret
+        0x31, 0xc0, // 0x100c: xor eax, eax
+        0xc3, // 0x100e: ret
+    ];
+    assert_eq!(
+        sweep(&[(0x1000, &code[..])], 0x1000),
+        [0x1000, 0x1004, 0x1009, 0x100b, 0x100c]
+    );
+}
+
+#[test_case]
+fn test_parse_block_list() {
+    assert_eq!(
+        parse_block_list("# blocks\n0x401000\n\n  401abc\n"),
+        Ok(alloc::vec![0x401000, 0x401abc])
+    );
+    assert_eq!(parse_block_list("0x10\nmain\n"), Err(2));
+}
@@ -0,0 +1,375 @@
+//! Just enough x86-64 decoding to find basic blocks:  how long each instruction is, and where it
+//! sends control.
+//!
+//! Covers the one-byte, `0f`, `0f 38` and `0f 3a` opcode maps, and VEX and EVEX encodings.  Opcodes
+//! that don't exist in 64-bit mode don't decode, and neither does anything cut off by the end of
+//! the code.  Near branches are taken to have 32-bit displacements whatever their operand size, as
+//! on Intel CPUs.
+
+use Immediate::*;
+use Operands::*;
+
+/// The longest an instruction can be.
+const MAX_LENGTH: usize = 15;
+
+/// Where an instruction sends control next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// To the next instruction.
+    Next,
+    /// To the target, or the next instruction.
+    Branch(u64),
+    /// To the target and never the next instruction.
+    Jump(u64),
+    /// To the target, or somewhere unknown for an indirect call, and back to the next instruction.
+    Call(Option<u64>),
+    /// Somewhere unknown:  a return, an indirect jump or the like.
+    Away,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub length: usize,
+    pub flow: Flow,
+}
+
+/// How big an opcode's immediate is.
+#[derive(Clone, Copy)]
+enum Immediate {
+    Nothing,
+    Byte,
+    /// 16 bits with an operand size prefix, 32 without.
+    Z,
+    /// 16, 32 or 64 bits, for `mov` to a register.
+    V,
+    /// `enter`'s 16 bits and 8 bits.
+    Enter,
+    /// An absolute address, for the `mov`s between `al`/`rax` and memory.
+    Offset,
+}
+
+/// What follows an opcode, and what it does to control.
+#[derive(Clone, Copy)]
+enum Operands {
+    Invalid,
+    Plain {
+        modrm: bool,
+        immediate: Immediate,
+    },
+    /// A relative branch with an 8-bit or 32-bit displacement, which is conditional or not.
+    Relative {
+        long: bool,
+        conditional: bool,
+    },
+    Call,
+    Away,
+}
+
+const fn plain(modrm: bool, immediate: Immediate) -> Operands {
+    Plain { modrm, immediate }
+}
+
+/// Decodes the instruction at the start of `code`, which is at `address`.  `None` if it isn't a
+/// valid instruction in 64-bit mode, or doesn't fit.
+pub fn decode(code: &[u8], address: u64) -> Option<Instruction> {
+    let code = &code[..code.len().min(MAX_LENGTH)];
+    let mut at = 0;
+    let mut operand_size_16 = false;
+    let mut address_size_32 = false;
+
+    // legacy prefixes, then REX
+    loop {
+        match *code.get(at)? {
+            0x66 => operand_size_16 = true,
+            0x67 => address_size_32 = true,
+            0xf0 | 0xf2 | 0xf3 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        at += 1;
+    }
+    let mut rex_w = false;
+    if let 0x40..=0x4f = *code.get(at)? {
+        rex_w = code[at] & 0x08 != 0;
+        at += 1;
+    }
+
+    let opcode = *code.get(at)?;
+    at += 1;
+    let operands = match opcode {
+        0x0f => return decode_0f(code, at, address),
+        0xc4 | 0xc5 | 0x62 => return decode_vex(code, at - 1),
+        _ => one_byte(opcode, code.get(at).map(|modrm| (modrm >> 3) & 7)),
+    };
+
+    let immediate_length = |immediate: Immediate| match immediate {
+        Nothing => 0,
+        Byte => 1,
+        Z if operand_size_16 => 2,
+        Z => 4,
+        V if rex_w => 8,
+        V if operand_size_16 => 2,
+        V => 4,
+        Enter => 3,
+        Offset if address_size_32 => 4,
+        Offset => 8,
+    };
+    match operands {
+        Invalid => None,
+        Plain { modrm, immediate } => {
+            if modrm {
+                at += modrm_length(&code[at..])?;
+            }
+            finish(code, at + immediate_length(immediate), Flow::Next)
+        }
+        Relative { long, conditional } => {
+            let (length, displacement) = match long {
+                true => (
+                    at + 4,
+                    i32::from_le_bytes(code.get(at..at + 4)?.try_into().ok()?) as i64,
+                ),
+                false => (at + 1, *code.get(at)? as i8 as i64),
+            };
+            let target = (address + length as u64).wrapping_add(displacement as u64);
+            let flow = match conditional {
+                true => Flow::Branch(target),
+                false => Flow::Jump(target),
+            };
+            finish(code, length, flow)
+        }
+        Call => {
+            let length = at + 4;
+            let displacement = i32::from_le_bytes(code.get(at..length)?.try_into().ok()?) as i64;
+            let target = (address + length as u64).wrapping_add(displacement as u64);
+            finish(code, length, Flow::Call(Some(target)))
+        }
+        Away => {
+            // `ret imm16` and `retf imm16` pop extra bytes
+            let length = match opcode {
+                0xc2 | 0xca => at + 2,
+                0xff => at + modrm_length(&code[at..])?,
+                _ => at,
+            };
+            let flow = match (opcode, code.get(at).map(|modrm| (modrm >> 3) & 7)) {
+                (0xff, Some(2 | 3)) => Flow::Call(None),
+                _ => Flow::Away,
+            };
+            finish(code, length, flow)
+        }
+    }
+}
+
+/// The instruction, if `code` has all `length` bytes of it.
+fn finish(code: &[u8], length: usize, flow: Flow) -> Option<Instruction> {
+    (length <= code.len()).then(|| Instruction { length, flow })
+}
+
+/// The one-byte opcode map.  `reg` is the ModRM byte's `reg` field, for the groups that depend on
+/// it.
+fn one_byte(opcode: u8, reg: Option<u8>) -> Operands {
+    match opcode {
+        // arithmetic:  r/m and reg both ways, then al/eax with an immediate
+        0x00..=0x3f => match opcode & 7 {
+            0..=3 => plain(true, Nothing),
+            4 => plain(false, Byte),
+            5 => plain(false, Z),
+            // push/pop of segment registers, `daa` and the like don't exist in 64-bit mode
+            _ => Invalid,
+        },
+        0x50..=0x5f => plain(false, Nothing),
+        0x63 => plain(true, Nothing),
+        0x68 => plain(false, Z),
+        0x69 => plain(true, Z),
+        0x6a => plain(false, Byte),
+        0x6b => plain(true, Byte),
+        0x6c..=0x6f => plain(false, Nothing),
+        0x70..=0x7f => Relative {
+            long: false,
+            conditional: true,
+        },
+        0x80 | 0x83 => plain(true, Byte),
+        0x81 => plain(true, Z),
+        0x84..=0x8f => plain(true, Nothing),
+        0x90..=0x99 | 0x9b..=0x9f => plain(false, Nothing),
+        0xa0..=0xa3 => plain(false, Offset),
+        0xa4..=0xa7 | 0xaa..=0xaf => plain(false, Nothing),
+        0xa8 => plain(false, Byte),
+        0xa9 => plain(false, Z),
+        0xb0..=0xb7 => plain(false, Byte),
+        0xb8..=0xbf => plain(false, V),
+        0xc0 | 0xc1 | 0xc6 => plain(true, Byte),
+        0xc7 => plain(true, Z),
+        0xc2 | 0xc3 | 0xca | 0xcb | 0xcf => Away,
+        0xc8 => plain(false, Enter),
+        0xc9 | 0xcc | 0xf1 | 0xf5 | 0xf8..=0xfd | 0xd7 | 0xec..=0xef => plain(false, Nothing),
+        0xcd | 0xe4..=0xe7 => plain(false, Byte),
+        0xd0..=0xd3 | 0xd8..=0xdf | 0xfe => plain(true, Nothing),
+        // `loop`s and `jrcxz`
+        0xe0..=0xe3 => Relative {
+            long: false,
+            conditional: true,
+        },
+        0xe8 => Call,
+        0xe9 => Relative {
+            long: true,
+            conditional: false,
+        },
+        0xeb => Relative {
+            long: false,
+            conditional: false,
+        },
+        // `hlt` ends a block as far as anything after it is concerned
+        0xf4 => Away,
+        // `test` takes an immediate, the rest of the group doesn't
+        0xf6 => match reg {
+            Some(0 | 1) => plain(true, Byte),
+            _ => plain(true, Nothing),
+        },
+        0xf7 => match reg {
+            Some(0 | 1) => plain(true, Z),
+            _ => plain(true, Nothing),
+        },
+        // indirect calls and jumps
+        0xff => match reg {
+            Some(2..=5) => Away,
+            _ => plain(true, Nothing),
+        },
+        _ => Invalid,
+    }
+}
+
+/// Decodes the rest of a `0f` opcode, whose second byte is at `at`.
+fn decode_0f(code: &[u8], mut at: usize, address: u64) -> Option<Instruction> {
+    let opcode = *code.get(at)?;
+    at += 1;
+    let (modrm, immediate) = match opcode {
+        0x38 => {
+            at += 1;
+            (true, 0)
+        }
+        0x3a => {
+            at += 1;
+            (true, 1)
+        }
+        0x80..=0x8f => {
+            let length = at + 4;
+            let displacement = i32::from_le_bytes(code.get(at..length)?.try_into().ok()?) as i64;
+            let target = (address + length as u64).wrapping_add(displacement as u64);
+            return finish(code, length, Flow::Branch(target));
+        }
+        // `ud2` and `sysret` don't carry on
+        0x0b | 0x07 => return finish(code, at, Flow::Away),
+        0x04
+        | 0x0a
+        | 0x0c
+        | 0x24..=0x27
+        | 0x36
+        | 0x39
+        | 0x3b..=0x3f
+        | 0x7a
+        | 0x7b
+        | 0xa6
+        | 0xa7 => return None,
+        0x05
+        | 0x06
+        | 0x08
+        | 0x09
+        | 0x0e
+        | 0x30..=0x37
+        | 0x77
+        | 0xa0..=0xa2
+        | 0xa8..=0xaa
+        | 0xc8..=0xcf => (false, 0),
+        0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 | 0x0f => (true, 1),
+        _ => (true, 0),
+    };
+    if modrm {
+        at += modrm_length(code.get(at..)?)?;
+    }
+    finish(code, at + immediate, Flow::Next)
+}
+
+/// Decodes a VEX (`c4`, `c5`) or EVEX (`62`) instruction starting at `at`.  None of them branch.
+fn decode_vex(code: &[u8], at: usize) -> Option<Instruction> {
+    let (map, opcode_at) = match code[at] {
+        0xc5 => (1, at + 2),
+        0xc4 => (*code.get(at + 1)? & 0x1f, at + 3),
+        _ => (*code.get(at + 1)? & 0x07, at + 4),
+    };
+    let opcode = *code.get(opcode_at)?;
+    // `vzeroupper` and `vzeroall` are the only ones without a ModRM byte
+    if map == 1 && opcode == 0x77 {
+        return finish(code, opcode_at + 1, Flow::Next);
+    }
+    let immediate = match (map, opcode) {
+        (3, _) | (1, 0x70..=0x73 | 0xc2 | 0xc4..=0xc6) => 1,
+        (1..=3, _) | (5 | 6, _) => 0,
+        _ => return None,
+    };
+    let at = opcode_at + 1;
+    finish(
+        code,
+        at + modrm_length(code.get(at..)?)? + immediate,
+        Flow::Next,
+    )
+}
+
+/// How long the ModRM byte at the start of `code` is, with its SIB byte and displacement.
+fn modrm_length(code: &[u8]) -> Option<usize> {
+    let modrm = *code.first()?;
+    let (mode, rm) = (modrm >> 6, modrm & 7);
+    let has_sib = mode != 3 && rm == 4;
+    let base = match has_sib {
+        true => *code.get(1)? & 7,
+        false => rm,
+    };
+    let displacement = match mode {
+        0 if base == 5 => 4,
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    Some(1 + has_sib as usize + displacement)
+}
+
+#[test_case]
+fn test_decode_lengths() {
+    let cases: &[(&[u8], usize)] = &[
+        (&[0x55], 1),                                     // push rbp
+        (&[0x48, 0x89, 0xe5], 3),                         // mov rbp, rsp
+        (&[0x48, 0x83, 0xec, 0x10], 4),                   // sub rsp, 0x10
+        (&[0x8b, 0x44, 0x24, 0x08], 4),                   // mov eax, [rsp + 8]
+        (&[0x48, 0x8b, 0x05, 1, 2, 3, 4], 7),             // mov rax, [rip + disp32]
+        (&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], 10),      // movabs rax, imm64
+        (&[0x66, 0xc7, 0x00, 1, 2], 5),                   // mov word [rax], imm16
+        (&[0xf7, 0xc1, 1, 2, 3, 4], 6),                   // test ecx, imm32
+        (&[0xf7, 0xd9], 2),                               // neg ecx
+        (&[0x0f, 0x1f, 0x44, 0x00, 0x00], 5),             // nop dword [rax + rax]
+        (&[0x66, 0x0f, 0x3a, 0x0f, 0xc1, 0x08], 6),       // palignr xmm0, xmm1, 8
+        (&[0xc5, 0xfd, 0x6f, 0x06], 4),                   // vmovdqa ymm0, [rsi]
+        (&[0xc4, 0xe2, 0x7d, 0x78, 0xc0], 5),             // vpbroadcastb ymm0, xmm0
+        (&[0x62, 0xf1, 0x7c, 0x48, 0x10, 0x46, 0x01], 7), // vmovups zmm0, [rsi + 64]
+        (&[0xc5, 0xf8, 0x77], 3),                         // vzeroupper
+    ];
+    for &(code, length) in cases {
+        let instruction = decode(code, 0).unwrap();
+        assert_eq!((instruction.length, instruction.flow), (length, Flow::Next));
+    }
+    assert_eq!(decode(&[0x48, 0xb8, 1, 2], 0), None);
+    assert_eq!(decode(&[0x06], 0), None);
+}
+
+#[test_case]
+fn test_decode_flow() {
+    let flow = |code: &[u8]| decode(code, 0x1000).unwrap().flow;
+    assert_eq!(flow(&[0x74, 0x10]), Flow::Branch(0x1012));
+    assert_eq!(flow(&[0xeb, 0xfe]), Flow::Jump(0x1000));
+    assert_eq!(flow(&[0x0f, 0x85, 0x00, 0x01, 0, 0]), Flow::Branch(0x1106));
+    assert_eq!(
+        flow(&[0xe8, 0xfb, 0xff, 0xff, 0xff]),
+        Flow::Call(Some(0x1000))
+    );
+    assert_eq!(flow(&[0xff, 0xd0]), Flow::Call(None));
+    assert_eq!(flow(&[0xff, 0xe0]), Flow::Away);
+    assert_eq!(flow(&[0xc3]), Flow::Away);
+    assert_eq!(flow(&[0x0f, 0x0b]), Flow::Away);
+}
@@ -0,0 +1,268 @@
+//! Runtime for SanitizerCoverage comparison tracing.
+//!
+//! Code built with `-Cllvm-args=-sanitizer-coverage-trace-compares` calls
+//! `__sanitizer_cov_trace_cmp*`, `__sanitizer_cov_trace_const_cmp*` and
+//! `__sanitizer_cov_trace_switch` with the operands of every integer comparison and switch.  The
+//! operand pairs go into the comparison log of the running task, the same way `coverage` keeps a
+//! map per task, so each fuzzing worker sees the comparisons made by its own input.
+//!
+//! Byte-string comparisons go through `memcmp`, which isn't instrumented;  harnesses comparing
+//! against magic strings can call `log_bytes` themselves.
+//!
+//! The mutator uses the log for input-to-state replacement:  if one operand of a failed
+//! comparison shows up in the input, the other one is patched in over it.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::executor::{self, current_task, register_exit_hook, register_switch_hook};
+use crate::task::slots::TaskSlots;
+use crate::task::TaskId;
+
+/// The longest operand recorded;  longer byte strings are cut short.
+pub const MAX_OPERAND_LEN: usize = 32;
+
+/// How many comparisons a log holds.  Comparisons after that are dropped.
+const LOG_SIZE: usize = 1024;
+
+/// The most tasks which can have a log at the same time.
+const MAX_LOGS: usize = 16;
+
+/// Switches with more cases than this are only partly recorded.
+const MAX_SWITCH_CASES: u64 = 32;
+
+/// One side of a comparison didn't match the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Operands {
+    len: u8,
+    /// Whether `a` is a constant in the code, so only `b` can have come from the input.
+    constant: bool,
+    a: [u8; MAX_OPERAND_LEN],
+    b: [u8; MAX_OPERAND_LEN],
+}
+
+impl Operands {
+    const EMPTY: Self = Self {
+        len: 0,
+        constant: false,
+        a: [0; MAX_OPERAND_LEN],
+        b: [0; MAX_OPERAND_LEN],
+    };
+
+    pub fn from_bytes(a: &[u8], b: &[u8], constant: bool) -> Self {
+        let len = a.len().min(b.len()).min(MAX_OPERAND_LEN);
+        let mut operands = Self {
+            len: len as u8,
+            constant,
+            ..Self::EMPTY
+        };
+        operands.a[..len].copy_from_slice(&a[..len]);
+        operands.b[..len].copy_from_slice(&b[..len]);
+        operands
+    }
+
+    /// The operands, as little-endian bytes for integers.
+    pub fn a(&self) -> &[u8] {
+        &self.a[..self.len as usize]
+    }
+
+    pub fn b(&self) -> &[u8] {
+        &self.b[..self.len as usize]
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.constant
+    }
+
+    /// Whether the operands are the two sides of an integer comparison, rather than byte strings.
+    pub fn is_integer(&self) -> bool {
+        matches!(self.len, 1 | 2 | 4 | 8)
+    }
+}
+
+struct CmpLog {
+    /// How many comparisons were made, which may be more than were recorded.
+    len: AtomicUsize,
+    entries: Box<[Operands]>,
+}
+
+static LOGS: TaskSlots<CmpLog, MAX_LOGS> = TaskSlots::new();
+
+/// The log of the running task, or null if it doesn't have one.
+static ACTIVE_LOG: AtomicPtr<CmpLog> = AtomicPtr::new(ptr::null_mut());
+
+/// Starts switching logs along with tasks, and dropping them with them.  Must be called once,
+/// after `executor::init`.
+pub fn init() {
+    register_switch_hook(on_switch);
+    register_exit_hook(|task| {
+        if let Some(log) = take(task) {
+            executor::reap(log);
+        }
+    });
+}
+
+fn on_switch(_from: Option<TaskId>, to: TaskId) {
+    let incoming = LOGS.get(to).unwrap_or(ptr::null_mut());
+    ACTIVE_LOG.store(incoming, Ordering::SeqCst);
+}
+
+/// Gives `task` an empty comparison log of its own.  Does nothing if it already has one.
+///
+/// Panics if `MAX_LOGS` tasks already have a log.
+pub fn attach(task: TaskId) {
+    if LOGS.get(task).is_some() {
+        return;
+    }
+
+    let log = Box::into_raw(Box::new(CmpLog {
+        len: AtomicUsize::new(0),
+        entries: vec![Operands::EMPTY; LOG_SIZE].into_boxed_slice(),
+    }));
+    without_interrupts(|| {
+        LOGS.insert(task, log)
+            .expect("too many tasks with comparison logs");
+
+        if current_task() == Some(task) {
+            ACTIVE_LOG.store(log, Ordering::SeqCst);
+        }
+    });
+}
+
+/// Takes `task`'s log out of its slot.
+fn take(task: TaskId) -> Option<Box<CmpLog>> {
+    without_interrupts(|| {
+        let log = LOGS.remove(task)?;
+        if ACTIVE_LOG.load(Ordering::Relaxed) == log {
+            ACTIVE_LOG.store(ptr::null_mut(), Ordering::SeqCst);
+        }
+        Some(unsafe { Box::from_raw(log) })
+    })
+}
+
+/// Frees `task`'s comparison log.  Tasks' logs go when they end anyway.
+pub fn detach(task: TaskId) {
+    drop(take(task));
+}
+
+/// Clears the calling task's log, before running the next input.
+pub fn reset() {
+    let log = ACTIVE_LOG.load(Ordering::Relaxed);
+    if !log.is_null() {
+        unsafe { (*log).len.store(0, Ordering::Relaxed) };
+    }
+}
+
+/// The distinct comparisons in the calling task's log, at most `max` of them, once an input has
+/// run.
+pub fn take(max: usize) -> Vec<Operands> {
+    let log = ACTIVE_LOG.load(Ordering::Relaxed);
+    if log.is_null() {
+        return Vec::new();
+    }
+
+    let log = unsafe { &*log };
+    let len = log.len.load(Ordering::Relaxed).min(LOG_SIZE);
+    let mut operands = log.entries[..len].to_vec();
+    operands.sort_unstable();
+    operands.dedup();
+    operands.truncate(max);
+    operands
+}
+
+/// Records a comparison of two byte strings which didn't match, e.g. from a harness checking
+/// for a magic string.
+pub fn log_bytes(expected: &[u8], actual: &[u8]) {
+    if expected != actual {
+        unsafe { push(Operands::from_bytes(expected, actual, true)) };
+    }
+}
+
+#[inline(always)]
+unsafe fn push(operands: Operands) {
+    let log = ACTIVE_LOG.load(Ordering::Relaxed);
+    if log.is_null() {
+        return;
+    }
+    let index = (*log).len.fetch_add(1, Ordering::Relaxed);
+    if index < LOG_SIZE {
+        ptr::write((*log).entries.as_mut_ptr().add(index), operands);
+    }
+}
+
+#[inline(always)]
+unsafe fn record(len: usize, a: u64, b: u64, constant: bool) {
+    if a == b {
+        return;
+    }
+    let mut operands = Operands {
+        len: len as u8,
+        constant,
+        ..Operands::EMPTY
+    };
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    let mut i = 0;
+    while i < len {
+        operands.a[i] = a[i];
+        operands.b[i] = b[i];
+        i += 1;
+    }
+    push(operands);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_cmp1(a: u8, b: u8) {
+    record(1, a as u64, b as u64, false);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_cmp2(a: u16, b: u16) {
+    record(2, a as u64, b as u64, false);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_cmp4(a: u32, b: u32) {
+    record(4, a as u64, b as u64, false);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_cmp8(a: u64, b: u64) {
+    record(8, a, b, false);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_const_cmp1(a: u8, b: u8) {
+    record(1, a as u64, b as u64, true);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_const_cmp2(a: u16, b: u16) {
+    record(2, a as u64, b as u64, true);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_const_cmp4(a: u32, b: u32) {
+    record(4, a as u64, b as u64, true);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_const_cmp8(a: u64, b: u64) {
+    record(8, a, b, true);
+}
+
+/// `cases` holds the number of cases, the width of `value` in bits, then the case values.
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_switch(value: u64, cases: *const u64) {
+    let count = (*cases).min(MAX_SWITCH_CASES) as usize;
+    let len = (*cases.add(1) / 8) as usize;
+    let mut i = 0;
+    while i < count {
+        record(len, *cases.add(2 + i), value, true);
+        i += 1;
+    }
+}
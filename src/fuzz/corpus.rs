@@ -0,0 +1,340 @@
+//! The inputs worth mutating further, shared between all fuzzing workers.
+//!
+//! An input is kept if its coverage map reaches an edge no earlier input reached, or reaches a
+//! known edge a number of times that falls into a new AFL-style hit-count bucket.  Seeds are picked
+//! with a weight favouring inputs that reach rarely reached edges and run quickly.
+
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use conquer_once::spin::Lazy;
+
+use crate::concurrency::rwlock::RwLock;
+use crate::fuzz::cmplog::Operands;
+use crate::fuzz::coverage::MAP_SIZE;
+use crate::rng::Rng;
+
+// edges are stored as u16 map indices
+const _: () = assert!(MAP_SIZE <= 1 << 16);
+
+/// Fixed-point scale for seed weights.
+const WEIGHT_SCALE: u64 = 1 << 16;
+/// How much faster or slower than average an input has to be before its weight stops changing.
+const MAX_SPEED_FACTOR: u64 = 4;
+/// How many entries are added between recomputing every entry's weight.
+const REWEIGH_INTERVAL: usize = 64;
+
+/// The corpus every worker adds to and picks from.
+pub static CORPUS: Lazy<RwLock<Corpus>> = Lazy::new(|| RwLock::new(Corpus::new()));
+
+/// Maps a hit count to a bitmask for its bucket (1, 2, 3, 4-7, 8-15, 16-31, 32-127, 128+), so
+/// that loops running a few more times than before don't each count as new coverage.
+pub fn bucket(hits: u8) -> u8 {
+    match hits {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4..=7 => 8,
+        8..=15 => 16,
+        16..=31 => 32,
+        32..=127 => 64,
+        128..=255 => 128,
+    }
+}
+
+/// Hashes which edges `map` reached and their hit-count buckets (FNV-1a).
+pub fn hash_coverage(map: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for (index, &hits) in map.iter().enumerate().filter(|(_, &hits)| hits != 0) {
+        for byte in (index as u32)
+            .to_le_bytes()
+            .into_iter()
+            .chain([bucket(hits)])
+        {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub input: Vec<u8>,
+    /// `hash_coverage` of the map that made this input interesting.
+    pub coverage_hash: u64,
+    /// The map indices this input reached.
+    pub edges: Vec<u16>,
+    /// How long the input took to run, in TSC ticks.
+    pub exec_time: u64,
+    /// Whether the input is part of the minimal set covering every edge, as of the last
+    /// `minimise` or `cull`.
+    pub favoured: bool,
+    /// The comparisons the input made that didn't match, for input-to-state mutation.
+    pub comparisons: Vec<Operands>,
+}
+
+pub struct Corpus {
+    entries: Vec<Entry>,
+    /// The union of every bucket seen for each map index.
+    seen: Vec<u8>,
+    /// How many entries reach each map index.
+    edge_hits: Vec<u32>,
+    hashes: BTreeSet<u64>,
+    /// The weight of each entry for `pick`, and their sum.
+    weights: Vec<u64>,
+    total_weight: u64,
+    /// The mean execution time as of the last `update_weights`.
+    mean_time: u64,
+    /// Entries added since the last `update_weights`.
+    added_since_reweigh: usize,
+}
+
+impl Corpus {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            seen: vec![0; MAP_SIZE],
+            edge_hits: vec![0; MAP_SIZE],
+            hashes: BTreeSet::new(),
+            weights: Vec::new(),
+            total_weight: 0,
+            mean_time: 1,
+            added_since_reweigh: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Entry> {
+        self.entries.get(index)
+    }
+
+    /// How many map indices any entry has reached.
+    pub fn edges_covered(&self) -> usize {
+        self.edge_hits.iter().filter(|&&hits| hits != 0).count()
+    }
+
+    /// Whether `map` reaches an edge, or an edge's hit-count bucket, that no entry has.
+    pub fn has_new_coverage(&self, map: &[u8]) -> bool {
+        map.iter()
+            .zip(self.seen.iter())
+            .any(|(&hits, &seen)| bucket(hits) & !seen != 0)
+    }
+
+    /// Adds `input` if `map`, the coverage it produced, has anything new.  Returns the index it
+    /// was added at.
+    pub fn add_if_interesting(
+        &mut self,
+        input: &[u8],
+        map: &[u8],
+        exec_time: u64,
+    ) -> Option<usize> {
+        if !self.has_new_coverage(map) {
+            return None;
+        }
+        self.add(input, map, exec_time)
+    }
+
+    /// Adds `input` unless an entry with exactly the same coverage is already there, e.g. for
+    /// seeds.
+    pub fn add(&mut self, input: &[u8], map: &[u8], exec_time: u64) -> Option<usize> {
+        let coverage_hash = hash_coverage(map);
+        if !self.hashes.insert(coverage_hash) {
+            return None;
+        }
+
+        let mut edges = Vec::new();
+        for (index, &hits) in map.iter().enumerate().filter(|(_, &hits)| hits != 0) {
+            self.seen[index] |= bucket(hits);
+            self.edge_hits[index] += 1;
+            edges.push(index as u16);
+        }
+
+        self.entries.push(Entry {
+            input: input.to_vec(),
+            coverage_hash,
+            edges,
+            exec_time,
+            favoured: false,
+            comparisons: Vec::new(),
+        });
+        self.added_since_reweigh += 1;
+        if self.added_since_reweigh >= REWEIGH_INTERVAL {
+            self.update_weights();
+        } else {
+            let weight = self.weight(self.entries.last().unwrap());
+            self.weights.push(weight);
+            self.total_weight += weight;
+        }
+        Some(self.entries.len() - 1)
+    }
+
+    /// Records the comparisons entry `index` made when it was run.
+    pub fn set_comparisons(&mut self, index: usize, comparisons: Vec<Operands>) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.comparisons = comparisons;
+        }
+    }
+
+    /// Recomputes every entry's weight.  Rarity changes whenever an entry is added, but going over
+    /// every edge of every entry takes long enough to hold up the other workers, so `add` only
+    /// weighs the new entry, and the rest catch up every `REWEIGH_INTERVAL` additions.
+    fn update_weights(&mut self) {
+        self.mean_time = match self.entries.len() as u64 {
+            0 => 1,
+            len => (self.entries.iter().map(|e| e.exec_time).sum::<u64>() / len).max(1),
+        };
+
+        self.weights = self
+            .entries
+            .iter()
+            .map(|entry| self.weight(entry))
+            .collect();
+        self.total_weight = self.weights.iter().sum();
+        self.added_since_reweigh = 0;
+    }
+
+    /// The weight of `entry`, from the edges' current rarity and the mean execution time as of
+    /// the last `update_weights`.
+    fn weight(&self, entry: &Entry) -> u64 {
+        let rarity: u64 = entry
+            .edges
+            .iter()
+            .map(|&edge| WEIGHT_SCALE / self.edge_hits[edge as usize].max(1) as u64)
+            .sum();
+        let mean_time = self.mean_time;
+        let exec_time = entry
+            .exec_time
+            .clamp(mean_time / MAX_SPEED_FACTOR, mean_time * MAX_SPEED_FACTOR);
+        let weight = (rarity * mean_time / exec_time.max(1)).max(1);
+        match entry.favoured {
+            true => weight * 2,
+            false => weight,
+        }
+    }
+
+    /// Picks the index of an entry to mutate next, weighted towards rare coverage and fast
+    /// inputs.
+    pub fn pick(&self, rng: &mut Rng) -> Option<usize> {
+        if self.total_weight == 0 {
+            return None;
+        }
+
+        let mut target = rng.next_u64() % self.total_weight;
+        for (index, &weight) in self.weights.iter().enumerate() {
+            if target < weight {
+                return Some(index);
+            }
+            target -= weight;
+        }
+        None
+    }
+
+    /// Greedily picks a small set of entries that together reach every edge any entry reaches,
+    /// preferring fast and small ones, and marks them as favoured.  Returns how many there are.
+    pub fn cull(&mut self) -> usize {
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        order.sort_by_key(|&index| {
+            let entry = &self.entries[index];
+            entry
+                .exec_time
+                .saturating_mul(entry.input.len().max(1) as u64)
+        });
+
+        let mut covered = vec![false; MAP_SIZE];
+        let mut favoured = 0;
+        for entry in self.entries.iter_mut() {
+            entry.favoured = false;
+        }
+        // the cheapest entry reaching each not yet covered edge is favoured
+        for index in order {
+            let entry = &mut self.entries[index];
+            if entry.edges.iter().any(|&edge| !covered[edge as usize]) {
+                for &edge in &entry.edges {
+                    covered[edge as usize] = true;
+                }
+                entry.favoured = true;
+                favoured += 1;
+            }
+        }
+
+        self.update_weights();
+        favoured
+    }
+
+    /// Drops every entry that isn't needed to reach every edge the corpus reaches.  Returns how
+    /// many entries were removed.
+    pub fn minimise(&mut self) -> usize {
+        self.cull();
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.favoured);
+
+        self.edge_hits.iter_mut().for_each(|hits| *hits = 0);
+        self.hashes.clear();
+        for entry in &self.entries {
+            self.hashes.insert(entry.coverage_hash);
+            for &edge in &entry.edges {
+                self.edge_hits[edge as usize] += 1;
+            }
+        }
+
+        self.update_weights();
+        before - self.entries.len()
+    }
+}
+
+impl Default for Corpus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_only_new_coverage_is_kept() {
+    let mut corpus = Corpus::new();
+    let mut map = vec![0u8; MAP_SIZE];
+    map[1] = 1;
+    assert_eq!(corpus.add_if_interesting(b"a", &map, 10), Some(0));
+    assert_eq!(corpus.add_if_interesting(b"b", &map, 10), None);
+
+    // same edge, new bucket
+    map[1] = 5;
+    assert_eq!(corpus.add_if_interesting(b"c", &map, 10), Some(1));
+    map[2] = 1;
+    assert_eq!(corpus.add_if_interesting(b"d", &map, 10), Some(2));
+    assert_eq!(corpus.edges_covered(), 2);
+}
+
+#[test_case]
+fn test_minimise_keeps_every_edge() {
+    let mut corpus = Corpus::new();
+    let mut map = vec![0u8; MAP_SIZE];
+    map[1..4].fill(1);
+    corpus.add(b"abc", &map, 10);
+    map.fill(0);
+    map[1] = 1;
+    corpus.add(b"just one", &map, 10);
+    map.fill(0);
+    map[2..4].fill(1);
+    corpus.add(b"two and three", &map, 10);
+
+    assert_eq!(corpus.minimise(), 2);
+    assert_eq!(corpus.len(), 1);
+    assert_eq!(corpus.edges_covered(), 3);
+
+    let mut rng = Rng::new(0);
+    assert_eq!(corpus.pick(&mut rng), Some(0));
+}
@@ -0,0 +1,314 @@
+//! Runtime for SanitizerCoverage instrumentation.
+//!
+//! Code built with
+//!
+//! ```text
+//! -Cpasses=sancov-module
+//! -Cllvm-args=-sanitizer-coverage-level=3
+//! -Cllvm-args=-sanitizer-coverage-trace-pc-guard
+//! -Cllvm-args=-sanitizer-coverage-inline-8bit-counters
+//! -Cllvm-args=-sanitizer-coverage-pc-table
+//! ```
+//!
+//! records every edge it takes, either by calling `__sanitizer_cov_trace_pc_guard` with the edge's
+//! guard or by bumping the edge's inline counter directly.  Either way the hits end up in the
+//! coverage map of the task that was running, so each fuzzing worker sees only its own inputs'
+//! coverage.
+//!
+//! Guard hits go straight into the running task's map.  Inline counters are global, so they're
+//! folded into the map of the task that was running whenever tasks are switched, and when the
+//! map is read.  Coverage from interrupt handlers lands in whichever task they interrupted.
+//!
+//! A task can `lend` its map to another one it's waiting on, like a worker to the program it runs,
+//! so the coverage of both ends up in the one map.  Code that isn't instrumented gets coverage from
+//! `breakpoints` instead, with `record_block` counting into the map after the guards and counters.
+//!
+//! LLVM doesn't instrument functions whose name starts with `__sanitizer_`, but it does instrument
+//! everything they call, so the callbacks below only use code that is inlined into them.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::executor::{self, current_task, register_exit_hook, register_switch_hook};
+use crate::task::slots::TaskSlots;
+use crate::task::TaskId;
+
+/// How many counters a coverage map has.  Edge indices beyond this wrap around.
+pub const MAP_SIZE: usize = 1 << 16;
+
+/// The most tasks which can have a coverage map at the same time.
+const MAX_MAPS: usize = 16;
+
+/// A task's coverage map, which it either has to itself or borrows from another task.
+struct Map {
+    counters: *mut u8,
+    /// Whether the counters belong to another task, which frees them.
+    borrowed: bool,
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        if !self.borrowed {
+            let counters = ptr::slice_from_raw_parts_mut(self.counters, MAP_SIZE);
+            unsafe { drop(Box::from_raw(counters)) };
+        }
+    }
+}
+
+// maps only go to the reaper once their task has ended, and borrowed counters stay with the lender
+unsafe impl Send for Map {}
+
+static MAPS: TaskSlots<Map, MAX_MAPS> = TaskSlots::new();
+
+/// The map of the running task, or null if it doesn't have one.
+static ACTIVE_MAP: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+/// How many guards have been numbered;  guard `n` counts into map index `n`.
+static GUARDS: AtomicU32 = AtomicU32::new(0);
+
+static COUNTERS_START: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static COUNTERS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+static PCS_START: AtomicPtr<usize> = AtomicPtr::new(ptr::null_mut());
+static PCS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    // Defined by the linker around the constructors the instrumentation registers
+    static __init_array_start: [unsafe extern "C" fn(); 0];
+    static __init_array_end: [unsafe extern "C" fn(); 0];
+}
+
+/// Runs the instrumentation's module constructors, which tell the runtime where the guards and
+/// counters are, and starts switching coverage maps along with tasks and dropping them with them.
+///
+/// Must be called once, after `executor::init`.  Code that runs before this isn't recorded.
+pub fn init() {
+    unsafe {
+        let mut constructor = __init_array_start.as_ptr();
+        while constructor < __init_array_end.as_ptr() {
+            (*constructor)();
+            constructor = constructor.add(1);
+        }
+    }
+
+    register_switch_hook(on_switch);
+    register_exit_hook(|task| {
+        if let Some(map) = take(task) {
+            executor::reap(map);
+        }
+    });
+}
+
+/// The counters of `task`'s map, if it has one.
+fn counters_of(task: TaskId) -> Option<*mut u8> {
+    MAPS.get(task).map(|map| unsafe { (*map).counters })
+}
+
+/// Adds the inline counters to `map` and clears them.
+///
+/// Must be called with interrupts disabled, since the counters belong to whoever is running.
+unsafe fn fold_counters(map: *mut u8) {
+    let counters = COUNTERS_START.load(Ordering::Relaxed);
+    let len = COUNTERS_LEN.load(Ordering::Relaxed);
+    if counters.is_null() {
+        return;
+    }
+
+    let base = GUARDS.load(Ordering::Relaxed) as usize + 1;
+    for index in 0..len {
+        let hits = *counters.add(index);
+        if hits == 0 {
+            continue;
+        }
+        if !map.is_null() {
+            let counter = map.add((base + index) % MAP_SIZE);
+            *counter = (*counter).wrapping_add(hits);
+        }
+        *counters.add(index) = 0;
+    }
+}
+
+fn on_switch(_from: Option<TaskId>, to: TaskId) {
+    let outgoing = ACTIVE_MAP.load(Ordering::Relaxed);
+    unsafe { fold_counters(outgoing) };
+
+    let incoming = counters_of(to).unwrap_or(ptr::null_mut());
+    ACTIVE_MAP.store(incoming, Ordering::SeqCst);
+}
+
+/// Gives `task` a zeroed coverage map of its own.  Does nothing if it already has one.
+///
+/// Panics if `MAX_MAPS` tasks already have a map.
+pub fn attach(task: TaskId) {
+    if MAPS.get(task).is_some() {
+        return;
+    }
+
+    let counters = Box::into_raw(vec![0u8; MAP_SIZE].into_boxed_slice()) as *mut u8;
+    install(
+        task,
+        Map {
+            counters,
+            borrowed: false,
+        },
+    );
+}
+
+/// Lets `borrower` count into `lender`'s map, e.g. while `lender` waits for it, until `borrower`
+/// is detached.  Does nothing if `lender` has no map or `borrower` already has one.
+///
+/// Panics if `MAX_MAPS` tasks already have a map.
+pub fn lend(lender: TaskId, borrower: TaskId) {
+    let counters = match counters_of(lender) {
+        Some(counters) => counters,
+        None => return,
+    };
+    if MAPS.get(borrower).is_none() {
+        install(
+            borrower,
+            Map {
+                counters,
+                borrowed: true,
+            },
+        );
+    }
+}
+
+/// Puts `map` in a free slot for `task`.
+fn install(task: TaskId, map: Map) {
+    let counters = map.counters;
+    let map = Box::into_raw(Box::new(map));
+    without_interrupts(|| {
+        MAPS.insert(task, map)
+            .expect("too many tasks with coverage maps");
+
+        if current_task() == Some(task) {
+            unsafe { fold_counters(ACTIVE_MAP.load(Ordering::Relaxed)) };
+            ACTIVE_MAP.store(counters, Ordering::SeqCst);
+        }
+    });
+}
+
+/// Takes `task`'s map out of its slot, folding in the inline counters if it's running.
+fn take(task: TaskId) -> Option<Box<Map>> {
+    without_interrupts(|| {
+        let map = unsafe { Box::from_raw(MAPS.remove(task)?) };
+        if current_task() == Some(task) {
+            unsafe { fold_counters(map.counters) };
+            ACTIVE_MAP.store(ptr::null_mut(), Ordering::SeqCst);
+        }
+        Some(map)
+    })
+}
+
+/// Frees `task`'s coverage map, or gives a borrowed one back.  Tasks' maps go when they end
+/// anyway.
+pub fn detach(task: TaskId) {
+    drop(take(task));
+}
+
+/// Clears the calling task's coverage map, before running the next input.
+pub fn reset() {
+    without_interrupts(|| {
+        let map = ACTIVE_MAP.load(Ordering::Relaxed);
+        unsafe { fold_counters(ptr::null_mut()) };
+        if !map.is_null() {
+            unsafe { ptr::write_bytes(map, 0, MAP_SIZE) };
+        }
+    })
+}
+
+/// Calls `f` with the calling task's coverage map, once an input has run.  Returns `None` if the
+/// task has no map.
+pub fn read<R>(f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    let map = without_interrupts(|| {
+        let map = ACTIVE_MAP.load(Ordering::Relaxed);
+        unsafe { fold_counters(map) };
+        map
+    });
+    if map.is_null() {
+        return None;
+    }
+    Some(f(unsafe { slice::from_raw_parts(map, MAP_SIZE) }))
+}
+
+/// How many blocks of code `breakpoints` covers fit in a map, after the guards and counters.
+pub fn block_capacity() -> usize {
+    let base = GUARDS.load(Ordering::Relaxed) as usize + 1 + COUNTERS_LEN.load(Ordering::Relaxed);
+    MAP_SIZE.saturating_sub(base)
+}
+
+/// The map index that counts block `block` of code `breakpoints` covers, or `None` if it's past
+/// `block_capacity`.  Blocks don't wrap around, since they'd share counters with edges.
+pub fn block_index(block: usize) -> Option<usize> {
+    (block < block_capacity()).then(|| MAP_SIZE - block_capacity() + block)
+}
+
+/// Counts a hit on block `block` of code `breakpoints` covers, in the running task's map.
+pub fn record_block(block: usize) {
+    let map = ACTIVE_MAP.load(Ordering::Relaxed);
+    let index = match block_index(block) {
+        Some(index) if !map.is_null() => index,
+        _ => return,
+    };
+    unsafe {
+        let counter = map.add(index);
+        *counter = (*counter).wrapping_add(1);
+    }
+}
+
+/// The address of the instrumented block behind map index `index`, if the code was built with
+/// `-sanitizer-coverage-pc-table` and the index belongs to an inline counter.
+pub fn pc_for(index: usize) -> Option<usize> {
+    let pcs = PCS_START.load(Ordering::Relaxed);
+    let counter = index.checked_sub(GUARDS.load(Ordering::Relaxed) as usize + 1)?;
+    if pcs.is_null() || counter >= PCS_LEN.load(Ordering::Relaxed) {
+        return None;
+    }
+    // the table holds a (pc, flags) pair per counter
+    Some(unsafe { *pcs.add(counter * 2) })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard_init(start: *mut u32, stop: *mut u32) {
+    // every module's constructor passes the same, linker-merged section
+    if start == stop || *start != 0 {
+        return;
+    }
+
+    let mut guard = start;
+    while guard < stop {
+        *guard = GUARDS.fetch_add(1, Ordering::Relaxed) + 1;
+        guard = guard.add(1);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_trace_pc_guard(guard: *mut u32) {
+    let map = ACTIVE_MAP.load(Ordering::Relaxed);
+    let index = *guard as usize;
+    if map.is_null() || index == 0 {
+        return;
+    }
+    let counter = map.add(index % MAP_SIZE);
+    *counter = (*counter).wrapping_add(1);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_8bit_counters_init(start: *mut u8, stop: *mut u8) {
+    COUNTERS_START.store(start, Ordering::Relaxed);
+    COUNTERS_LEN.store(stop as usize - start as usize, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sanitizer_cov_pcs_init(start: *const usize, stop: *const usize) {
+    PCS_START.store(start as *mut usize, Ordering::Relaxed);
+    let entries = (stop as usize - start as usize) / mem::size_of::<usize>();
+    PCS_LEN.store(entries / 2, Ordering::Relaxed);
+}
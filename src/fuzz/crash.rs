@@ -0,0 +1,566 @@
+//! Catching and triaging crashes in fuzz targets.
+//!
+//! `run_protected` saves a recovery point before calling into the target.  When the target
+//! panics or faults, the panic handler or exception handler records what happened and jumps back
+//! to that point, so the worker carries on with the next input.  The target's stack frames are
+//! abandoned rather than unwound (the kernel is built with `panic=abort`), so anything they held
+//! is leaked;  the harness's teardown hook should put shared state back in order.  Crashes in an
+//! interrupt handler that interrupted the target aren't recovered from, since jumping out of the
+//! handler would leave its locks held;  they are handled the way they would be outside a target.
+//!
+//! Crashes are bucketed by a signature made of the crash kind, the faulting PC (or panic location)
+//! and the top `SIGNATURE_FRAMES` return addresses.  Only the first crash in each bucket is
+//! reported;  later ones just count towards its hits.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+
+use barefuzz_protocol::{crash_kind, CrashReport, Message};
+use conquer_once::spin::Lazy;
+
+use crate::backtrace;
+use crate::concurrency::mutex::Mutex;
+use crate::host;
+use crate::interrupts::{self, InterruptFrame, StandardContext};
+use crate::task::executor::{self, current_task};
+use crate::task::slots::TaskSlots;
+use crate::{eprintln, println};
+
+/// How many return addresses are kept with a crash.
+const MAX_FRAMES: usize = 32;
+/// How many of the innermost return addresses go into a crash's signature.
+const SIGNATURE_FRAMES: usize = 8;
+const MAX_MESSAGE_LEN: usize = 256;
+/// The most tasks which can be running protected code at the same time.
+const MAX_RECOVERY_POINTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashKind {
+    Panic,
+    DivideError,
+    InvalidOpcode,
+    GeneralProtection { error_code: u64 },
+    StackSegment { error_code: u64 },
+    SegmentNotPresent { error_code: u64 },
+    PageFault { address: u64, error_code: u64 },
+    Guest,
+}
+
+impl CrashKind {
+    /// The kind's number in crash reports sent to the host.
+    pub fn id(&self) -> u8 {
+        match self {
+            CrashKind::Panic => crash_kind::PANIC,
+            CrashKind::DivideError => crash_kind::DIVIDE_ERROR,
+            CrashKind::InvalidOpcode => crash_kind::INVALID_OPCODE,
+            CrashKind::GeneralProtection { .. } => crash_kind::GENERAL_PROTECTION,
+            CrashKind::StackSegment { .. } => crash_kind::STACK_SEGMENT,
+            CrashKind::SegmentNotPresent { .. } => crash_kind::SEGMENT_NOT_PRESENT,
+            CrashKind::PageFault { .. } => crash_kind::PAGE_FAULT,
+            CrashKind::Guest => crash_kind::GUEST,
+        }
+    }
+
+    fn error_code(&self) -> u64 {
+        match *self {
+            CrashKind::GeneralProtection { error_code }
+            | CrashKind::StackSegment { error_code }
+            | CrashKind::SegmentNotPresent { error_code }
+            | CrashKind::PageFault { error_code, .. } => error_code,
+            _ => 0,
+        }
+    }
+}
+
+/// What was recorded about a crash when it happened.  Doesn't allocate, since it's filled in from
+/// exception handlers.
+#[derive(Clone)]
+pub struct CrashInfo {
+    pub kind: CrashKind,
+    /// The interrupt frame of the fault, for everything but panics.
+    pub frame: Option<InterruptFrame>,
+    /// The faulting instruction, or for panics a hash of the panic location.
+    pub pc: u64,
+    frames: [usize; MAX_FRAMES],
+    frame_count: usize,
+    message: [u8; MAX_MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl CrashInfo {
+    fn new(kind: CrashKind) -> Self {
+        Self {
+            kind,
+            frame: None,
+            pc: 0,
+            frames: [0; MAX_FRAMES],
+            frame_count: 0,
+            message: [0; MAX_MESSAGE_LEN],
+            message_len: 0,
+        }
+    }
+
+    /// The return addresses of the crashing code, innermost first.
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.frame_count]
+    }
+
+    /// The panic message, truncated to `MAX_MESSAGE_LEN` bytes.
+    pub fn message(&self) -> &str {
+        let mut len = self.message_len;
+        // a truncated message may end in the middle of a character
+        while core::str::from_utf8(&self.message[..len]).is_err() {
+            len -= 1;
+        }
+        core::str::from_utf8(&self.message[..len]).unwrap()
+    }
+
+    pub fn signature(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let frames = &self.frames()[..self.frame_count.min(SIGNATURE_FRAMES)];
+        let words = [self.kind.id() as u64, self.pc]
+            .into_iter()
+            .chain(frames.iter().map(|&f| f as u64));
+        for word in words {
+            for byte in word.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100_0000_01b3);
+            }
+        }
+        hash
+    }
+}
+
+impl Write for CrashInfo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MAX_MESSAGE_LEN - self.message_len);
+        self.message[self.message_len..self.message_len + len]
+            .copy_from_slice(&s.as_bytes()[..len]);
+        self.message_len += len;
+        Ok(())
+    }
+}
+
+/// Callee-saved registers, where to continue and the flags, as saved by `call_with_recovery`.
+#[derive(Debug, Default)]
+#[repr(C)]
+struct JmpBuf {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    /// A crash has been recorded and the landing pad is on its way back to the recovery point.
+    Recovering,
+}
+
+struct RecoveryPoint {
+    jmp: JmpBuf,
+    /// The interrupt depth of the protected code:  crashes deeper than that happened in a handler
+    /// which interrupted it, and can't be recovered from without abandoning the handler.
+    depth: usize,
+    state: State,
+    crash: Option<CrashInfo>,
+}
+
+static POINTS: TaskSlots<RecoveryPoint, MAX_RECOVERY_POINTS> = TaskSlots::new();
+
+/// Forgets the recovery points of tasks that end in protected code, e.g. because they were
+/// killed.  Must be called once, after `executor::init`.
+pub fn init() {
+    executor::register_exit_hook(|task| {
+        POINTS.remove(task);
+    });
+}
+
+/// The recovery point of the running task, if it's running protected code.
+fn current_point() -> Option<*mut RecoveryPoint> {
+    POINTS.get(current_task()?)
+}
+
+/// Saves the callee-saved registers, return address and flags in `jmp`, then calls `f(arg)` and
+/// returns 0.  If `recover_to(jmp)` is called before `f` returns, this returns 1 instead.
+#[naked]
+unsafe extern "C" fn call_with_recovery(
+    jmp: *mut JmpBuf,
+    f: extern "C" fn(*mut u8),
+    arg: *mut u8,
+) -> u64 {
+    asm!(
+        "
+        mov [rdi], rbx
+        mov [rdi + 8], rbp
+        mov [rdi + 16], r12
+        mov [rdi + 24], r13
+        mov [rdi + 32], r14
+        mov [rdi + 40], r15
+        lea rax, [rsp + 8] // the stack pointer once we've returned
+        mov [rdi + 48], rax
+        mov rax, [rsp] // our return address
+        mov [rdi + 56], rax
+        pushfq
+        pop rax
+        mov [rdi + 64], rax
+
+        mov rax, rsi
+        mov rdi, rdx
+        sub rsp, 8 // realign the stack for the call
+        call rax
+    .global barefuzz_recovery_return
+    barefuzz_recovery_return:
+        add rsp, 8
+        xor eax, eax
+        ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Returns from the `call_with_recovery` call that filled in `jmp`, with the value 1 and the flags
+/// it was called with, so that a crash with interrupts off doesn't leave them off.
+#[naked]
+unsafe extern "C" fn recover_to(jmp: *const JmpBuf) -> ! {
+    asm!(
+        "
+        mov rbx, [rdi]
+        mov rbp, [rdi + 8]
+        mov r12, [rdi + 16]
+        mov r13, [rdi + 24]
+        mov r14, [rdi + 32]
+        mov r15, [rdi + 40]
+        mov rsp, [rdi + 48]
+        push qword ptr [rdi + 64]
+        popfq
+        mov eax, 1
+        jmp [rdi + 56]
+        ",
+        options(noreturn)
+    )
+}
+
+// The exception handlers resume faulting code here, with `rax` holding an address just past the
+// faulting instruction.  Pushing it makes it look as if the faulting code had called the landing
+// pad, and the unwind info (which starts after the push) says exactly that, so the backtrace taken
+// in `barefuzz_fault_landing_inner` goes through the faulting code's frames.  The handlers can't
+// push it themselves, since the interrupt frame they return through sits right below the faulting
+// stack pointer.
+global_asm!(
+    "
+    .global barefuzz_fault_landing
+    barefuzz_fault_landing:
+    push rax
+    .cfi_startproc
+    push rbp
+    .cfi_def_cfa_offset 16
+    .cfi_offset rbp, -16
+    mov rbp, rsp
+    .cfi_def_cfa_register rbp
+    and rsp, -16
+    call barefuzz_fault_landing_inner
+    ud2
+    .cfi_endproc
+    "
+);
+
+extern "C" {
+    fn barefuzz_fault_landing();
+    /// Where `call_with_recovery` returns to from the protected code.
+    static barefuzz_recovery_return: u8;
+}
+
+/// Captures the backtrace of a crash, leaving out the innermost `skip` frames above this one.
+///
+/// Backtraces stop at the recovery point, so that the same crash has the same signature wherever
+/// the protected code was called from.
+#[inline(never)]
+fn capture_frames(skip: usize, frames: &mut [usize]) -> usize {
+    let stop_at = unsafe { &barefuzz_recovery_return as *const u8 as usize };
+    // and `capture_until` and this function
+    backtrace::capture_until(skip + 2, frames, stop_at)
+}
+
+#[no_mangle]
+extern "C" fn barefuzz_fault_landing_inner() -> ! {
+    let point = current_point().expect("fault landing pad reached without a recovery point");
+    unsafe {
+        if let Some(crash) = (*point).crash.as_mut() {
+            // skip this function and the landing pad
+            crash.frame_count = capture_frames(2, &mut crash.frames);
+        }
+        recover_to(&(*point).jmp)
+    }
+}
+
+/// Called by exception handlers.  If the running task is in protected code, and the fault is in
+/// that code rather than in another handler which interrupted it, records the fault and makes the
+/// handler return to the landing pad instead of the faulting instruction, and returns `true`.
+pub fn recover_from_fault(
+    kind: CrashKind,
+    frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) -> bool {
+    let point = match current_point() {
+        Some(point) => point,
+        None => return false,
+    };
+    let point = unsafe { &mut *point };
+    // a fault while getting back to the recovery point can't be recovered from
+    if point.state != State::Running || interrupts::interrupt_depth() != point.depth + 1 {
+        return false;
+    }
+
+    let mut crash = CrashInfo::new(kind);
+    crash.frame = Some(*frame);
+    crash.pc = frame.instruction_pointer;
+    point.crash = Some(crash);
+    point.state = State::Recovering;
+
+    ctx.rax = frame.instruction_pointer as usize + 1;
+    frame.instruction_pointer = barefuzz_fault_landing as usize as u64;
+    true
+}
+
+/// Like `recover_from_fault`, but also records `message`, e.g. for crashes reported by guest
+/// programs.
+pub fn recover_with_message(
+    kind: CrashKind,
+    message: &str,
+    frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) -> bool {
+    if !recover_from_fault(kind, frame, ctx) {
+        return false;
+    }
+    if let Some(point) = current_point() {
+        if let Some(crash) = unsafe { (*point).crash.as_mut() } {
+            let _ = crash.write_str(message);
+        }
+    }
+    true
+}
+
+/// Records a crash that happened outside the running code, in a program it ran in user mode,
+/// and returns to the recovery point.  `frame` is where the program was when it crashed.
+///
+/// Panics if the running task isn't in protected code, or if called from an interrupt handler.
+pub fn recover_from_program(kind: CrashKind, frame: InterruptFrame, message: &str) -> ! {
+    let point = match current_point() {
+        Some(point) => unsafe { &mut *point },
+        None => panic!("program crashed outside of a fuzz target: {:?}", kind),
+    };
+    assert!(point.state == State::Running, "recovering twice");
+    assert_eq!(
+        interrupts::interrupt_depth(),
+        point.depth,
+        "recovering from an interrupt handler"
+    );
+    point.state = State::Recovering;
+
+    let mut crash = CrashInfo::new(kind);
+    crash.frame = Some(frame);
+    crash.pc = frame.instruction_pointer;
+    let _ = crash.write_str(message);
+    point.crash = Some(crash);
+
+    unsafe { recover_to(&point.jmp) }
+}
+
+/// Called by the panic handler.  If the running task is in protected code, and didn't panic in an
+/// interrupt handler, records the panic and returns to the recovery point;  otherwise returns so
+/// that the panic is handled as usual.
+pub fn recover_from_panic(info: &PanicInfo) {
+    let point = match current_point() {
+        Some(point) => point,
+        None => return,
+    };
+    let point = unsafe { &mut *point };
+    // jumping out of a handler would leave its locks held and the interrupt unacknowledged
+    if point.state != State::Running || interrupts::interrupt_depth() != point.depth {
+        return;
+    }
+    point.state = State::Recovering;
+
+    let mut crash = CrashInfo::new(CrashKind::Panic);
+    let _ = write!(crash, "{}", info);
+    if let Some(location) = info.location() {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for byte in location.file().bytes().chain(location.line().to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+        crash.pc = hash;
+    }
+    // skip this function
+    crash.frame_count = capture_frames(1, &mut crash.frames);
+    point.crash = Some(crash);
+
+    unsafe { recover_to(&point.jmp) }
+}
+
+/// Runs `f`, returning what happened if it panicked or faulted.  Outside of a task, just runs
+/// `f`.
+pub fn run_protected(mut f: impl FnMut()) -> Result<(), CrashInfo> {
+    extern "C" fn trampoline(arg: *mut u8) {
+        let f = unsafe { &mut *(arg as *mut &mut dyn FnMut()) };
+        f();
+    }
+
+    let task = match current_task() {
+        Some(task) => task,
+        None => {
+            f();
+            return Ok(());
+        }
+    };
+
+    let mut point = RecoveryPoint {
+        jmp: JmpBuf::default(),
+        depth: interrupts::interrupt_depth(),
+        state: State::Running,
+        crash: None,
+    };
+    POINTS
+        .insert(task, &mut point)
+        .expect("too many tasks running protected code");
+
+    let mut f: &mut dyn FnMut() = &mut f;
+    let recovered = unsafe {
+        call_with_recovery(
+            &mut point.jmp,
+            trampoline,
+            &mut f as *mut &mut dyn FnMut() as *mut u8,
+        )
+    };
+
+    POINTS.remove(task);
+
+    if recovered == 0 {
+        return Ok(());
+    }
+    // read through a raw pointer:  the crash was written behind the compiler's back
+    match unsafe { ptr::read_volatile(&point.crash) } {
+        Some(crash) => Err(crash),
+        None => unreachable!("recovered without recording a crash"),
+    }
+}
+
+/// Every input which crashed the same way.
+#[derive(Clone)]
+pub struct Bucket {
+    pub signature: u64,
+    pub kind: CrashKind,
+    /// The first input that crashed this way.
+    pub input: Vec<u8>,
+    pub frame: Option<InterruptFrame>,
+    pub pc: u64,
+    pub frames: Vec<usize>,
+    pub message: String,
+    pub hits: u64,
+    /// The smallest input found that crashes the same way, once it's been minimised.
+    pub reproducer: Option<Vec<u8>>,
+}
+
+pub static BUCKETS: Lazy<Mutex<BTreeMap<u64, Bucket>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Files `crash`, caused by `input`, into its bucket.  Returns its signature, and whether it was
+/// the first crash with that signature.
+pub fn record(crash: &CrashInfo, input: &[u8]) -> (u64, bool) {
+    let signature = crash.signature();
+    let mut buckets = BUCKETS.lock();
+    if let Some(bucket) = buckets.get_mut(&signature) {
+        bucket.hits += 1;
+        return (signature, false);
+    }
+
+    buckets.insert(
+        signature,
+        Bucket {
+            signature,
+            kind: crash.kind,
+            input: input.to_vec(),
+            frame: crash.frame,
+            pc: crash.pc,
+            frames: crash.frames().to_vec(),
+            message: String::from(crash.message()),
+            hits: 1,
+            reproducer: None,
+        },
+    );
+    (signature, true)
+}
+
+/// Records the minimised reproducer for the bucket with `signature`.
+pub fn set_reproducer(signature: u64, reproducer: Vec<u8>) {
+    if let Some(bucket) = BUCKETS.lock().get_mut(&signature) {
+        bucket.reproducer = Some(reproducer);
+    }
+}
+
+impl Bucket {
+    pub fn to_report(&self) -> CrashReport {
+        CrashReport {
+            signature: self.signature,
+            kind: self.kind.id(),
+            address: match self.kind {
+                CrashKind::PageFault { address, .. } => address,
+                _ => 0,
+            },
+            error_code: self.kind.error_code(),
+            pc: self.pc,
+            hits: self.hits,
+            frames: self.frames.iter().map(|&frame| frame as u64).collect(),
+            message: self.message.clone(),
+            input: self.input.clone(),
+            reproducer: self.reproducer.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Reports the bucket with `signature` to the host, and prints it to the console.
+pub fn report(signature: u64) {
+    // a copy, so that other workers can file crashes while this one prints and sends
+    let bucket = match BUCKETS.lock().get(&signature) {
+        Some(bucket) => bucket.clone(),
+        None => return,
+    };
+    println!(
+        "CRASH {:016x}: {:?} at {:#x}",
+        bucket.signature, bucket.kind, bucket.pc
+    );
+    if !bucket.message.is_empty() {
+        println!("  {}", bucket.message);
+    }
+    if let Some(frame) = bucket.frame {
+        println!("  {:#X?}", frame);
+    }
+    for (depth, address) in bucket.frames.iter().enumerate() {
+        println!("  #{:<2} {:#x}", depth, address);
+    }
+    host::send(&Message::Crash(bucket.to_report()));
+}
+
+/// Prints a line for every bucket so far.
+pub fn print_summary() {
+    let buckets = BUCKETS.lock();
+    eprintln!("{} unique crashes", buckets.len());
+    for bucket in buckets.values() {
+        eprintln!(
+            "  {:016x} {:?} at {:#x}, {} hits",
+            bucket.signature, bucket.kind, bucket.pc, bucket.hits
+        );
+    }
+}
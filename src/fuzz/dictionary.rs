@@ -0,0 +1,228 @@
+//! Tokens for the mutators' dictionaries.
+//!
+//! Dictionaries are in AFL's `.dict` format:  one token per line, written as a double-quoted
+//! string, optionally preceded by a name and `=`, with `\\`, `\"` and `\xNN` escapes.  Blank lines
+//! and lines starting with `#` are ignored, as are AFL's `@level` suffixes on names.
+//!
+//! `init` loads a dictionary embedded at build time with the `dictionary-embedded` feature (from
+//! the file named by `BAREFUZZ_DICT`), one from the `opt/barefuzz/dict` fw_cfg file, and tokens
+//! harvested from the kernel's `.rodata`.  The host tool can send more while fuzzing.
+//!
+//! The bootloader doesn't hand over the kernel's ELF file, so `.rodata` is found with the
+//! section symbols from `eh_frame.ld` instead.  Fuzz targets are linked into the kernel, so their
+//! string literals end up in there, alongside the kernel's own.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::fmt;
+use core::slice;
+
+use crate::fuzz::runner;
+use crate::{fw_cfg, println};
+
+/// Shorter printable runs in `.rodata` aren't taken as tokens.
+const MIN_RODATA_TOKEN_LEN: usize = 4;
+/// Longer printable runs in `.rodata` are split into words.
+const MAX_RODATA_TOKEN_LEN: usize = 32;
+/// The most tokens taken from `.rodata`.
+const MAX_RODATA_TOKENS: usize = 1024;
+
+#[cfg(feature = "dictionary-embedded")]
+const EMBEDDED_DICTIONARY: Option<&[u8]> = Some(include_bytes!(env!("BAREFUZZ_DICT")));
+#[cfg(not(feature = "dictionary-embedded"))]
+const EMBEDDED_DICTIONARY: Option<&[u8]> = None;
+
+extern "C" {
+    // Symbols defined in eh_frame.ld
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+}
+
+/// Why a line of a dictionary couldn't be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    /// Counting from 1.
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let not_space = |byte: &u8| !byte.is_ascii_whitespace();
+    let start = bytes.iter().position(not_space).unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(not_space)
+        .map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Parses the quoted token at the start of `line`, which must be all that's left on it.
+fn parse_token(line: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut bytes = match line.split_first() {
+        Some((b'"', rest)) => rest.iter().copied(),
+        _ => return Err("expected a quoted token"),
+    };
+    let mut token = Vec::new();
+    loop {
+        match bytes.next().ok_or("unterminated token")? {
+            b'"' => break,
+            b'\\' => match bytes.next().ok_or("unterminated token")? {
+                byte @ (b'\\' | b'"') => token.push(byte),
+                b'x' => {
+                    let high = bytes.next().and_then(hex_digit);
+                    let low = bytes.next().and_then(hex_digit);
+                    match (high, low) {
+                        (Some(high), Some(low)) => token.push(high << 4 | low),
+                        _ => return Err("bad \\x escape"),
+                    }
+                }
+                _ => return Err("unknown escape"),
+            },
+            byte @ 0x20..=0x7e => token.push(byte),
+            _ => return Err("unprintable character in token; use \\x"),
+        }
+    }
+    if !bytes.all(|byte| byte.is_ascii_whitespace()) {
+        return Err("trailing characters after token");
+    }
+    if token.is_empty() {
+        return Err("empty token");
+    }
+    Ok(token)
+}
+
+/// Parses a dictionary in AFL's format.
+pub fn parse(text: &[u8]) -> Result<Vec<Vec<u8>>, ParseError> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.split(|&byte| byte == b'\n').enumerate() {
+        let line = trim(line);
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+
+        // skip the name and level, if there are any
+        let value = match line.iter().position(|&byte| byte == b'=') {
+            Some(equals) if line[0] != b'"' => {
+                let name = &line[..equals];
+                let valid = |&byte: &u8| byte.is_ascii_alphanumeric() || b"_@ \t".contains(&byte);
+                if !name.iter().all(valid) {
+                    return Err(ParseError {
+                        line: index + 1,
+                        reason: "bad token name",
+                    });
+                }
+                trim(&line[equals + 1..])
+            }
+            _ => line,
+        };
+        let token = parse_token(value).map_err(|reason| ParseError {
+            line: index + 1,
+            reason,
+        })?;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Finds strings in `data`:  runs of printable characters long enough to be worth a token, split
+/// into words if they're too long to be a single one.  Returns at most `max` distinct tokens.
+pub fn harvest_strings(data: &[u8], max: usize) -> Vec<Vec<u8>> {
+    let mut tokens = BTreeSet::new();
+    let printable = |byte: &u8| (0x20..=0x7e).contains(byte);
+    for run in data.split(|byte| !printable(byte)) {
+        if tokens.len() >= max {
+            break;
+        }
+        if run.len() < MIN_RODATA_TOKEN_LEN {
+            continue;
+        }
+        if run.len() <= MAX_RODATA_TOKEN_LEN {
+            tokens.insert(run);
+            continue;
+        }
+        // string literals are packed together without terminators, so a long run is most likely
+        // several of them, or a message;  its words are the best guess at what gets compared
+        let separator = |byte: &u8| byte.is_ascii_whitespace() || b"{}()[],;:'\"".contains(byte);
+        for word in run.split(separator) {
+            if (MIN_RODATA_TOKEN_LEN..=MAX_RODATA_TOKEN_LEN).contains(&word.len()) {
+                tokens.insert(word);
+            }
+        }
+    }
+    tokens.into_iter().take(max).map(<[u8]>::to_vec).collect()
+}
+
+/// The kernel's read-only data, which includes the fuzz targets' string literals.
+fn rodata() -> &'static [u8] {
+    unsafe {
+        let start = &__rodata_start as *const u8;
+        let end = &__rodata_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Parses `text` and adds its tokens to every worker's dictionary.  Returns how many there were.
+pub fn load(text: &[u8]) -> Result<usize, ParseError> {
+    let tokens = parse(text)?;
+    let count = tokens.len();
+    for token in tokens {
+        runner::add_token(token);
+    }
+    Ok(count)
+}
+
+/// Loads the built-in dictionaries and the tokens from `.rodata`.
+pub fn init() {
+    let sources = [
+        (
+            "the embedded dictionary",
+            EMBEDDED_DICTIONARY.map(<[u8]>::to_vec),
+        ),
+        ("opt/barefuzz/dict", fw_cfg::read_file("opt/barefuzz/dict")),
+    ];
+    for (name, text) in sources {
+        match text.as_deref().map(load) {
+            Some(Ok(count)) => println!("Loaded {} tokens from {}", count, name),
+            Some(Err(error)) => println!("Couldn't load {}: {}", name, error),
+            None => {}
+        }
+    }
+
+    let tokens = harvest_strings(rodata(), MAX_RODATA_TOKENS);
+    println!("Harvested {} tokens from .rodata", tokens.len());
+    for token in tokens {
+        runner::add_token(token);
+    }
+}
+
+#[test_case]
+fn test_parse_dictionary() {
+    let text = b"# comment\n\nkw_magic=\"MAGIC\"\nkw_hex@2 = \"\\x7fELF\"\n\"a \\\"quote\\\"\"\n";
+    let tokens = parse(text).unwrap();
+    assert_eq!(
+        tokens,
+        [&b"MAGIC"[..], b"\x7fELF", b"a \"quote\""].map(<[u8]>::to_vec)
+    );
+
+    let error = parse(b"\"ok\"\n\"unterminated\n").unwrap_err();
+    assert_eq!(error.line, 2);
+}
+
+#[test_case]
+fn test_harvest_strings() {
+    let data = b"\0\x01MAGIC\0ab\0Couldn't open the file at the given offset\xff";
+    let tokens = harvest_strings(data, 16);
+    assert!(tokens.contains(&b"MAGIC".to_vec()));
+    assert!(tokens.contains(&b"offset".to_vec()));
+    assert!(!tokens.iter().any(|token| token == b"ab"));
+}
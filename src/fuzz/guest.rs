@@ -0,0 +1,667 @@
+//! Guest programs:  fuzz targets which drive the fuzzing loop themselves through hypercalls,
+//! rather than being called with each input.
+//!
+//! # Hypercall ABI
+//!
+//! A guest makes a hypercall with `int 0x80`, the number in `rax` and arguments in `rdi` and
+//! `rsi`.  The result comes back in `rax`, and every other register is preserved.  Calls made
+//! from anything but a guest return `HYPERCALL_ERROR`.
+//!
+//! | `rax`  | call            | arguments             | returns                         |
+//! |--------|-----------------|-----------------------|---------------------------------|
+//! | `0x10` | `NextInput`     | buffer, capacity      | bytes copied into the buffer    |
+//! | `0x11` | `EndIteration`  |                       | 0                               |
+//! | `0x12` | `Snapshot`      |                       | 0                               |
+//! | `0x13` | `Crash`         | message, length       | doesn't return                  |
+//! | `0x14` | `Log`           | message, length       | 0                               |
+//! | `0x15` | `Exit`          |                       | doesn't return                  |
+//!
+//! Messages are UTF-8.  Inputs longer than the buffer are cut short.  `abi` has wrappers for all
+//! of them.
+//!
+//! # Running a guest
+//!
+//! Each fuzzing worker gets its own instance of the program, with its own stack, which runs as a
+//! coroutine of the worker rather than a task of its own:  `run`, the `guest_program` fuzz target,
+//! switches to the guest, which takes the input with `NextInput`, and switches back when the guest
+//! calls `EndIteration` or asks for another input.  The first input starts the program at its entry
+//! point, and returning from the entry point (or `Exit`) makes the next input start it again.
+//!
+//! After `Snapshot`, every input starts from the snapshot instead, with the guest's stack and
+//! writable memory put back the way they were.  Without one, the program carries on from where
+//! it asked for the input, and after a crash from where it asked for the one before.
+//!
+//! Guests run in the worker's task, so crashes in them are caught like crashes in any other
+//! target.
+//!
+//! # Guests in processes
+//!
+//! A separately built static executable can be a guest too, running in user mode as a process of
+//! its own:  the `linux_guest` target in `program` starts it once per worker and keeps it running
+//! from one input to the next.  It makes hypercalls with `int 0x80` as above, or with `syscall`
+//! and the number `HYPERCALL_SYSCALL_BASE` plus the hypercall's, alongside its Linux system calls.
+//! Buffers and messages have to be in its own memory.  The worker waits while the program works
+//! on an input;  `NextInput` without one on offer ends the iteration and makes the program wait
+//! for the next.  `Crash` and faults end the program and crash the target, and `Exit` just ends
+//! it, so the next input starts it again.  Its memory isn't the worker's to save and restore, so
+//! `Snapshot` fails:  the program carries its state over from one input to the next.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr, slice, str};
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::fuzz::crash::{recover_with_message, CrashKind};
+use crate::fuzz::snapshot::{Registers, Snapshot};
+use crate::interrupts::{attach_new_interrupt_handler, InterruptFrame, StandardContext};
+use crate::linux::{self, Exit, ExitCell};
+use crate::println;
+use crate::task::executor::{self, current_task};
+use crate::task::slots::TaskSlots;
+use crate::task::TaskId;
+
+pub mod abi;
+
+/// Returned by hypercalls made from outside a guest, or which fail.
+pub const HYPERCALL_ERROR: u64 = u64::MAX;
+
+/// Where the hypercalls start among system call numbers, for programs making them with `syscall`.
+pub const HYPERCALL_SYSCALL_BASE: u64 = 0x4246_0000;
+
+/// How big each guest's stack is.
+const GUEST_STACK_SIZE: usize = 64 * 1024;
+
+/// The most guests which can be running at the same time, one per worker.
+const MAX_GUESTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Hypercall {
+    NextInput = 0x10,
+    EndIteration = 0x11,
+    Snapshot = 0x12,
+    Crash = 0x13,
+    Log = 0x14,
+    Exit = 0x15,
+}
+
+impl Hypercall {
+    const ALL: [Self; 6] = [
+        Self::NextInput,
+        Self::EndIteration,
+        Self::Snapshot,
+        Self::Crash,
+        Self::Log,
+        Self::Exit,
+    ];
+
+    /// The hypercall a program makes with system call `number`, if it's one.
+    pub fn from_syscall(number: u64) -> Option<Self> {
+        let number = number.checked_sub(HYPERCALL_SYSCALL_BASE)?;
+        Self::ALL.into_iter().find(|&call| call as u64 == number)
+    }
+
+    fn handler(self) -> extern "C" fn(&mut InterruptFrame, &mut StandardContext) {
+        match self {
+            Self::NextInput => next_input,
+            Self::EndIteration => end_iteration,
+            Self::Snapshot => snapshot,
+            Self::Crash => crash,
+            Self::Log => log,
+            Self::Exit => exit,
+        }
+    }
+}
+
+/// A program to run as a guest.
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// Where the program starts, called with no arguments on its own stack.
+    pub entry: u64,
+    /// Memory the program writes to other than its stack, which snapshots save and restore.
+    pub writable: Vec<Range<u64>>,
+}
+
+static PROGRAM: OnceCell<Program> = OnceCell::uninit();
+
+/// Makes `program` the one the `guest_program` fuzz target runs.  Can only be called once,
+/// before fuzzing starts.
+pub fn load(program: Program) {
+    PROGRAM.init_once(|| program);
+}
+
+/// The loaded program, or the built-in example guest if none was loaded.  Separately built programs
+/// run as processes instead, with the `linux_guest` target.
+pub fn program() -> &'static Program {
+    PROGRAM.get_or_init(|| Program {
+        entry: example_guest as usize as u64,
+        writable: Vec::new(),
+    })
+}
+
+/// The worker's callee-saved registers and where it continues, while a guest runs.
+#[derive(Debug, Default)]
+#[repr(C)]
+struct WorkerContext {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+/// One instance of the program.
+struct Guest {
+    stack: Vec<u64>,
+    worker: WorkerContext,
+    /// Where the guest stopped at the end of its last iteration, if it's been started.
+    parked: Option<Registers>,
+    snapshot: Option<Snapshot>,
+    /// The input for the running iteration, until the guest takes it.
+    input: Option<(*const u8, usize)>,
+    /// The program's task and how it ended, for a guest running as a process of its own.
+    process: Option<(TaskId, ExitCell)>,
+    /// Whether a process is working on an input, which the worker waits for:  set when the worker
+    /// offers one, and cleared when the program asks for the next or ends the iteration.
+    busy: AtomicBool,
+    /// What a process said when it crashed.
+    crash_message: Option<String>,
+}
+
+impl Guest {
+    fn new() -> Self {
+        Self {
+            stack: vec![0; GUEST_STACK_SIZE / 8],
+            worker: WorkerContext::default(),
+            parked: None,
+            snapshot: None,
+            input: None,
+            process: None,
+            busy: AtomicBool::new(false),
+            crash_message: None,
+        }
+    }
+
+    /// Offers `input` to the guest for the next iteration.
+    fn offer(&mut self, input: &[u8]) {
+        self.input = Some((input.as_ptr(), input.len()));
+        self.busy.store(true, Ordering::SeqCst);
+    }
+
+    /// The input on offer, for `NextInput`.  Without one, the program has taken this iteration's
+    /// input already, so asking again ends the iteration.
+    fn take_input(&mut self) -> Option<&'static [u8]> {
+        match self.input.take() {
+            Some((input, len)) => Some(unsafe { slice::from_raw_parts(input, len) }),
+            None => {
+                self.busy.store(false, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// Ends the iteration, unless the program hasn't taken its input yet.
+    fn end_iteration(&mut self) {
+        if self.input.is_none() {
+            self.busy.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the hypercall being handled comes from the guest's process, rather than from the
+    /// guest running in its worker's task.
+    fn called_from_process(&self) -> bool {
+        matches!(self.process, Some((task, _)) if Some(task) == current_task())
+    }
+
+    fn stack_top(&self) -> u64 {
+        let end = self.stack.as_ptr() as u64 + (self.stack.len() * 8) as u64;
+        end & !0xf
+    }
+
+    /// The registers to start the program with, with a return address to `Exit` on its stack.
+    fn entry_registers(&mut self) -> Registers {
+        let sp = self.stack_top() - 8;
+        unsafe { *(sp as *mut u64) = barefuzz_guest_returned as usize as u64 };
+        Registers {
+            frame: InterruptFrame {
+                instruction_pointer: program().entry,
+                stack_pointer: sp,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+// the input is only read by the guest's own task, and the reaper just frees the rest
+unsafe impl Send for Guest {}
+
+static GUESTS: TaskSlots<Guest, MAX_GUESTS> = TaskSlots::new();
+/// The guests of processes, by the program's task rather than the worker's.  The worker's slot in
+/// `GUESTS` owns them.
+static PROCESSES: TaskSlots<Guest, MAX_GUESTS> = TaskSlots::new();
+
+/// The running task's guest, if it has one or it's a guest's process.
+fn current_guest() -> Option<&'static mut Guest> {
+    let task = current_task()?;
+    unsafe { GUESTS.get(task).or_else(|| PROCESSES.get(task))?.as_mut() }
+}
+
+/// The running task's guest, made first if it doesn't have one yet.
+///
+/// Panics if `MAX_GUESTS` tasks already have a guest.
+fn current_or_new_guest() -> &'static mut Guest {
+    if let Some(guest) = current_guest() {
+        return guest;
+    }
+    let task = current_task().expect("guests can only run in a task");
+    let guest = Box::into_raw(Box::new(Guest::new()));
+    without_interrupts(|| GUESTS.insert(task, guest)).expect("too many guests");
+    unsafe { &mut *guest }
+}
+
+// Where the program goes if it returns from its entry point.
+global_asm!(
+    "
+    .global barefuzz_guest_returned
+    barefuzz_guest_returned:
+    mov eax, {exit}
+    int 0x80
+    ud2
+    ",
+    exit = const Hypercall::Exit as u8,
+);
+
+extern "C" {
+    fn barefuzz_guest_returned();
+}
+
+const _: () = assert!(mem::size_of::<InterruptFrame>() == 40);
+const _: () = assert!(mem::size_of::<StandardContext>() == 112);
+
+/// Saves the worker's state in `worker` and switches to the guest with `registers`.  Returns
+/// once a hypercall switches back with `leave_guest`.
+#[naked]
+unsafe extern "C" fn enter_guest(worker: *mut WorkerContext, registers: *const Registers) {
+    asm!(
+        "
+        mov [rdi], rbx
+        mov [rdi + 8], rbp
+        mov [rdi + 16], r12
+        mov [rdi + 24], r13
+        mov [rdi + 32], r14
+        mov [rdi + 40], r15
+        lea rax, [rsp + 8] // the stack pointer once we've returned
+        mov [rdi + 48], rax
+        mov rax, [rsp] // our return address
+        mov [rdi + 56], rax
+
+        // the interrupt frame, then the registers, with rsi last
+        push qword ptr [rsi + 32]
+        push qword ptr [rsi + 24]
+        push qword ptr [rsi + 16]
+        push qword ptr [rsi + 8]
+        push qword ptr [rsi]
+        mov rbp, [rsi + 152]
+        mov rax, [rsi + 40]
+        mov rbx, [rsi + 48]
+        mov rcx, [rsi + 56]
+        mov rdx, [rsi + 64]
+        mov rdi, [rsi + 80]
+        mov r8, [rsi + 88]
+        mov r9, [rsi + 96]
+        mov r10, [rsi + 104]
+        mov r11, [rsi + 112]
+        mov r12, [rsi + 120]
+        mov r13, [rsi + 128]
+        mov r14, [rsi + 136]
+        mov r15, [rsi + 144]
+        mov rsi, [rsi + 72]
+        iretq
+        ",
+        options(noreturn)
+    )
+}
+
+/// Returns from the `enter_guest` call that filled in `worker`.
+#[naked]
+unsafe extern "C" fn leave_guest(worker: *const WorkerContext) -> ! {
+    asm!(
+        "
+        mov rbx, [rdi]
+        mov rbp, [rdi + 8]
+        mov r12, [rdi + 16]
+        mov r13, [rdi + 24]
+        mov r14, [rdi + 32]
+        mov r15, [rdi + 40]
+        mov rsp, [rdi + 48]
+        jmp [rdi + 56]
+        ",
+        options(noreturn)
+    )
+}
+
+/// The registers of the code that made a hypercall, `rbp` included.
+fn interrupted_registers(frame: &mut InterruptFrame, ctx: &StandardContext) -> Registers {
+    let rbp = unsafe { *frame.saved_rbp(false) };
+    Registers {
+        frame: *frame,
+        ctx: *ctx,
+        rbp,
+    }
+}
+
+/// Makes the hypercall return to the worker instead of the guest.
+fn switch_to_worker(guest: &Guest, frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    *frame = InterruptFrame {
+        instruction_pointer: leave_guest as usize as u64,
+        stack_pointer: frame.stack_pointer,
+        ..Default::default()
+    };
+    ctx.rdi = &guest.worker as *const WorkerContext as usize;
+}
+
+/// Runs one iteration of the calling worker's guest on `input`.  The `guest_program` fuzz target.
+pub fn run(input: &[u8]) {
+    let guest = current_or_new_guest();
+    let registers = match (&guest.snapshot, guest.parked) {
+        (Some(snapshot), _) => snapshot.restore(),
+        (None, Some(parked)) => parked,
+        (None, None) => guest.entry_registers(),
+    };
+    guest.offer(input);
+    unsafe { enter_guest(&mut guest.worker, &registers) };
+    guest.input = None;
+}
+
+crate::fuzz_target!(guest_program, |data: &[u8]| run(data));
+
+/// Makes the program running as `task`, which ends with `exit`, the calling worker's guest.  Call
+/// with the executor locked, so the program can't make a hypercall before it's attached.
+///
+/// Panics if `MAX_GUESTS` programs are attached already.
+pub fn attach_process(task: TaskId, exit: ExitCell) {
+    let guest = current_or_new_guest();
+    guest.process = Some((task, exit));
+    without_interrupts(|| PROCESSES.insert(task, guest)).expect("too many guests");
+}
+
+/// The task of the program that's the calling worker's guest, and how it ended, if there is one.
+pub fn process() -> Option<(TaskId, ExitCell)> {
+    current_guest()?.process.clone()
+}
+
+/// Offers `input` to the calling worker's program, which has to be attached.
+pub fn offer(input: &[u8]) {
+    let guest = current_guest().expect("no program to offer the input to");
+    without_interrupts(|| guest.offer(input));
+}
+
+/// Whether the calling worker's program is still working on the input it was offered.
+pub fn busy() -> bool {
+    current_guest().map_or(false, |guest| guest.busy.load(Ordering::SeqCst))
+}
+
+/// Takes back the input on offer, if the program hasn't taken it, once the worker is done waiting.
+pub fn withdraw() {
+    if let Some(guest) = current_guest() {
+        without_interrupts(|| guest.input = None);
+    }
+}
+
+/// Detaches the calling worker's program once it's ended, returning what it said if it crashed.
+pub fn detach_process() -> Option<String> {
+    let guest = current_guest()?;
+    let (task, _) = guest.process.take()?;
+    without_interrupts(|| PROCESSES.remove(task));
+    guest.busy.store(false, Ordering::SeqCst);
+    guest.crash_message.take()
+}
+
+/// The running task's guest, or `None` after making the hypercall fail if it doesn't have one.
+fn guest_or_error(ctx: &mut StandardContext) -> Option<&'static mut Guest> {
+    let guest = current_guest();
+    if guest.is_none() {
+        ctx.rax = HYPERCALL_ERROR as usize;
+    }
+    guest
+}
+
+/// Reads a message the guest passed in `rdi` and `rsi`.  A process's has to be in its memory.
+fn message(guest: &Guest, ctx: &StandardContext) -> &'static str {
+    let bytes = match guest.called_from_process() {
+        true => match linux::user_memory(ctx.rdi as u64, ctx.rsi as u64, false) {
+            Ok(bytes) => bytes,
+            Err(_) => return "<message isn't in the program's memory>",
+        },
+        false => unsafe { slice::from_raw_parts(ctx.rdi as *const u8, ctx.rsi) },
+    };
+    str::from_utf8(bytes).unwrap_or("<message isn't UTF-8>")
+}
+
+/// `NextInput` from a process:  copies the input into the program's buffer, or makes the program
+/// wait for one by rewinding it to make the hypercall again once another task has had a turn.
+fn next_input_for_process(
+    guest: &mut Guest,
+    frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) {
+    match guest.take_input() {
+        Some(input) => {
+            let len = input.len().min(ctx.rsi);
+            ctx.rax = match linux::user_memory(ctx.rdi as u64, len as u64, true) {
+                Ok(buffer) => {
+                    buffer.copy_from_slice(&input[..len]);
+                    len
+                }
+                Err(_) => HYPERCALL_ERROR as usize,
+            };
+        }
+        None => {
+            // `int 0x80` and `syscall` are both two bytes
+            frame.instruction_pointer -= 2;
+            executor::preempt(frame, ctx);
+        }
+    }
+}
+
+extern "C" fn next_input(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    if guest.called_from_process() {
+        return next_input_for_process(guest, frame, ctx);
+    }
+    match guest.take_input() {
+        Some(input) => {
+            let len = input.len().min(ctx.rsi);
+            unsafe { ptr::copy_nonoverlapping(input.as_ptr(), ctx.rdi as *mut u8, len) };
+            ctx.rax = len;
+        }
+        None => {
+            // this iteration's input was taken already, so this starts the next one:  park the
+            // guest on the hypercall, to make it again once there's an input
+            let mut registers = interrupted_registers(frame, ctx);
+            registers.frame.instruction_pointer -= 2;
+            guest.parked = Some(registers);
+            switch_to_worker(guest, frame, ctx);
+        }
+    }
+}
+
+extern "C" fn end_iteration(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    ctx.rax = 0;
+    guest.end_iteration();
+    if guest.called_from_process() {
+        return;
+    }
+    guest.parked = Some(interrupted_registers(frame, ctx));
+    switch_to_worker(guest, frame, ctx);
+}
+
+extern "C" fn snapshot(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    if guest.called_from_process() {
+        ctx.rax = HYPERCALL_ERROR as usize;
+        return;
+    }
+    ctx.rax = 0;
+    let mut regions = vec![frame.stack_pointer..guest.stack_top()];
+    regions.extend(program().writable.iter().cloned());
+    let snapshot = Snapshot::take(interrupted_registers(frame, ctx), &regions);
+    println!("Guest snapshot taken, {} bytes", snapshot.size());
+    guest.snapshot = Some(snapshot);
+}
+
+extern "C" fn crash(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    let message = message(guest, ctx);
+    if guest.called_from_process() {
+        // the worker crashes the target once it sees how the program ended
+        guest.crash_message = Some(String::from(message));
+        let exit = Exit::Faulted {
+            kind: CrashKind::Guest,
+            frame: *frame,
+        };
+        return linux::end_current_process(exit, frame, ctx);
+    }
+    if !recover_with_message(CrashKind::Guest, message, frame, ctx) {
+        panic!("guest crashed outside of a fuzz target: {}", message);
+    }
+}
+
+extern "C" fn log(_frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    println!("[guest] {}", message(guest, ctx));
+    ctx.rax = 0;
+}
+
+extern "C" fn exit(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let guest = match guest_or_error(ctx) {
+        Some(guest) => guest,
+        None => return,
+    };
+    if guest.called_from_process() {
+        return linux::end_current_process(Exit::Exited(0), frame, ctx);
+    }
+    guest.parked = None;
+    switch_to_worker(guest, frame, ctx);
+}
+
+/// Whether system call `number` is a hypercall.
+pub fn is_hypercall(number: u64) -> bool {
+    Hypercall::from_syscall(number).is_some()
+}
+
+/// Makes the hypercall a program made with `syscall`, for `linux::handle_syscall`.
+pub fn handle_syscall(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    match Hypercall::from_syscall(ctx.rax as u64) {
+        Some(call) => call.handler()(frame, ctx),
+        None => ctx.rax = HYPERCALL_ERROR as usize,
+    }
+}
+
+/// Attaches the hypercall handlers, and has guests dropped along with their tasks.  Must be called
+/// once, after `executor::init`.
+pub fn init() {
+    attach_new_interrupt_handler(Hypercall::NextInput as u8, next_input);
+    attach_new_interrupt_handler(Hypercall::EndIteration as u8, end_iteration);
+    attach_new_interrupt_handler(Hypercall::Snapshot as u8, snapshot);
+    attach_new_interrupt_handler(Hypercall::Crash as u8, crash);
+    attach_new_interrupt_handler(Hypercall::Log as u8, log);
+    attach_new_interrupt_handler(Hypercall::Exit as u8, exit);
+    executor::register_exit_hook(|task| {
+        // a program's guest belongs to its worker
+        PROCESSES.remove(task);
+        if let Some(guest) = GUESTS.remove(task) {
+            let guest = unsafe { Box::from_raw(guest) };
+            if let Some((process, _)) = &guest.process {
+                PROCESSES.remove(*process);
+            }
+            executor::reap(guest);
+        }
+    });
+}
+
+/// The program run by the `guest_program` target when no other is loaded.  It only uses hypercalls,
+/// like a separately built program would.
+extern "C" fn example_guest() {
+    let mut buffer = [0; 256];
+    abi::log("example guest started");
+    abi::snapshot();
+    loop {
+        let len = abi::next_input(&mut buffer);
+        if buffer[..len].starts_with(b"HYPER") {
+            abi::crash("example guest got HYPER");
+        }
+        abi::end_iteration();
+    }
+}
+
+#[test_case]
+fn test_process_iterations() {
+    let mut guest = Guest::new();
+    let input = [1u8, 2, 3];
+
+    // the program asks for an input before the worker has one
+    assert!(guest.take_input().is_none());
+    assert!(!guest.busy.load(Ordering::SeqCst));
+
+    // it gets the input, and the worker waits until it ends the iteration
+    guest.offer(&input);
+    assert!(guest.busy.load(Ordering::SeqCst));
+    guest.end_iteration();
+    assert!(
+        guest.busy.load(Ordering::SeqCst),
+        "ended before taking the input"
+    );
+    assert_eq!(guest.take_input(), Some(&input[..]));
+    assert!(guest.busy.load(Ordering::SeqCst));
+    guest.end_iteration();
+    assert!(!guest.busy.load(Ordering::SeqCst));
+
+    // or until it asks for the next one
+    guest.offer(&input);
+    assert_eq!(guest.take_input(), Some(&input[..]));
+    assert!(guest.take_input().is_none());
+    assert!(!guest.busy.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_hypercall_syscall_numbers() {
+    assert_eq!(
+        Hypercall::from_syscall(HYPERCALL_SYSCALL_BASE + 0x10),
+        Some(Hypercall::NextInput)
+    );
+    assert_eq!(
+        Hypercall::from_syscall(HYPERCALL_SYSCALL_BASE + 0x15),
+        Some(Hypercall::Exit)
+    );
+    assert!(!is_hypercall(HYPERCALL_SYSCALL_BASE + 0x16));
+    assert!(!is_hypercall(0x10));
+    assert!(!is_hypercall(60));
+}
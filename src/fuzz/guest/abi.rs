@@ -0,0 +1,65 @@
+//! The guest side of the hypercall ABI:  thin wrappers around `int 0x80`, as a guest program
+//! would write them.  The built-in example guest uses these, and a separately built program can
+//! copy them.
+
+use core::arch::asm;
+
+use super::Hypercall;
+
+#[inline(always)]
+unsafe fn hypercall(number: Hypercall, arg0: u64, arg1: u64) -> u64 {
+    let result;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number as u64 => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+    );
+    result
+}
+
+/// Waits for the next input, copies as much of it as fits into `buffer`, and returns how many
+/// bytes that was.  Ends the previous iteration if `end_iteration` wasn't called.
+pub fn next_input(buffer: &mut [u8]) -> usize {
+    unsafe {
+        hypercall(
+            Hypercall::NextInput,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+        ) as usize
+    }
+}
+
+/// Says that the current input has been dealt with.
+pub fn end_iteration() {
+    unsafe { hypercall(Hypercall::EndIteration, 0, 0) };
+}
+
+/// Saves the program's state here.  Every later iteration starts from this point, with the
+/// program's stack and writable memory as they are now.
+pub fn snapshot() {
+    unsafe { hypercall(Hypercall::Snapshot, 0, 0) };
+}
+
+/// Reports a crash with `message`.  Doesn't return.
+pub fn crash(message: &str) -> ! {
+    unsafe {
+        hypercall(
+            Hypercall::Crash,
+            message.as_ptr() as u64,
+            message.len() as u64,
+        );
+    }
+    unreachable!("crash hypercall returned");
+}
+
+/// Prints `message` on the kernel's console.
+pub fn log(message: &str) {
+    unsafe {
+        hypercall(
+            Hypercall::Log,
+            message.as_ptr() as u64,
+            message.len() as u64,
+        )
+    };
+}
@@ -0,0 +1,152 @@
+//! Registration of fuzz targets.
+//!
+//! `fuzz_target!` puts a `Harness` describing the target in the `fuzz_targets` linker section,
+//! the same way `#[test_case]` collects tests, so targets can live anywhere in the kernel and are
+//! all found at boot.
+//!
+//! A target takes either the raw input as `&[u8]`, or any type implementing `Arbitrary`, which is
+//! built from the input before each run.
+
+use core::slice;
+
+use crate::fuzz::arbitrary::{Arbitrary, Unstructured};
+use crate::{fw_cfg, println};
+
+/// A fuzz target, as registered by `fuzz_target!`.
+#[derive(Debug)]
+pub struct Harness {
+    pub name: &'static str,
+    /// Runs one input.
+    pub run: fn(&[u8]),
+    /// Runs once in each worker before it starts fuzzing.
+    pub init: Option<fn()>,
+    /// Runs after every input, to undo whatever state it left behind.
+    pub teardown: Option<fn()>,
+}
+
+/// What a fuzz target can take as its argument.
+pub trait FuzzInput<'a>: Sized {
+    /// Makes the argument out of `data`, or `None` if it doesn't describe one.
+    fn from_input(data: &'a [u8]) -> Option<Self>;
+}
+
+impl<'a> FuzzInput<'a> for &'a [u8] {
+    fn from_input(data: &'a [u8]) -> Option<Self> {
+        Some(data)
+    }
+}
+
+impl<'a, T: Arbitrary> FuzzInput<'a> for T {
+    fn from_input(data: &'a [u8]) -> Option<Self> {
+        Unstructured::new(data).arbitrary().ok()
+    }
+}
+
+/// Runs `target` on `data`, skipping inputs it can't take.  Used by `fuzz_target!`.
+#[doc(hidden)]
+pub fn run_target<'a, T: FuzzInput<'a>>(data: &'a [u8], target: fn(T)) {
+    if let Some(input) = T::from_input(data) {
+        target(input);
+    }
+}
+
+/// Registers a fuzz target.  The argument's type has to be written out, since it decides how the
+/// input is handed over.
+///
+/// ```ignore
+/// fuzz_target!(parse_header, |data: &[u8]| {
+///     let _ = parse_header(data);
+/// });
+///
+/// fuzz_target!(filesystem, |ops: Vec<FsOp>| fs_ops(&ops), init = mount, teardown = unmount);
+/// ```
+#[macro_export]
+macro_rules! fuzz_target {
+    ($name:ident, $run:expr $(, init = $init:expr)? $(, teardown = $teardown:expr)? $(,)?) => {
+        // out of the way of whatever else is called `$name`, like the function being fuzzed
+        const _: () = {
+            #[used]
+            #[link_section = "fuzz_targets"]
+            static HARNESS: $crate::fuzz::harness::Harness = $crate::fuzz::harness::Harness {
+                name: stringify!($name),
+                run: {
+                    fn run(data: &[u8]) {
+                        $crate::fuzz::harness::run_target(data, $run)
+                    }
+                    run
+                },
+                init: $crate::fuzz_target!(@optional $($init)?),
+                teardown: $crate::fuzz_target!(@optional $($teardown)?),
+            };
+        };
+    };
+    (@optional) => {
+        None
+    };
+    (@optional $hook:expr) => {
+        Some($hook)
+    };
+}
+
+extern "C" {
+    // Defined by the linker around the `fuzz_targets` section
+    static __start_fuzz_targets: Harness;
+    static __stop_fuzz_targets: Harness;
+}
+
+/// Every registered fuzz target.
+pub fn harnesses() -> &'static [Harness] {
+    unsafe {
+        let start = &__start_fuzz_targets as *const Harness;
+        let stop = &__stop_fuzz_targets as *const Harness;
+        slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Harness> {
+    harnesses().iter().find(|harness| harness.name == name)
+}
+
+/// Picks the harness to fuzz:  the one named by the `opt/barefuzz/harness` fw_cfg file if QEMU was
+/// given one, then the one named by `BAREFUZZ_HARNESS` at build time, then the first registered.
+pub fn select() -> &'static Harness {
+    println!("Fuzz targets:");
+    for harness in harnesses() {
+        println!("  {}", harness.name);
+    }
+
+    let requested = fw_cfg::read_string("opt/barefuzz/harness");
+    let requested = requested.as_deref().or(option_env!("BAREFUZZ_HARNESS"));
+    if let Some(name) = requested {
+        match find(name) {
+            Some(harness) => return harness,
+            None => println!("No fuzz target called {:?}, using the default", name),
+        }
+    }
+    harnesses().first().expect("no fuzz targets registered")
+}
+
+// A target that is always there, for checking that the fuzzer finds its way through a few nested
+// comparisons.
+crate::fuzz_target!(magic_bytes, |data: &[u8]| {
+    if data.len() >= 4 && data[0] == b'F' && data[1] == b'U' && data[2] == b'Z' && data[3] == b'Z' {
+        println!("magic_bytes: found FUZZ");
+    }
+});
+
+#[cfg(test)]
+fn parse_header(data: &[u8]) -> Option<u8> {
+    data.first().copied()
+}
+
+// named after the function it fuzzes, as in `fuzz_target!`'s example
+#[cfg(test)]
+crate::fuzz_target!(parse_header, |data: &[u8]| {
+    let _ = parse_header(data);
+});
+
+#[test_case]
+fn test_target_named_after_function() {
+    assert_eq!(parse_header(b"x"), Some(b'x'));
+    assert!(find("parse_header").is_some());
+}
@@ -0,0 +1,110 @@
+//! Shrinking crashing inputs down to minimal reproducers.
+//!
+//! `minimise` runs the harness on smaller and simpler variants of a crashing input, through the
+//! same `runner::execute` the workers use, and keeps a variant only if it still crashes with the
+//! same signature.  Passes of chunk removal and byte simplification repeat until neither changes
+//! anything, or the execution budget runs out.
+
+use alloc::vec::Vec;
+
+use crate::fuzz::harness::Harness;
+use crate::fuzz::runner::execute;
+
+/// The most times the harness is run while minimising one input.
+const MAX_EXECS: usize = 1 << 14;
+
+struct Minimiser<'a> {
+    harness: &'a Harness,
+    signature: u64,
+    execs: usize,
+}
+
+impl Minimiser<'_> {
+    fn out_of_budget(&self) -> bool {
+        self.execs >= MAX_EXECS
+    }
+
+    /// Whether `input` still crashes the same way.
+    fn reproduces(&mut self, input: &[u8]) -> bool {
+        if self.out_of_budget() {
+            return false;
+        }
+        self.execs += 1;
+        match execute(self.harness, input) {
+            Err(crash) => crash.signature() == self.signature,
+            Ok(_) => false,
+        }
+    }
+
+    /// Removes chunks of `input`, from half its length down to single bytes.
+    fn remove_chunks(&mut self, input: &mut Vec<u8>) {
+        let mut chunk = (input.len() / 2).max(1);
+        loop {
+            let mut at = 0;
+            while at < input.len() && !self.out_of_budget() {
+                let end = (at + chunk).min(input.len());
+                let mut candidate = Vec::with_capacity(input.len() - (end - at));
+                candidate.extend_from_slice(&input[..at]);
+                candidate.extend_from_slice(&input[end..]);
+                if self.reproduces(&candidate) {
+                    *input = candidate;
+                } else {
+                    at += chunk;
+                }
+            }
+            if chunk == 1 {
+                break;
+            }
+            chunk /= 2;
+        }
+    }
+
+    /// Replaces each byte with the smallest of zero and the values made by shifting it right that
+    /// still crashes.
+    fn simplify_bytes(&mut self, input: &mut [u8]) {
+        for at in 0..input.len() {
+            let original = input[at];
+            // in increasing order, so the first that works is the simplest
+            let candidates = core::iter::once(0).chain((1..8).rev().map(|shift| original >> shift));
+            let mut tried = None;
+            for value in candidates {
+                if value == original || tried == Some(value) {
+                    continue;
+                }
+                if self.out_of_budget() {
+                    return;
+                }
+                tried = Some(value);
+                input[at] = value;
+                if self.reproduces(input) {
+                    break;
+                }
+                input[at] = original;
+            }
+        }
+    }
+}
+
+/// Shrinks `input`, which crashes `harness` with `signature`, to a smaller input crashing the same
+/// way.  Returns `input` unchanged if it doesn't reproduce.
+pub fn minimise(harness: &Harness, input: &[u8], signature: u64) -> Vec<u8> {
+    let mut minimiser = Minimiser {
+        harness,
+        signature,
+        execs: 0,
+    };
+    let mut input = input.to_vec();
+    // crashes that depend on state left behind by earlier inputs can't be minimised
+    if !minimiser.reproduces(&input) {
+        return input;
+    }
+
+    loop {
+        let before = input.clone();
+        minimiser.remove_chunks(&mut input);
+        minimiser.simplify_bytes(&mut input);
+        if input == before || minimiser.out_of_budget() {
+            return input;
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Havoc-style mutation of fuzz inputs.
+//!
+//! Each call to `Mutator::mutate` stacks a random number of small mutations (bit flips,
+//! arithmetic, interesting values, block operations, dictionary tokens) on top of each other, the
+//! way AFL's havoc stage does.  Every decision comes from the mutator's `Rng`, so the same seed
+//! and the same inputs always give the same outputs.
+//!
+//! Given the comparisons the seed made (see `cmplog`), it also does input-to-state replacement:
+//! an operand of a failed comparison found in the input is overwritten with the other operand.
+
+use alloc::vec::Vec;
+
+use crate::fuzz::cmplog::{Operands, MAX_OPERAND_LEN};
+use crate::rng::Rng;
+
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: [i16; 10] = [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: [i32; 8] = [
+    -2147483648,
+    -100663046,
+    -32769,
+    32768,
+    65535,
+    65536,
+    100663045,
+    2147483647,
+];
+
+/// The largest amount added to or subtracted from a value by the arithmetic mutations.
+const ARITH_MAX: usize = 35;
+/// At most `1 << MAX_STACK_POWER` mutations are stacked on one input.
+const MAX_STACK_POWER: usize = 4;
+/// The largest block inserted, duplicated or deleted in one go.
+const MAX_BLOCK_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    FlipBit,
+    FlipByte,
+    Arith8,
+    Arith16,
+    Arith32,
+    Interesting8,
+    Interesting16,
+    Interesting32,
+    RandomByte,
+    DeleteBlock,
+    DuplicateBlock,
+    CopyBlock,
+    InsertRandomBlock,
+    Splice,
+    InsertToken,
+    OverwriteToken,
+    InputToState,
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; 17] = [
+        Mutation::FlipBit,
+        Mutation::FlipByte,
+        Mutation::Arith8,
+        Mutation::Arith16,
+        Mutation::Arith32,
+        Mutation::Interesting8,
+        Mutation::Interesting16,
+        Mutation::Interesting32,
+        Mutation::RandomByte,
+        Mutation::DeleteBlock,
+        Mutation::DuplicateBlock,
+        Mutation::CopyBlock,
+        Mutation::InsertRandomBlock,
+        Mutation::Splice,
+        Mutation::InsertToken,
+        Mutation::OverwriteToken,
+        Mutation::InputToState,
+    ];
+}
+
+pub struct Mutator {
+    rng: Rng,
+    max_len: usize,
+    dictionary: Vec<Vec<u8>>,
+    /// Comparisons made by the input being mutated.
+    comparisons: Vec<Operands>,
+}
+
+impl Mutator {
+    pub fn new(seed: u64, max_len: usize) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            max_len,
+            dictionary: Vec::new(),
+            comparisons: Vec::new(),
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Adds a token for the dictionary mutations to insert.  Empty and duplicate tokens are
+    /// ignored.
+    pub fn add_token(&mut self, token: &[u8]) {
+        if !token.is_empty() && !self.dictionary.iter().any(|t| t == token) {
+            self.dictionary.push(token.to_vec());
+        }
+    }
+
+    pub fn dictionary(&self) -> &[Vec<u8>] {
+        &self.dictionary
+    }
+
+    /// Sets the comparisons the input about to be mutated made, for input-to-state replacement.
+    pub fn set_comparisons(&mut self, comparisons: Vec<Operands>) {
+        self.comparisons = comparisons;
+    }
+
+    /// Applies a stack of random mutations to `input`, leaving it at most `max_len` bytes long.
+    pub fn mutate(&mut self, input: &mut Vec<u8>) {
+        self.mutate_with(input, None)
+    }
+
+    /// Like `mutate`, but may also splice in part of `other`, usually another corpus entry.
+    pub fn mutate_with(&mut self, input: &mut Vec<u8>, other: Option<&[u8]>) {
+        let stacked: usize = 1 << (1 + self.rng.below(MAX_STACK_POWER));
+        let mut applied = 0;
+        // some mutations can't apply to every input (e.g. 32-bit ones to a 2 byte input), so
+        // give up eventually rather than spinning
+        for _ in 0..stacked * 4 {
+            if applied == stacked {
+                break;
+            }
+            let mutation = Mutation::ALL[self.rng.below(Mutation::ALL.len())];
+            if self.apply(mutation, input, other) {
+                applied += 1;
+            }
+        }
+        input.truncate(self.max_len);
+    }
+
+    /// Applies a single mutation.  Returns `false` if it couldn't be applied to this input.
+    pub fn apply(&mut self, mutation: Mutation, input: &mut Vec<u8>, other: Option<&[u8]>) -> bool {
+        match mutation {
+            Mutation::FlipBit => self.flip_bit(input),
+            Mutation::FlipByte => self.flip_byte(input),
+            Mutation::Arith8 => self.arith(input, 1),
+            Mutation::Arith16 => self.arith(input, 2),
+            Mutation::Arith32 => self.arith(input, 4),
+            Mutation::Interesting8 => {
+                let value = INTERESTING_8[self.rng.below(INTERESTING_8.len())];
+                self.put_interesting(input, &value.to_le_bytes())
+            }
+            Mutation::Interesting16 => {
+                let value = INTERESTING_16[self.rng.below(INTERESTING_16.len())];
+                let bytes = self.endian(value.to_le_bytes(), value.to_be_bytes());
+                self.put_interesting(input, &bytes)
+            }
+            Mutation::Interesting32 => {
+                let value = INTERESTING_32[self.rng.below(INTERESTING_32.len())];
+                let bytes = self.endian(value.to_le_bytes(), value.to_be_bytes());
+                self.put_interesting(input, &bytes)
+            }
+            Mutation::RandomByte => self.random_byte(input),
+            Mutation::DeleteBlock => self.delete_block(input),
+            Mutation::DuplicateBlock => self.duplicate_block(input),
+            Mutation::CopyBlock => self.copy_block(input),
+            Mutation::InsertRandomBlock => self.insert_random_block(input),
+            Mutation::Splice => match other {
+                Some(other) => self.splice(input, other),
+                None => false,
+            },
+            Mutation::InsertToken => self.insert_token(input),
+            Mutation::OverwriteToken => self.overwrite_token(input),
+            Mutation::InputToState => self.input_to_state(input),
+        }
+    }
+
+    fn endian<const N: usize>(&mut self, little: [u8; N], big: [u8; N]) -> [u8; N] {
+        if self.rng.below(2) == 0 {
+            little
+        } else {
+            big
+        }
+    }
+
+    /// How many bytes can be inserted without going over `max_len`.
+    fn room(&self, input: &[u8]) -> usize {
+        self.max_len.saturating_sub(input.len())
+    }
+
+    /// Picks a block length in `1..=max`.  `max` must not be 0.
+    fn block_len(&mut self, max: usize) -> usize {
+        1 + self.rng.below(max.min(MAX_BLOCK_LEN))
+    }
+
+    fn flip_bit(&mut self, input: &mut [u8]) -> bool {
+        if input.is_empty() {
+            return false;
+        }
+        let bit = self.rng.below(input.len() * 8);
+        input[bit / 8] ^= 1 << (bit % 8);
+        true
+    }
+
+    fn flip_byte(&mut self, input: &mut [u8]) -> bool {
+        if input.is_empty() {
+            return false;
+        }
+        let at = self.rng.below(input.len());
+        input[at] ^= 0xFF;
+        true
+    }
+
+    fn random_byte(&mut self, input: &mut [u8]) -> bool {
+        if input.is_empty() {
+            return false;
+        }
+        let at = self.rng.below(input.len());
+        // xor with 1..=255 so the byte always changes
+        input[at] ^= 1 + self.rng.below(255) as u8;
+        true
+    }
+
+    /// Adds or subtracts a small amount to a `width` byte integer of either endianness.
+    fn arith(&mut self, input: &mut [u8], width: usize) -> bool {
+        if input.len() < width {
+            return false;
+        }
+        let at = self.rng.below(input.len() - width + 1);
+        let big_endian = self.rng.below(2) == 0;
+        let delta = 1 + self.rng.below(ARITH_MAX) as u32;
+        let subtract = self.rng.below(2) == 0;
+
+        let field = &mut input[at..at + width];
+        let mut value = 0u32;
+        for (i, byte) in field.iter().enumerate() {
+            let shift = (if big_endian { width - 1 - i } else { i }) * 8;
+            value |= (*byte as u32) << shift;
+        }
+        value = if subtract {
+            value.wrapping_sub(delta)
+        } else {
+            value.wrapping_add(delta)
+        };
+        for (i, byte) in field.iter_mut().enumerate() {
+            let shift = (if big_endian { width - 1 - i } else { i }) * 8;
+            *byte = (value >> shift) as u8;
+        }
+        true
+    }
+
+    /// Overwrites part of `input` with `value`, or inserts it if `input` is too short.
+    fn put_interesting(&mut self, input: &mut Vec<u8>, value: &[u8]) -> bool {
+        if input.len() >= value.len() && self.rng.below(4) != 0 {
+            let at = self.rng.below(input.len() - value.len() + 1);
+            input[at..at + value.len()].copy_from_slice(value);
+            true
+        } else {
+            self.insert(input, value)
+        }
+    }
+
+    fn insert(&mut self, input: &mut Vec<u8>, bytes: &[u8]) -> bool {
+        if bytes.is_empty() || self.room(input) < bytes.len() {
+            return false;
+        }
+        let at = self.rng.below(input.len() + 1);
+        input.splice(at..at, bytes.iter().copied());
+        true
+    }
+
+    fn delete_block(&mut self, input: &mut Vec<u8>) -> bool {
+        // never delete everything, since an empty input has little left to mutate
+        if input.len() < 2 {
+            return false;
+        }
+        let len = self.block_len(input.len() - 1);
+        let at = self.rng.below(input.len() - len + 1);
+        input.drain(at..at + len);
+        true
+    }
+
+    fn duplicate_block(&mut self, input: &mut Vec<u8>) -> bool {
+        if input.is_empty() || self.room(input) == 0 {
+            return false;
+        }
+        let len = self.block_len(input.len().min(self.room(input)));
+        let from = self.rng.below(input.len() - len + 1);
+        let to = self.rng.below(input.len() + 1);
+        let block: Vec<u8> = input[from..from + len].to_vec();
+        input.splice(to..to, block);
+        true
+    }
+
+    fn copy_block(&mut self, input: &mut [u8]) -> bool {
+        if input.len() < 2 {
+            return false;
+        }
+        let len = self.block_len(input.len() - 1);
+        let from = self.rng.below(input.len() - len + 1);
+        let to = self.rng.below(input.len() - len + 1);
+        input.copy_within(from..from + len, to);
+        from != to
+    }
+
+    fn insert_random_block(&mut self, input: &mut Vec<u8>) -> bool {
+        if self.room(input) == 0 {
+            return false;
+        }
+        let len = self.block_len(self.room(input));
+        let at = self.rng.below(input.len() + 1);
+        // either one repeated byte, which is good at hitting length checks, or random bytes
+        let repeated = if self.rng.below(2) == 0 {
+            Some(self.rng.next_u32() as u8)
+        } else {
+            None
+        };
+        let rng = &mut self.rng;
+        let block = (0..len).map(|_| repeated.unwrap_or_else(|| rng.next_u32() as u8));
+        input.splice(at..at, block);
+        true
+    }
+
+    /// Replaces the tail of `input` with the tail of `other`, starting from random points in each.
+    fn splice(&mut self, input: &mut Vec<u8>, other: &[u8]) -> bool {
+        if other.is_empty() {
+            return false;
+        }
+        let keep = self.rng.below(input.len() + 1);
+        let from = self.rng.below(other.len());
+        let take = (other.len() - from).min(self.max_len.saturating_sub(keep));
+        if take == 0 {
+            return false;
+        }
+        input.truncate(keep);
+        input.extend_from_slice(&other[from..from + take]);
+        true
+    }
+
+    fn pick_token(&mut self) -> Option<Vec<u8>> {
+        if self.dictionary.is_empty() {
+            return None;
+        }
+        Some(self.dictionary[self.rng.below(self.dictionary.len())].clone())
+    }
+
+    fn insert_token(&mut self, input: &mut Vec<u8>) -> bool {
+        match self.pick_token() {
+            Some(token) => self.insert(input, &token),
+            None => false,
+        }
+    }
+
+    fn overwrite_token(&mut self, input: &mut [u8]) -> bool {
+        let token = match self.pick_token() {
+            Some(token) if token.len() <= input.len() => token,
+            _ => return false,
+        };
+        let at = self.rng.below(input.len() - token.len() + 1);
+        input[at..at + token.len()].copy_from_slice(&token);
+        true
+    }
+
+    /// Finds one operand of a logged comparison in `input` and replaces it with the other.
+    fn input_to_state(&mut self, input: &mut [u8]) -> bool {
+        // most comparisons don't involve the input at all, so try a few
+        for _ in 0..8 {
+            if self.comparisons.is_empty() {
+                return false;
+            }
+            let operands = self.comparisons[self.rng.below(self.comparisons.len())];
+            let mut from = [0; MAX_OPERAND_LEN];
+            let mut to = [0; MAX_OPERAND_LEN];
+            let len = operands.a().len();
+            // a constant operand is in the code, so only the other one can be in the input
+            if operands.is_constant() || self.rng.below(2) == 0 {
+                from[..len].copy_from_slice(operands.b());
+                to[..len].copy_from_slice(operands.a());
+            } else {
+                from[..len].copy_from_slice(operands.a());
+                to[..len].copy_from_slice(operands.b());
+            }
+            if operands.is_integer() && self.rng.below(2) == 0 {
+                from[..len].reverse();
+                to[..len].reverse();
+            }
+
+            let (from, to) = (&from[..len], &to[..len]);
+            if len == 0 || input.len() < len {
+                continue;
+            }
+            let found: Vec<usize> = (0..=input.len() - len)
+                .filter(|&at| &input[at..at + len] == from)
+                .collect();
+            if found.is_empty() {
+                continue;
+            }
+            let at = found[self.rng.below(found.len())];
+            input[at..at + len].copy_from_slice(to);
+            return true;
+        }
+        false
+    }
+}
+
+#[test_case]
+fn test_mutations_are_reproducible() {
+    let seed_input: Vec<u8> = (0..64).collect();
+    let mut a = Mutator::new(42, 256);
+    let mut b = Mutator::new(42, 256);
+    for _ in 0..100 {
+        let mut x = seed_input.clone();
+        let mut y = seed_input.clone();
+        a.mutate(&mut x);
+        b.mutate(&mut y);
+        assert_eq!(x, y);
+    }
+}
+
+#[test_case]
+fn test_mutations_respect_max_len() {
+    let mut mutator = Mutator::new(7, 32);
+    mutator.add_token(b"a token longer than nothing");
+    let other: Vec<u8> = (0..100).collect();
+    let mut input = Vec::new();
+    for _ in 0..1000 {
+        mutator.mutate_with(&mut input, Some(&other));
+        assert!(input.len() <= 32);
+    }
+}
+
+#[test_case]
+fn test_token_insertion() {
+    let mut mutator = Mutator::new(1, 64);
+    mutator.add_token(b"MAGIC");
+    let mut input = alloc::vec![0u8; 8];
+    assert!(mutator.apply(Mutation::InsertToken, &mut input, None));
+    assert_eq!(input.len(), 13);
+    assert!(input.windows(5).any(|window| window == b"MAGIC"));
+}
+
+#[test_case]
+fn test_input_to_state() {
+    let mut mutator = Mutator::new(3, 64);
+    mutator.set_comparisons(alloc::vec![Operands::from_bytes(b"MAGIC", b"abcde", true)]);
+    let mut input = b"xxabcdexx".to_vec();
+    assert!(mutator.apply(Mutation::InputToState, &mut input, None));
+    assert_eq!(&input, b"xxMAGICxx");
+
+    let mut input = b"nothing to see".to_vec();
+    assert!(!mutator.apply(Mutation::InputToState, &mut input, None));
+}
@@ -0,0 +1,135 @@
+//! Fuzzing static Linux executables:  the `linux_program` fuzz target runs the program in the
+//! `opt/barefuzz/program` fw_cfg file on each input, which it reads as its standard input.
+//!
+//! Every input gets a fresh copy of the program, loaded by `task::loader` and run as a task of its
+//! own in user mode while the worker waits for it.  A program that faults, or sends itself a signal
+//! (as `abort` does), crashes the target, with the program's instruction pointer as the faulting
+//! PC.  One still running after `PROGRAM_TIMEOUT_MS` is killed, which isn't a crash.
+//!
+//! The `linux_guest` target runs the same program as a guest instead, which drives the fuzzing
+//! loop with hypercalls as `guest` describes:  each worker starts a copy on its first input and
+//! offers it every input after that, waiting until the program ends the iteration or asks for the
+//! next input.  A copy that's ended, or was killed for taking too long with an input, is replaced
+//! by a fresh one on the next input.
+//!
+//! Programs aren't instrumented, so their coverage comes from `breakpoints`, which patches each
+//! copy before it runs.  The worker lends the program its coverage map, which collects that and
+//! the coverage of the kernel code the program makes run.  A breakpoint is only hit once in a copy,
+//! so a guest's blocks are counted for the first input that reaches them after it started.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::iter;
+
+use conquer_once::spin::OnceCell;
+
+use crate::fuzz::crash::{recover_from_program, CrashKind};
+use crate::fuzz::{breakpoints, coverage, guest};
+use crate::fw_cfg;
+use crate::linux::{Exit, ExitCell};
+use crate::task::{executor, loader, TaskId};
+use crate::time;
+
+/// How long a program gets to run on one input.
+const PROGRAM_TIMEOUT_MS: u64 = 1000;
+
+/// The executable and its arguments.
+static PROGRAM: OnceCell<(Vec<u8>, Vec<String>)> = OnceCell::uninit();
+
+/// Reads the program from fw_cfg, once.  The `linux_program` target's init hook.
+fn read_program() {
+    PROGRAM.init_once(|| {
+        let image = fw_cfg::read_file("opt/barefuzz/program")
+            .expect("linux_program needs a program in opt/barefuzz/program");
+        breakpoints::init(&image);
+        let args = fw_cfg::read_string("opt/barefuzz/program-args").unwrap_or_default();
+        let args = iter::once("program")
+            .chain(args.split_whitespace())
+            .map(String::from)
+            .collect();
+        (image, args)
+    });
+}
+
+/// Loads a fresh copy of the program and starts it with `stdin` as its standard input, lending it
+/// the calling worker's coverage map.  With `as_guest`, it's the worker's guest as well.
+fn start(stdin: Vec<u8>, as_guest: bool) -> (TaskId, ExitCell) {
+    read_program();
+    let (image, args) = PROGRAM.get().unwrap();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let program = loader::load(image, &args)
+        .unwrap_or_else(|error| panic!("couldn't load opt/barefuzz/program: {}", error));
+    breakpoints::arm(program.address_space());
+    let process = program.process(stdin, false);
+    let exit = process.exit_cell();
+    let mut executor = executor::INSTANCE.get().unwrap().lock();
+    let task = program.spawn_as(&mut executor, process);
+    // the executor is locked, so the program can't start before it has the map or its guest
+    coverage::lend(executor::current_task().unwrap(), task);
+    if as_guest {
+        guest::attach_process(task, exit.clone());
+    }
+    (task, exit)
+}
+
+/// Waits until the program running as `task` has ended or `done` says so, and kills it if that
+/// takes more than `PROGRAM_TIMEOUT_MS`.
+fn wait(task: TaskId, exit: &ExitCell, done: impl Fn() -> bool) {
+    // the program needs the timer to get a turn, even while replaying
+    let preemption = executor::preemption();
+    executor::set_preemption(true);
+    let start = time::ticks();
+    while exit.get().is_none()
+        && !done()
+        && time::ticks_to_ms(time::ticks() - start) < PROGRAM_TIMEOUT_MS
+    {
+        executor::idle();
+    }
+    executor::set_preemption(preemption);
+    if exit.get().is_none() && !done() {
+        executor::INSTANCE.get().unwrap().lock().kill(task);
+    }
+}
+
+/// Crashes the target if the program that ended with `exit` faulted or signalled itself.
+fn check_exit(exit: &ExitCell, message: &str) {
+    match exit.get() {
+        Some(&Exit::Faulted { kind, frame }) => recover_from_program(kind, frame, message),
+        Some(&Exit::Signalled { signal, frame }) => {
+            let message = format!("program killed itself with signal {}", signal);
+            recover_from_program(CrashKind::Guest, frame, &message)
+        }
+        _ => {}
+    }
+}
+
+/// Runs the program with `input` as its standard input, and waits for it to end.
+pub fn run(input: &[u8]) {
+    let (task, exit) = start(input.to_vec(), false);
+    wait(task, &exit, || false);
+    coverage::detach(task);
+    check_exit(&exit, "");
+}
+
+crate::fuzz_target!(linux_program, |data: &[u8]| run(data), init = read_program);
+
+/// Offers `input` to the calling worker's copy of the program, started first if it isn't running,
+/// and waits for it to be done with it.
+pub fn run_guest(input: &[u8]) {
+    let (task, exit) = guest::process().unwrap_or_else(|| start(Vec::new(), true));
+    guest::offer(input);
+    wait(task, &exit, || !guest::busy());
+    guest::withdraw();
+    if exit.get().is_some() {
+        let message = guest::detach_process().unwrap_or_default();
+        coverage::detach(task);
+        check_exit(&exit, &message);
+    }
+}
+
+crate::fuzz_target!(
+    linux_guest,
+    |data: &[u8]| run_guest(data),
+    init = read_program
+);
@@ -0,0 +1,140 @@
+//! Replay mode:  run the selected harness once on a single input, and exit QEMU with the outcome.
+//!
+//! Replay is picked at boot if the kernel was built with the `replay-embedded` feature, if QEMU
+//! was given an `opt/barefuzz/input` fw_cfg file, or if the `opt/barefuzz/mode` fw_cfg file says
+//! `replay`.  The input is, in that order of preference:
+//!
+//! - the file named by `BAREFUZZ_REPLAY_INPUT` at build time, with `replay-embedded`;
+//! - the `opt/barefuzz/input` fw_cfg file, which stands in for a ramdisk since the bootloader
+//!   doesn't load modules;
+//! - the first seed the host tool sends over COM2, e.g. `barefuzz-host unix:... seed crash`.
+//!
+//! The input runs in a single task with preemption turned off, with the seed from
+//! `opt/barefuzz/seed` or else `REPLAY_SEED`, so that runs are repeatable.  A crash prints a full
+//! report, and the QEMU exit code says what happened (see `QemuExitCode`).
+
+use alloc::vec::Vec;
+
+use barefuzz_protocol::{Decoder, Message};
+use x86_64::VirtAddr;
+
+use crate::fuzz::crash::{CrashInfo, CrashKind};
+use crate::fuzz::harness;
+use crate::fuzz::runner;
+use crate::memory::diagnostics;
+use crate::task::executor::{self, Executor};
+use crate::{eprintln, exit_qemu, fw_cfg, hlt_loop, host, serial, vga_buffer, QemuExitCode};
+
+/// The seed used unless another is given, so that replays don't depend on the time.
+pub const REPLAY_SEED: u64 = 0;
+
+#[cfg(feature = "replay-embedded")]
+const EMBEDDED_INPUT: Option<&[u8]> = Some(include_bytes!(env!("BAREFUZZ_REPLAY_INPUT")));
+#[cfg(not(feature = "replay-embedded"))]
+const EMBEDDED_INPUT: Option<&[u8]> = None;
+
+/// Whether the kernel should replay an input rather than fuzz.
+pub fn requested() -> bool {
+    EMBEDDED_INPUT.is_some()
+        || fw_cfg::read_string("opt/barefuzz/mode").as_deref() == Some("replay")
+        || fw_cfg::read_file("opt/barefuzz/input").is_some()
+}
+
+/// Spawns the task replaying the input on the selected harness.
+pub fn start(executor: &mut Executor) {
+    let harness = harness::select();
+    runner::configure(harness, runner::requested_seed().unwrap_or(REPLAY_SEED));
+    executor.spawn(replay);
+}
+
+fn load_input() -> Vec<u8> {
+    if let Some(input) = EMBEDDED_INPUT {
+        eprintln!("Replaying the input embedded at build time");
+        return input.to_vec();
+    }
+    if let Some(input) = fw_cfg::read_file("opt/barefuzz/input") {
+        eprintln!("Replaying the input from fw_cfg");
+        return input;
+    }
+
+    eprintln!("Waiting for the host to send an input on COM2");
+    let mut decoder = Decoder::new();
+    loop {
+        match host::receive(&mut decoder) {
+            Message::AddSeed(input) => return input,
+            Message::Ping => host::send(&Message::Pong),
+            _ => host::send(&Message::Error(
+                "only seeds are taken in replay mode".into(),
+            )),
+        }
+    }
+}
+
+fn exit_code(kind: &CrashKind) -> QemuExitCode {
+    match kind {
+        CrashKind::Panic => QemuExitCode::Panic,
+        CrashKind::DivideError => QemuExitCode::DivideError,
+        CrashKind::InvalidOpcode => QemuExitCode::InvalidOpcode,
+        CrashKind::GeneralProtection { .. } => QemuExitCode::GeneralProtection,
+        CrashKind::StackSegment { .. } => QemuExitCode::StackSegment,
+        CrashKind::SegmentNotPresent { .. } => QemuExitCode::SegmentNotPresent,
+        CrashKind::PageFault { .. } => QemuExitCode::PageFault,
+        CrashKind::Guest => QemuExitCode::GuestCrash,
+    }
+}
+
+fn print_report(crash: &CrashInfo) {
+    eprintln!(
+        "CRASH {:016x}: {:?} at {:#x}",
+        crash.signature(),
+        crash.kind,
+        crash.pc
+    );
+    if !crash.message().is_empty() {
+        eprintln!("  {}", crash.message());
+    }
+    if let Some(frame) = crash.frame {
+        eprintln!("  {:#X?}", frame);
+    }
+    if let CrashKind::PageFault { address, .. } = crash.kind {
+        eprintln!("  {}", diagnostics::walk(VirtAddr::new_truncate(address)));
+    }
+    eprintln!("  backtrace:");
+    for (depth, address) in crash.frames().iter().enumerate() {
+        eprintln!("    #{:<2} {:#x}", depth, address);
+    }
+}
+
+fn finish(code: QemuExitCode) -> ! {
+    serial::flush();
+    vga_buffer::flush();
+    exit_qemu(code);
+    hlt_loop();
+}
+
+fn replay() {
+    executor::set_preemption(false);
+    let harness = runner::harness().expect("replay task spawned without a harness");
+
+    let input = load_input();
+    eprintln!(
+        "Running {} on {} bytes, seed {}",
+        harness.name,
+        input.len(),
+        runner::seed()
+    );
+    if let Some(init) = harness.init {
+        init();
+    }
+
+    match runner::execute(harness, &input) {
+        Ok(ticks) => {
+            eprintln!("No crash ({} TSC ticks)", ticks);
+            finish(QemuExitCode::NoCrash);
+        }
+        Err(crash) => {
+            print_report(&crash);
+            finish(exit_code(&crash.kind));
+        }
+    }
+}
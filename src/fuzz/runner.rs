@@ -0,0 +1,225 @@
+//! The fuzzing loop run by each worker task.
+//!
+//! Every iteration picks a seed from the shared corpus, mutates it, runs the harness on it with a
+//! fresh coverage map, adds it to the corpus if it reached anything new, and reports progress.
+//! Inputs that crash the harness are filed into crash buckets instead, and minimised if they
+//! start a new one.  Each corpus entry keeps the comparisons it made, for input-to-state
+//! mutations of it later.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use conquer_once::spin::{Lazy, OnceCell};
+use crossbeam_queue::SegQueue;
+
+use crate::concurrency::rwlock::RwLock;
+use crate::fuzz::breakpoints;
+use crate::fuzz::cmplog;
+use crate::fuzz::corpus::CORPUS;
+use crate::fuzz::coverage;
+use crate::fuzz::crash::{self, CrashInfo};
+use crate::fuzz::dictionary;
+use crate::fuzz::harness::Harness;
+use crate::fuzz::minimise::minimise;
+use crate::fuzz::mutator::Mutator;
+use crate::fuzz::stats;
+use crate::task::executor::{current_task, Executor};
+use crate::time::ticks;
+use crate::{fw_cfg, println};
+
+/// Inputs are never mutated to be longer than this.
+pub const MAX_INPUT_LEN: usize = 4096;
+
+/// How many distinct comparisons are kept with each corpus entry.
+const MAX_COMPARISONS: usize = 128;
+
+static HARNESS: OnceCell<&'static Harness> = OnceCell::uninit();
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Inputs from the host waiting for a worker to run them.
+static PENDING_SEEDS: Lazy<SegQueue<Vec<u8>>> = Lazy::new(SegQueue::new);
+/// Dictionary tokens from the host, which every worker copies into its mutator.
+static TOKENS: Lazy<RwLock<Vec<Vec<u8>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+static TOKEN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Total inputs run by all workers.
+pub static EXECS: AtomicU64 = AtomicU64::new(0);
+
+/// The harness being fuzzed, once `start` has been called.
+pub fn harness() -> Option<&'static Harness> {
+    HARNESS.get().copied()
+}
+
+/// The seed for this run, which harnesses wanting randomness should use so that runs can be
+/// repeated.
+pub fn seed() -> u64 {
+    SEED.load(Ordering::SeqCst)
+}
+
+/// The seed given in the `opt/barefuzz/seed` fw_cfg file, if there is one.
+pub fn requested_seed() -> Option<u64> {
+    fw_cfg::read_string("opt/barefuzz/seed").and_then(|seed| seed.parse().ok())
+}
+
+/// Sets the harness and seed, without spawning any workers.  Can only be called once.
+pub fn configure(harness: &'static Harness, seed: u64) {
+    HARNESS.init_once(|| harness);
+    SEED.store(seed, Ordering::SeqCst);
+}
+
+/// Loads the dictionaries and spawns `workers` tasks fuzzing `harness`.
+///
+/// The seed for the mutators comes from the `opt/barefuzz/seed` fw_cfg file if there is one, so
+/// that a run can be repeated.
+pub fn start(executor: &mut Executor, harness: &'static Harness, workers: usize) {
+    let seed = requested_seed().unwrap_or_else(ticks);
+    configure(harness, seed);
+    println!(
+        "Fuzzing {} with {} workers, seed {}",
+        harness.name, workers, seed
+    );
+    dictionary::init();
+
+    for _ in 0..workers {
+        executor.spawn(worker);
+    }
+}
+
+/// Queues `input` to be run by the next free worker, and added to the corpus unless an entry
+/// already has exactly the same coverage.
+pub fn add_seed(input: Vec<u8>) {
+    PENDING_SEEDS.push(input);
+}
+
+/// Adds a token to every worker's dictionary.
+pub fn add_token(token: Vec<u8>) {
+    let mut tokens = TOKENS.write();
+    tokens.push(token);
+    TOKEN_COUNT.store(tokens.len(), Ordering::SeqCst);
+}
+
+/// Copies tokens added since the last call into `mutator`.
+fn sync_tokens(mutator: &mut Mutator, seen: &mut usize) {
+    if TOKEN_COUNT.load(Ordering::Relaxed) == *seen {
+        return;
+    }
+    let tokens = TOKENS.read();
+    for token in &tokens[*seen..] {
+        mutator.add_token(token);
+    }
+    *seen = tokens.len();
+}
+
+/// Runs `input` through `harness` with a clean coverage map and comparison log.  Returns how long
+/// it took in `time::ticks`, or what happened if it crashed.
+pub fn execute(harness: &Harness, input: &[u8]) -> Result<u64, CrashInfo> {
+    coverage::reset();
+    cmplog::reset();
+    let start = ticks();
+    let result = crash::run_protected(|| (harness.run)(input));
+    let elapsed = ticks() - start;
+    if let Some(teardown) = harness.teardown {
+        teardown();
+    }
+    result.map(|()| elapsed)
+}
+
+/// Takes a seed from the corpus and mutates it, possibly splicing in another entry.
+fn generate(mutator: &mut Mutator) -> Vec<u8> {
+    let corpus = CORPUS.read();
+    let mut input = match corpus.pick(mutator.rng()) {
+        Some(index) => {
+            let entry = corpus.get(index).unwrap();
+            mutator.set_comparisons(entry.comparisons.clone());
+            entry.input.clone()
+        }
+        // nothing to start from yet, so start from nothing
+        None => Vec::new(),
+    };
+    let other = corpus
+        .pick(mutator.rng())
+        .map(|index| corpus.get(index).unwrap().input.as_slice());
+    mutator.mutate_with(&mut input, other);
+    input
+}
+
+/// Adds `input` to the corpus if the coverage map shows it reached something new, or for seeds
+/// from the host, anything not already in the corpus.
+fn collect_feedback(input: &[u8], exec_time: u64, from_host: bool) -> Option<usize> {
+    let index = coverage::read(|map| {
+        let index = if from_host {
+            CORPUS.write().add(input, map, exec_time)
+        } else if CORPUS.read().has_new_coverage(map) {
+            CORPUS.write().add_if_interesting(input, map, exec_time)
+        } else {
+            None
+        };
+        if index.is_some() {
+            breakpoints::commit(map);
+        }
+        index
+    })
+    .flatten()?;
+
+    let comparisons = cmplog::take(MAX_COMPARISONS);
+    CORPUS.write().set_comparisons(index, comparisons);
+    stats::record_new_path();
+    Some(index)
+}
+
+fn worker() {
+    let harness = harness().expect("fuzzing workers spawned without a harness");
+    let task = current_task().expect("fuzzing worker isn't running as a task");
+    coverage::attach(task);
+    cmplog::attach(task);
+    if let Some(init) = harness.init {
+        init();
+    }
+
+    let mut mutator = Mutator::new(seed() ^ task.as_u64(), MAX_INPUT_LEN);
+    let mut tokens_seen = 0;
+    loop {
+        sync_tokens(&mut mutator, &mut tokens_seen);
+        let (input, from_host) = match PENDING_SEEDS.pop() {
+            Ok(input) => (input, true),
+            Err(_) => (generate(&mut mutator), false),
+        };
+        let exec_time = match execute(harness, &input) {
+            Ok(exec_time) => {
+                stats::record_exec_time(exec_time);
+                Some(exec_time)
+            }
+            Err(crash) => {
+                let (signature, new) = crash::record(&crash, &input);
+                if new {
+                    let reproducer = minimise(harness, &input, signature);
+                    println!(
+                        "[{:?}] minimised crash {:016x} from {} to {} bytes",
+                        task,
+                        signature,
+                        input.len(),
+                        reproducer.len()
+                    );
+                    crash::set_reproducer(signature, reproducer);
+                    crash::report(signature);
+                }
+                None
+            }
+        };
+
+        if let Some(index) =
+            exec_time.and_then(|exec_time| collect_feedback(&input, exec_time, from_host))
+        {
+            let corpus = CORPUS.read();
+            println!(
+                "[{:?}] new input #{} ({} bytes), {} edges",
+                task,
+                index,
+                input.len(),
+                corpus.edges_covered()
+            );
+        }
+
+        EXECS.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,78 @@
+//! Snapshots of a guest program's state, to rewind it to between inputs.
+//!
+//! A snapshot holds the registers at the point it was taken and copies of the memory regions
+//! the program can write to.  Restoring it copies the memory back and hands back the registers,
+//! so the program carries on from the snapshot as if nothing had run since.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::slice;
+
+use crate::interrupts::{InterruptFrame, StandardContext};
+
+/// Everything needed to resume code stopped at a hypercall.
+///
+/// `rbp` is kept separately, since the interrupt trampolines save it themselves rather than in
+/// the `StandardContext`.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub frame: InterruptFrame,
+    pub ctx: StandardContext,
+    pub rbp: u64,
+}
+
+/// Saved registers and memory.
+pub struct Snapshot {
+    registers: Registers,
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Saves `registers` and the contents of `regions`, which must be mapped and readable.
+    pub fn take(registers: Registers, regions: &[Range<u64>]) -> Self {
+        let regions = regions
+            .iter()
+            .filter(|region| !region.is_empty())
+            .map(|region| {
+                let len = (region.end - region.start) as usize;
+                let bytes = unsafe { slice::from_raw_parts(region.start as *const u8, len) };
+                (region.start, bytes.to_vec())
+            })
+            .collect();
+        Self { registers, regions }
+    }
+
+    /// Puts the saved memory back and returns the saved registers.
+    ///
+    /// Mustn't be called while running on memory the snapshot covers, e.g. the guest's stack.
+    pub fn restore(&self) -> Registers {
+        for (start, bytes) in &self.regions {
+            unsafe {
+                slice::from_raw_parts_mut(*start as *mut u8, bytes.len()).copy_from_slice(bytes)
+            };
+        }
+        self.registers
+    }
+
+    /// How many bytes of memory are saved.
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+#[test_case]
+fn test_snapshot_restores_memory() {
+    let mut memory = alloc::vec![1u8, 2, 3, 4];
+    let start = memory.as_ptr() as u64;
+    let registers = Registers {
+        rbp: 42,
+        ..Default::default()
+    };
+
+    let snapshot = Snapshot::take(registers, &[start + 1..start + 3]);
+    assert_eq!(snapshot.size(), 2);
+    memory.copy_from_slice(&[9, 9, 9, 9]);
+    assert_eq!(snapshot.restore().rbp, 42);
+    assert_eq!(memory, [9, 2, 3, 9]);
+}
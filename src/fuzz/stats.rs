@@ -0,0 +1,174 @@
+//! Fuzzing statistics, shown in a panel at the top of the screen and logged on serial.
+//!
+//! The workers count executions, timeouts and new paths as they go, and `monitor` runs as a task
+//! redrawing the panel every `PANEL_INTERVAL_MS`.  Every `SERIAL_INTERVAL_SECS` it also prints a
+//! `stats:` line of `key=value` pairs to serial, for scripts watching the console.
+//!
+//! Times come from `time::ticks`:  the TSC, using the frequency `time` measured, or in
+//! `deterministic` mode the instructions retired, on a virtual clock.
+
+use alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::deterministic;
+use crate::fuzz::corpus::CORPUS;
+use crate::fuzz::crash::BUCKETS;
+use crate::fuzz::runner;
+use crate::serial_println;
+use crate::task::executor::yield_;
+use crate::time::{ticks, ticks_to_ms};
+use crate::vga_buffer::{Color, BUFFER_WIDTH, WRITER};
+
+/// Inputs running for longer than this are counted as timeouts.  They aren't stopped, since a
+/// harness can't be interrupted partway through an input.
+pub const TIMEOUT_MS: u64 = 100;
+
+/// How many rows at the top of the screen the panel takes.
+const PANEL_ROWS: usize = 3;
+
+const PANEL_INTERVAL_MS: u64 = 250;
+const SERIAL_INTERVAL_SECS: u64 = 5;
+
+static START: AtomicU64 = AtomicU64::new(0);
+static LAST_NEW_PATH: AtomicU64 = AtomicU64::new(0);
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the clock.  Must be called after `time::init` and before any inputs run.
+pub fn init() {
+    let now = ticks();
+    START.store(now, Ordering::SeqCst);
+    LAST_NEW_PATH.store(now, Ordering::SeqCst);
+}
+
+fn ms_since(tsc: u64) -> u64 {
+    ticks_to_ms(ticks().saturating_sub(tsc))
+}
+
+/// Notes that an input took `ticks` TSC ticks, counting it as a timeout if it took too long.
+pub fn record_exec_time(ticks: u64) {
+    if ticks_to_ms(ticks) > TIMEOUT_MS {
+        TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Notes that an input reached something new and was added to the corpus.
+pub fn record_new_path() {
+    LAST_NEW_PATH.store(ticks(), Ordering::Relaxed);
+}
+
+/// The statistics at one point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub execs: u64,
+    pub corpus_len: usize,
+    pub edges: usize,
+    pub crashes: usize,
+    pub timeouts: u64,
+    pub uptime_ms: u64,
+    pub since_new_path_ms: u64,
+}
+
+impl Snapshot {
+    pub fn take() -> Self {
+        let (corpus_len, edges) = {
+            let corpus = CORPUS.read();
+            (corpus.len(), corpus.edges_covered())
+        };
+        Snapshot {
+            execs: runner::EXECS.load(Ordering::Relaxed),
+            corpus_len,
+            edges,
+            crashes: BUCKETS.lock().len(),
+            timeouts: TIMEOUTS.load(Ordering::Relaxed),
+            uptime_ms: ms_since(START.load(Ordering::Relaxed)),
+            since_new_path_ms: ms_since(LAST_NEW_PATH.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Formats a duration in milliseconds as hours, minutes and seconds.
+struct Duration(u64);
+
+impl core::fmt::Display for Duration {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let secs = self.0 / 1000;
+        write!(f, "{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+fn draw_panel(stats: &Snapshot, execs_per_sec: u64) {
+    let name = runner::harness().map_or("-", |harness| harness.name);
+    let rows = [
+        (
+            format!(" barefuzz: {}    up {}", name, Duration(stats.uptime_ms)),
+            Color::White,
+            Color::Blue,
+        ),
+        (
+            format!(
+                " execs {:<12} execs/s {:<8} corpus {:<8} edges {}",
+                stats.execs, execs_per_sec, stats.corpus_len, stats.edges
+            ),
+            Color::LightGreen,
+            Color::Black,
+        ),
+        (
+            format!(
+                " crashes {:<10} timeouts {:<7} last new path {} ago",
+                stats.crashes,
+                stats.timeouts,
+                Duration(stats.since_new_path_ms)
+            ),
+            if stats.crashes > 0 {
+                Color::LightRed
+            } else {
+                Color::LightGray
+            },
+            Color::Black,
+        ),
+    ];
+
+    let mut writer = WRITER.lock();
+    writer.reserve_rows(PANEL_ROWS);
+    for (row, (text, foreground, background)) in rows.iter().enumerate() {
+        let padded = format!("{:<width$}", text, width = BUFFER_WIDTH);
+        writer.write_at(row, 0, &padded, *foreground, *background);
+    }
+}
+
+fn log_line(stats: &Snapshot, execs_per_sec: u64) {
+    serial_println!(
+        "stats: execs={} execs_per_sec={} corpus={} edges={} crashes={} timeouts={} \
+         since_new_path_ms={} uptime_ms={}",
+        stats.execs,
+        execs_per_sec,
+        stats.corpus_len,
+        stats.edges,
+        stats.crashes,
+        stats.timeouts,
+        stats.since_new_path_ms,
+        stats.uptime_ms
+    );
+}
+
+/// Keeps the panel and the serial log up to date, forever.  Meant to be spawned as a task.
+pub fn monitor() {
+    let mut last = Snapshot::take();
+    let mut last_logged_ms = last.uptime_ms;
+    loop {
+        while ms_since(START.load(Ordering::Relaxed)) - last.uptime_ms < PANEL_INTERVAL_MS {
+            yield_();
+        }
+
+        deterministic::report_overshoot();
+        let stats = Snapshot::take();
+        let elapsed_ms = (stats.uptime_ms - last.uptime_ms).max(1);
+        let execs_per_sec = (stats.execs - last.execs) * 1000 / elapsed_ms;
+        draw_panel(&stats, execs_per_sec);
+        if stats.uptime_ms - last_logged_ms >= SERIAL_INTERVAL_SECS * 1000 {
+            log_line(&stats, execs_per_sec);
+            last_logged_ms = stats.uptime_ms;
+        }
+        last = stats;
+    }
+}
@@ -0,0 +1,130 @@
+//! QEMU's firmware configuration device, used to hand files and options to the kernel at boot.
+//!
+//! Anything passed with e.g.
+//!
+//! ```text
+//! -fw_cfg name=opt/barefuzz/harness,string=parse_header
+//! -fw_cfg name=opt/barefuzz/seeds,file=seeds.bin
+//! ```
+//!
+//! can be read back by name, without rebuilding the kernel.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::concurrency::mutex::Mutex;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+const FILE_NAME_LEN: usize = 56;
+
+pub struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+pub static FW_CFG: Mutex<FwCfg> = Mutex::new(FwCfg::new());
+
+/// Where a file is, as listed in the device's file directory.
+#[derive(Debug, Clone, Copy)]
+struct FileEntry {
+    key: u16,
+    size: u32,
+}
+
+impl FwCfg {
+    const fn new() -> Self {
+        Self {
+            selector: Port::new(SELECTOR_PORT),
+            data: Port::new(DATA_PORT),
+        }
+    }
+
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    fn read_be_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    fn read_be_u16(&mut self) -> u16 {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+
+    pub fn is_present(&mut self) -> bool {
+        let mut signature = [0; 4];
+        self.select(SIGNATURE_KEY);
+        self.read_bytes(&mut signature);
+        &signature == b"QEMU"
+    }
+
+    fn find(&mut self, name: &str) -> Option<FileEntry> {
+        if !self.is_present() {
+            return None;
+        }
+
+        self.select(FILE_DIR_KEY);
+        let count = self.read_be_u32();
+        for _ in 0..count {
+            let size = self.read_be_u32();
+            let key = self.read_be_u16();
+            let _reserved = self.read_be_u16();
+            let mut entry_name = [0; FILE_NAME_LEN];
+            self.read_bytes(&mut entry_name);
+
+            let len = entry_name
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(FILE_NAME_LEN);
+            if &entry_name[..len] == name.as_bytes() {
+                return Some(FileEntry { key, size });
+            }
+        }
+        None
+    }
+
+    /// Reads the file called `name` into `buf`, returning the file's full size, which may be more
+    /// than was read.
+    pub fn read_into(&mut self, name: &str, buf: &mut [u8]) -> Option<usize> {
+        let entry = self.find(name)?;
+        let size = entry.size as usize;
+        self.select(entry.key);
+        self.read_bytes(&mut buf[..size.min(buf.len())]);
+        Some(size)
+    }
+}
+
+/// Reads the whole file called `name`.
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    // don't hold the lock while allocating;  the device only has one read position anyway, so
+    // find the size first and read into the buffer afterwards
+    let size = without_interrupts(|| FW_CFG.lock().find(name))?.size as usize;
+    let mut contents = vec![0; size];
+    without_interrupts(|| FW_CFG.lock().read_into(name, &mut contents))?;
+    Some(contents)
+}
+
+/// Reads the file called `name` as a string, without any trailing whitespace.
+pub fn read_string(name: &str) -> Option<String> {
+    let contents = read_file(name)?;
+    let string = String::from_utf8(contents).ok()?;
+    Some(String::from(string.trim_end()))
+}
@@ -4,6 +4,9 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Debug exceptions get their own stack, since single-stepping a `syscall` traps at the entry point
+/// while it's still on the program's stack.
+pub const DEBUG_IST_INDEX: u16 = 1;
 
 static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
@@ -15,34 +18,70 @@ static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
         
         stack_start + STACK_SIZE
     };
+    tss.interrupt_stack_table[DEBUG_IST_INDEX as usize] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+    };
+    // interrupts from user mode switch to this stack.  Handlers only take locks whose holders
+    // can't be preempted, leave freeing the tasks they end to the executor's reaper and switch
+    // tasks by rewriting the interrupt frame, so it's free again by the time the next one arrives
+    tss.privilege_stack_table[0] = {
+        const STACK_SIZE: usize = 4096 * 8;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+    };
     tss
 });
 
 static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
     let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
     (
         gdt,
         Selectors {
             code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
             tss_selector,
         },
     )
 });
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    /// Both user selectors have their requested privilege level set to 3.
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// The top of the stack interrupts from user mode switch to, which `syscall` entries switch to
+/// as well.
+pub fn kernel_stack_top() -> VirtAddr {
+    TSS.privilege_stack_table[0]
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
@@ -0,0 +1,125 @@
+//! The link to the host tool over the second serial port.
+//!
+//! COM1 carries the console's unframed text, so the host tool gets COM2 to itself and speaks the
+//! framed protocol from `barefuzz_protocol`.  `serve` runs as a task answering its requests, and
+//! `send` is used for messages the kernel sends on its own, like new crashes.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use barefuzz_protocol::{Decoder, Message, Stats};
+use conquer_once::raw::Lazy;
+use conquer_once::spin::Spin;
+
+use crate::concurrency::mutex::Mutex;
+use crate::fuzz::corpus::CORPUS;
+use crate::fuzz::crash::BUCKETS;
+use crate::fuzz::dictionary;
+use crate::fuzz::runner;
+use crate::println;
+use crate::task::executor::{self, yield_};
+use crate::task::loader;
+use crate::uart::SerialPort;
+
+pub static SERIAL2: Lazy<Mutex<SerialPort>, Spin> = Lazy::new(|| {
+    let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+    serial_port.init();
+    Mutex::new(serial_port)
+});
+
+/// Sends `message` to the host.
+pub fn send(message: &Message) {
+    let frame = message.encode();
+    let mut serial = SERIAL2.lock();
+    for byte in frame {
+        serial.send_raw(byte);
+    }
+}
+
+fn stats() -> Stats {
+    let corpus = CORPUS.read();
+    let buckets = BUCKETS.lock();
+    Stats {
+        execs: runner::EXECS.load(Ordering::Relaxed),
+        corpus_len: corpus.len() as u32,
+        edges: corpus.edges_covered() as u32,
+        crashes: buckets.len() as u32,
+        crash_hits: buckets.values().map(|bucket| bucket.hits).sum(),
+    }
+}
+
+fn handle(message: Message) {
+    match message {
+        Message::Ping => send(&Message::Pong),
+        Message::AddSeed(input) => runner::add_seed(input),
+        Message::AddToken(token) => runner::add_token(token),
+        Message::AddDictionary(text) => match dictionary::load(&text) {
+            Ok(_) => send(&Message::Done),
+            Err(error) => send(&Message::Error(format!("{}", error))),
+        },
+        Message::RunProgram { image, args } => {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            match loader::load(&image, &args) {
+                Ok(program) => {
+                    let task = program.spawn(&mut executor::INSTANCE.get().unwrap().lock());
+                    println!(
+                        "Started {} as task {:?}",
+                        args.first().unwrap_or(&"program"),
+                        task
+                    );
+                    send(&Message::Done);
+                }
+                Err(error) => send(&Message::Error(format!("{}", error))),
+            }
+        }
+        Message::GetStats => send(&Message::Stats(stats())),
+        Message::GetCrashes => {
+            let reports: Vec<_> = BUCKETS.lock().values().map(|b| b.to_report()).collect();
+            for report in reports {
+                send(&Message::Crash(report));
+            }
+            send(&Message::Done);
+        }
+        Message::GetCorpus { from } => {
+            let inputs: Vec<_> = CORPUS
+                .read()
+                .entries()
+                .iter()
+                .skip(from as usize)
+                .map(|entry| entry.input.clone())
+                .collect();
+            for (index, input) in (from..).zip(inputs) {
+                send(&Message::CorpusEntry { index, input });
+            }
+            send(&Message::Done);
+        }
+        message => send(&Message::Error(format!(
+            "{:#04x} isn't a request",
+            message.kind()
+        ))),
+    }
+}
+
+/// Waits for the next message from the host, telling it about any frames that didn't make it
+/// through intact.
+pub fn receive(decoder: &mut Decoder) -> Message {
+    loop {
+        let byte = SERIAL2.lock().try_receive();
+        match byte.and_then(|byte| decoder.push(byte)) {
+            Some(Ok(message)) => return message,
+            Some(Err(error)) => send(&Message::Error(format!("{}", error))),
+            None if byte.is_none() => yield_(),
+            None => {}
+        }
+    }
+}
+
+/// Answers requests from the host, forever.  Meant to be spawned as a task.
+pub fn serve() {
+    let mut decoder = Decoder::new();
+    loop {
+        handle(receive(&mut decoder));
+    }
+}
@@ -68,7 +68,9 @@ push_state!(),
 lea rdi, [rbp + 16] // interrupt frame
 mov rsi, rsp // standard context
 mov rdx, [rbp + 8] // error code
+inc qword ptr [rip + {depth}]
 call {callback}
+dec qword ptr [rip + {depth}]
 
 ",
 pop_state!(),
@@ -80,6 +82,7 @@ add rsp, 8 // skip error code
 iretq
             ",
             callback = sym $callback,
+            depth = sym $crate::interrupts::INTERRUPT_DEPTH,
                         size = const core::mem::size_of::<StandardContext>(),
             options(noreturn)
             )
@@ -105,7 +108,9 @@ push_state!(),
 "
 lea rdi, [rbp + 8] // interrupt frame
 mov rsi, rsp // standard context
+inc qword ptr [rip + {depth}]
 call {callback}
+dec qword ptr [rip + {depth}]
 ",
 pop_state!(),
 "
@@ -115,6 +120,7 @@ pop rbp
 iretq
             ",
             callback = sym $callback,
+            depth = sym $crate::interrupts::INTERRUPT_DEPTH,
             size = const core::mem::size_of::<StandardContext>(),
 
             options(noreturn)
@@ -1,25 +1,31 @@
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 
 use conquer_once::spin::Lazy;
 use x86_64::registers::rflags::RFlags;
 use x86_64::registers::segmentation::{Segment, CS, SS};
 use x86_64::structures::idt::InterruptDescriptorTable;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub use user_interrupts::attach_new_interrupt_handler;
 
 use crate::concurrency::mutex::Mutex;
+use crate::fuzz::breakpoints;
+use crate::fuzz::crash::{recover_from_fault, CrashKind};
 use crate::interrupts::user_interrupts::handle_user_interrupt;
 use crate::pic::ChainedPics;
+use crate::task::executor;
 use crate::{
-    eprintln, gdt, hlt_loop, println, serial, set_handler, set_handler_error_code, vga_buffer,
+    deterministic, eprintln, gdt, hlt_loop, linux, println, serial, set_handler,
+    set_handler_error_code, vga_buffer,
 };
 
 mod idt;
 
 #[macro_use]
 mod entry;
+pub mod syscall;
 mod user_interrupts;
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -42,6 +48,20 @@ impl InterruptIndex {
     }
 }
 
+/// How many interrupt handlers (and system calls) are running, counting ones that interrupted
+/// another.  The trampolines keep it around the call to the handler.
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// How deeply nested in interrupt handlers the caller is;  0 in a task's own context.
+pub fn interrupt_depth() -> usize {
+    INTERRUPT_DEPTH.load(Ordering::SeqCst)
+}
+
+/// Whether the caller runs in an interrupt handler rather than in a task's own context.
+pub fn in_interrupt() -> bool {
+    interrupt_depth() != 0
+}
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -86,12 +106,42 @@ impl Default for InterruptFrame {
     }
 }
 
+impl InterruptFrame {
+    /// Whether the interrupted code was running in user mode.
+    pub fn from_user_mode(&self) -> bool {
+        self.code_segment & 3 == 3
+    }
+
+    /// The interrupted code's `rbp`, which the trampolines in `entry` save just below the frame
+    /// (below the error code, if there is one) and restore on the way out.
+    ///
+    /// # Safety
+    ///
+    /// The frame must be the one a trampoline handed to its handler.
+    pub unsafe fn saved_rbp<'a>(&mut self, has_error_code: bool) -> &'a mut u64 {
+        let below = if has_error_code { 2 } else { 1 };
+        &mut *(self as *mut Self as *mut u64).sub(below)
+    }
+}
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    use crate::deterministic::{
+        debug_handler, performance_counter_handler, PERFORMANCE_COUNTER_VECTOR,
+    };
     use crate::task::executor::timer_interrupt_handler;
 
     let mut idt = InterruptDescriptorTable::new();
     unsafe {
-        set_handler!(idt.breakpoint, breakpoint_handler);
+        set_handler!(idt.divide_error, divide_error_handler);
+        set_handler!(idt.debug, debug_handler).set_stack_index(gdt::DEBUG_IST_INDEX);
+        // open to user mode for `breakpoints`
+        set_handler!(idt.breakpoint, breakpoint_handler).set_privilege_level(PrivilegeLevel::Ring3);
+        set_handler!(idt.invalid_opcode, invalid_opcode_handler);
+        set_handler_error_code!(idt.stack_segment_fault, stack_segment_fault_handler);
+        set_handler_error_code!(
+            idt.general_protection_fault,
+            general_protection_fault_handler
+        );
         set_handler_error_code!(idt.page_fault, page_fault_handler);
         set_handler_error_code!(idt.segment_not_present, segment_not_present_handler);
         set_handler_error_code!(idt.double_fault, double_fault_handler)
@@ -104,10 +154,15 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
             idt[InterruptIndex::Keyboard.as_usize()],
             keyboard_interrupt_handler
         );
+        set_handler!(
+            idt[PERFORMANCE_COUNTER_VECTOR as usize],
+            performance_counter_handler
+        );
         set_handler!(
             idt[user_interrupts::USER_INTERRUPT_VECTOR as usize],
             _handle_user_interrupt
-        );
+        )
+        .set_privilege_level(PrivilegeLevel::Ring3);
     }
     idt
 });
@@ -120,10 +175,45 @@ extern "C" fn _handle_user_interrupt(
     interrupt_frame: &mut InterruptFrame,
     ctx: &mut StandardContext,
 ) {
+    // a program asking for something that isn't there is its own problem
+    if !user_interrupts::is_attached(ctx.rax)
+        && end_faulting_user_task(
+            // what the CPU reports when a gate is out of the program's reach
+            CrashKind::GeneralProtection {
+                error_code: ((user_interrupts::USER_INTERRUPT_VECTOR as u64) << 3) | 2,
+            },
+            "unhandled user interrupt",
+            interrupt_frame,
+            ctx,
+            false,
+        )
+    {
+        return;
+    }
     handle_user_interrupt(ctx.rax, interrupt_frame, ctx);
 }
 
 extern "C" fn breakpoint_handler(interrupt_frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    if breakpoints::handle_breakpoint(interrupt_frame) {
+        return;
+    }
+    // a program's own `int3` ends it the way it did before the gate was open to user mode:  with
+    // the general protection fault the CPU raised on the `int3`
+    if interrupt_frame.from_user_mode() {
+        interrupt_frame.instruction_pointer -= 1;
+    }
+    if end_faulting_user_task(
+        CrashKind::GeneralProtection {
+            error_code: (3 << 3) | 2,
+        },
+        "breakpoint",
+        interrupt_frame,
+        ctx,
+        false,
+    ) {
+        return;
+    }
+
     println!(
         "EXCEPTION: BREAKPOINT\n{:#X?}, {:#X?}",
         interrupt_frame, ctx
@@ -132,8 +222,158 @@ extern "C" fn breakpoint_handler(interrupt_frame: &mut InterruptFrame, ctx: &mut
     vga_buffer::flush();
 }
 
-extern "C" fn page_fault_handler(interrupt_frame: &mut InterruptFrame, _ctx: &mut StandardContext) {
+/// Ends the running task if the fault happened in user mode, where it's the program's problem
+/// rather than the kernel's, and tells `linux` how its process ended.  Returns whether it did.
+fn end_faulting_user_task(
+    kind: CrashKind,
+    name: &str,
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+    has_error_code: bool,
+) -> bool {
+    if !interrupt_frame.from_user_mode() {
+        return false;
+    }
+
+    // whoever's waiting for the program reports the fault its own way
+    if !linux::record_fault(kind, interrupt_frame) {
+        eprintln!(
+            "{} in user task {:?} at {:#x}, ending it",
+            name,
+            executor::current_task(),
+            interrupt_frame.instruction_pointer
+        );
+    }
+    let rbp = unsafe { interrupt_frame.saved_rbp(has_error_code) };
+    executor::exit_current_task(interrupt_frame, ctx, rbp);
+    true
+}
+
+extern "C" fn divide_error_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) {
+    if recover_from_fault(CrashKind::DivideError, interrupt_frame, ctx) {
+        return;
+    }
+    if end_faulting_user_task(
+        CrashKind::DivideError,
+        "divide error",
+        interrupt_frame,
+        ctx,
+        false,
+    ) {
+        return;
+    }
+
+    eprintln!("EXCEPTION: DIVIDE ERROR\n{:#X?}", interrupt_frame);
+    serial::flush();
+    vga_buffer::flush();
+    hlt_loop();
+}
+
+extern "C" fn invalid_opcode_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) {
+    if recover_from_fault(CrashKind::InvalidOpcode, interrupt_frame, ctx) {
+        return;
+    }
+    if end_faulting_user_task(
+        CrashKind::InvalidOpcode,
+        "invalid opcode",
+        interrupt_frame,
+        ctx,
+        false,
+    ) {
+        return;
+    }
+
+    eprintln!("EXCEPTION: INVALID OPCODE\n{:#X?}", interrupt_frame);
+    serial::flush();
+    vga_buffer::flush();
+    hlt_loop();
+}
+
+extern "C" fn stack_segment_fault_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+    error_code: u64,
+) {
+    if recover_from_fault(CrashKind::StackSegment { error_code }, interrupt_frame, ctx) {
+        return;
+    }
+    if end_faulting_user_task(
+        CrashKind::StackSegment { error_code },
+        "stack segment fault",
+        interrupt_frame,
+        ctx,
+        true,
+    ) {
+        return;
+    }
+
+    eprintln!(
+        "EXCEPTION: STACK SEGMENT FAULT {:#x}\n{:#X?}",
+        error_code, interrupt_frame
+    );
+    serial::flush();
+    vga_buffer::flush();
+    hlt_loop();
+}
+
+extern "C" fn general_protection_fault_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+    error_code: u64,
+) {
+    if deterministic::emulate_rdtsc(interrupt_frame, ctx) {
+        return;
+    }
+    if recover_from_fault(
+        CrashKind::GeneralProtection { error_code },
+        interrupt_frame,
+        ctx,
+    ) {
+        return;
+    }
+    if end_faulting_user_task(
+        CrashKind::GeneralProtection { error_code },
+        "general protection fault",
+        interrupt_frame,
+        ctx,
+        true,
+    ) {
+        return;
+    }
+
+    eprintln!(
+        "EXCEPTION: GENERAL PROTECTION FAULT {:#x}\n{:#X?}",
+        error_code, interrupt_frame
+    );
+    serial::flush();
+    vga_buffer::flush();
+    hlt_loop();
+}
+
+extern "C" fn page_fault_handler(
+    interrupt_frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+    error_code: u64,
+) {
     use x86_64::registers::control::Cr2;
+    let addr = Cr2::read();
+    let kind = CrashKind::PageFault {
+        address: addr.as_u64(),
+        error_code,
+    };
+    if recover_from_fault(kind, interrupt_frame, ctx) {
+        return;
+    }
+    if end_faulting_user_task(kind, "page fault", interrupt_frame, ctx, true) {
+        return;
+    }
+
     unsafe {
         crate::allocator::ALLOCATOR.inner.force_unlock();
     }
@@ -141,9 +381,10 @@ extern "C" fn page_fault_handler(interrupt_frame: &mut InterruptFrame, _ctx: &mu
         r"EXCEPTION: PAGE FAULT
     Accessed Address: {:X?}
     {:#X?}
-    ",
-        Cr2::read(),
-        interrupt_frame
+    {}",
+        addr,
+        interrupt_frame,
+        crate::memory::diagnostics::walk(addr)
     );
     serial::flush();
     vga_buffer::flush();
@@ -152,10 +393,28 @@ extern "C" fn page_fault_handler(interrupt_frame: &mut InterruptFrame, _ctx: &mu
 
 extern "C" fn segment_not_present_handler(
     interrupt_frame: &mut InterruptFrame,
-    _ctx: &mut StandardContext,
+    ctx: &mut StandardContext,
+    error_code: u64,
 ) {
     use x86_64::registers::control::Cr2;
 
+    if recover_from_fault(
+        CrashKind::SegmentNotPresent { error_code },
+        interrupt_frame,
+        ctx,
+    ) {
+        return;
+    }
+    if end_faulting_user_task(
+        CrashKind::SegmentNotPresent { error_code },
+        "segment not present",
+        interrupt_frame,
+        ctx,
+        true,
+    ) {
+        return;
+    }
+
     eprintln!(
         r"EXCEPTION: SEGMENT NOT PRESENT
     Accessed Address: {:X?}
@@ -0,0 +1,102 @@
+//! The `syscall` instruction, which programs in user mode use for the system calls `linux`
+//! implements.
+//!
+//! `syscall` doesn't switch stacks or save anything but `rip` (in `rcx`) and `rflags` (in `r11`),
+//! so the entry point switches to the kernel stack itself and builds the interrupt frame an
+//! interrupt from user mode would have pushed.  After that it's a trampoline like the ones in
+//! `entry`, counting itself in the interrupt depth and handing the frame and the saved registers
+//! to the handler, and returning with `iretq` rather than `sysretq`.  Handlers can therefore
+//! switch tasks by rewriting the frame, the same as the handlers of `int 0x80`, which stays the
+//! kernel's own interface.
+//!
+//! Only code in user mode can make system calls:  the frame always says the call came from there.
+
+use core::arch::asm;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::interrupts::{InterruptFrame, StandardContext};
+use crate::linux;
+
+/// The stack pointer of the program making a system call, while the entry point builds the frame.
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// The selectors to return to user mode with.
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+
+/// Enables `syscall` and points it at the entry point.  The GDT has to be loaded already.
+pub fn init() {
+    let selectors = gdt::selectors();
+    USER_CS.store(selectors.user_code_selector.0 as u64, Ordering::SeqCst);
+    USER_SS.store(selectors.user_data_selector.0 as u64, Ordering::SeqCst);
+    KERNEL_RSP.store(gdt::kernel_stack_top().as_u64(), Ordering::SeqCst);
+
+    // `sysret` isn't used, but `Star` insists the user selectors are laid out the way it needs
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("the GDT doesn't suit syscall");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // handlers run with interrupts off, like the interrupt trampolines, so the heap and page table
+    // locks they take are ones whose holders can't be preempted
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn handle_syscall(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    linux::handle_syscall(frame, ctx);
+}
+
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!(
+        "
+        mov [rip + {user_rsp}], rsp
+        mov rsp, [rip + {kernel_rsp}]
+        and rsp, -16
+
+        // the frame an interrupt at the instruction after the syscall would have pushed
+        push qword ptr [rip + {user_ss}]
+        push qword ptr [rip + {user_rsp}]
+        push r11 // rflags
+        push qword ptr [rip + {user_cs}]
+        push rcx // rip
+
+        // set up fake stack frame
+        push rbp
+        mov rbp, rsp
+        ",
+        push_state!(),
+        "
+        lea rdi, [rbp + 8] // interrupt frame
+        mov rsi, rsp // standard context
+        inc qword ptr [rip + {depth}]
+        call {handler}
+        dec qword ptr [rip + {depth}]
+        ",
+        pop_state!(),
+        "
+        mov rsp, rbp
+        pop rbp
+
+        iretq
+        ",
+        user_rsp = sym USER_RSP,
+        kernel_rsp = sym KERNEL_RSP,
+        user_cs = sym USER_CS,
+        user_ss = sym USER_SS,
+        handler = sym handle_syscall,
+        depth = sym crate::interrupts::INTERRUPT_DEPTH,
+        size = const mem::size_of::<StandardContext>(),
+        options(noreturn)
+    )
+}
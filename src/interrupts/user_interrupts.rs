@@ -42,6 +42,11 @@ pub fn attach_new_interrupt_handler(
     }
 }
 
+/// Whether a handler is attached to `idx`.
+pub fn is_attached(idx: usize) -> bool {
+    unsafe { INSTANCE.handlers.get(idx).map_or(false, Option::is_some) }
+}
+
 pub fn handle_user_interrupt(idx: usize, frame: &mut InterruptFrame, ctx: &mut StandardContext) {
     unsafe {
         if idx >= INSTANCE.handlers.len() {
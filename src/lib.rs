@@ -12,10 +12,13 @@
 #![feature(let_chains)]
 #![feature(thread_local)]
 #![feature(c_unwind)]
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
+// lets `#[derive(Arbitrary)]`'s `::barefuzz` paths work inside the kernel too
+extern crate self as barefuzz;
 
 use alloc::vec::Vec;
 use core::panic::PanicInfo;
@@ -30,13 +33,23 @@ use bootloader::{entry_point, BootInfo};
 
 
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
 pub mod concurrency;
+pub mod deterministic;
+pub mod elf;
+pub mod fuzz;
+pub mod fw_cfg;
 pub mod gdt;
+pub mod host;
 pub mod interrupts;
+pub mod linux;
 pub mod memory;
 pub mod pic;
+pub mod rng;
 pub mod serial;
 pub mod task;
+pub mod time;
 pub mod uart;
 pub mod vga_buffer;
 
@@ -47,6 +60,7 @@ pub static LOCKS: Mutex<Vec<&'static Semaphore>> = Mutex::new(Vec::new());
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    interrupts::syscall::init();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
 }
@@ -85,6 +99,17 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    /// A replayed input ran without crashing.
+    NoCrash = 0x12,
+    /// A replayed input crashed;  the low bits are the crash kind's number.
+    Panic = 0x20,
+    DivideError = 0x21,
+    InvalidOpcode = 0x22,
+    GeneralProtection = 0x23,
+    StackSegment = 0x24,
+    SegmentNotPresent = 0x25,
+    PageFault = 0x26,
+    GuestCrash = 0x27,
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
@@ -107,8 +132,16 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+
+    // tests may use the heap
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialisation failed");
+
     test_main();
     hlt_loop();
 }
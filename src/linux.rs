@@ -0,0 +1,601 @@
+//! A minimal Linux personality, so that unmodified static executables (e.g. ones linked against
+//! musl) can run as programs in user mode.
+//!
+//! Programs make system calls with `syscall`, the number in `rax` and arguments in `rdi`, `rsi`,
+//! `rdx`, `r10`, `r8` and `r9`.  The result comes back in `rax`, a negative errno on failure, and
+//! every register but `rcx` and `r11` is preserved.  Only what a static executable needs to start
+//! up, read its input, allocate memory, write output and exit is there:
+//!
+//! - `read` and `readv` on fd 0 read the process's standard input, which is whatever it was
+//!   started with:  the current input, for programs run by the `linux_program` fuzz target.  No
+//!   other fd can be read.
+//! - `write` and `writev` on fds 1 and 2 print to the console, unless the process's output is
+//!   discarded.
+//! - `mmap` only makes anonymous mappings, which `munmap` frees, and `brk` grows the heap that
+//!   starts right after the executable.  `mprotect` and `madvise` do nothing.
+//! - `arch_prctl` sets and gets the `fs` base, which is switched along with the task.
+//! - `clock_gettime` and `gettimeofday` count from boot, whichever clock is asked for.
+//! - `getrandom` is seeded the same way in every process, so runs can be reproduced.
+//! - `exit` and `exit_group` end the process, and so does `tkill` or `tgkill` sending it a signal
+//!   that isn't ignored by default, since handlers never run:  that's how `abort` ends a program.
+//! - The system call numbers from `guest::HYPERCALL_SYSCALL_BASE` up are hypercalls, for programs
+//!   that are guests.
+//! - Signal masks and handlers, ids, `uname` and a few more do just enough to keep libc happy, and
+//!   anything else fails with `ENOSYS`.
+//!
+//! Each process belongs to a user task, spawned by `task::loader`.  How it ended goes to its
+//! `ExitCell` once the task is gone, for whoever's waiting on it, and the executor's reaper frees
+//! it.
+//!
+//! System calls run with interrupts off, on the stack everything in user mode shares.  The heap and
+//! kernel memory locks they take keep interrupts off while they're held, so no task the timer has
+//! switched away from can be holding one.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{align_down, align_up, VirtAddr};
+
+use crate::fuzz::crash::CrashKind;
+use crate::fuzz::guest;
+use crate::interrupts::{InterruptFrame, StandardContext};
+use crate::memory::diagnostics;
+use crate::rng::Rng;
+use crate::task::executor::{self, current_task};
+use crate::task::slots::TaskSlots;
+use crate::task::TaskId;
+use crate::{print, println, time};
+
+/// The most processes which can exist at the same time.
+const MAX_PROCESSES: usize = 16;
+
+/// The end of the lower half, which is all programs get.
+const USER_END: u64 = 0x_8000_0000_0000;
+/// `mmap` hands out memory going down from here, well below the stack.
+const MMAP_TOP: u64 = 0x_7f00_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+/// What `getrandom` is seeded with.
+const RANDOM_SEED: u64 = 0x_6261_7265_6675_7a7a;
+
+const SYS_READ: usize = 0;
+const SYS_WRITE: usize = 1;
+const SYS_CLOSE: usize = 3;
+const SYS_LSEEK: usize = 8;
+const SYS_MMAP: usize = 9;
+const SYS_MPROTECT: usize = 10;
+const SYS_MUNMAP: usize = 11;
+const SYS_BRK: usize = 12;
+const SYS_RT_SIGACTION: usize = 13;
+const SYS_RT_SIGPROCMASK: usize = 14;
+const SYS_IOCTL: usize = 16;
+const SYS_READV: usize = 19;
+const SYS_WRITEV: usize = 20;
+const SYS_SCHED_YIELD: usize = 24;
+const SYS_MADVISE: usize = 28;
+const SYS_GETPID: usize = 39;
+const SYS_EXIT: usize = 60;
+const SYS_UNAME: usize = 63;
+const SYS_GETTIMEOFDAY: usize = 96;
+const SYS_GETUID: usize = 102;
+const SYS_GETGID: usize = 104;
+const SYS_GETEUID: usize = 107;
+const SYS_GETEGID: usize = 108;
+const SYS_ARCH_PRCTL: usize = 158;
+const SYS_GETTID: usize = 186;
+const SYS_TKILL: usize = 200;
+const SYS_SET_TID_ADDRESS: usize = 218;
+const SYS_CLOCK_GETTIME: usize = 228;
+const SYS_EXIT_GROUP: usize = 231;
+const SYS_TGKILL: usize = 234;
+const SYS_GETRANDOM: usize = 318;
+
+const EPERM: i64 = 1;
+const ESRCH: i64 = 3;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
+/// The most `iovec`s `readv` and `writev` take at once.
+const IOV_MAX: u64 = 1024;
+
+/// The result of a system call:  what to return, or an errno.
+type SyscallResult = Result<u64, i64>;
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy)]
+pub enum Exit {
+    Exited(i32),
+    Faulted {
+        kind: CrashKind,
+        frame: InterruptFrame,
+    },
+    /// It sent itself a signal, e.g. `SIGABRT` from `abort`.
+    Signalled {
+        signal: u64,
+        frame: InterruptFrame,
+    },
+    /// Its task was ended some other way, e.g. killed for taking too long.
+    Killed,
+}
+
+/// Where a process's `Exit` goes once its task has ended.
+pub type ExitCell = Arc<OnceCell<Exit>>;
+
+/// The state a program's system calls work on.
+pub struct Process {
+    stdin: Vec<u8>,
+    stdin_read: usize,
+    /// Whether writes to stdout and stderr are printed, rather than discarded.
+    echo: bool,
+    /// The heap, from `brk_start` up to `brk`.
+    brk_start: u64,
+    brk: u64,
+    /// The bottom of the memory `mmap` has handed out so far.
+    mmap_bottom: u64,
+    fs_base: u64,
+    rng: Rng,
+    exit: Option<Exit>,
+    exit_cell: ExitCell,
+}
+
+impl Process {
+    /// A process whose heap starts at `brk`, reading `stdin` as its standard input.  Its output is
+    /// printed if `echo` is set.
+    pub fn new(brk: u64, stdin: Vec<u8>, echo: bool) -> Self {
+        let brk = align_up(brk, PAGE_SIZE);
+        Self {
+            stdin,
+            stdin_read: 0,
+            echo,
+            brk_start: brk,
+            brk,
+            mmap_bottom: MMAP_TOP,
+            fs_base: 0,
+            rng: Rng::new(RANDOM_SEED),
+            exit: None,
+            exit_cell: Arc::new(OnceCell::uninit()),
+        }
+    }
+
+    /// Where to find out how the process ended.
+    pub fn exit_cell(&self) -> ExitCell {
+        self.exit_cell.clone()
+    }
+
+    /// Whether anything is waiting to hear how the process ended.
+    fn waited_on(&self) -> bool {
+        Arc::strong_count(&self.exit_cell) > 1
+    }
+
+    fn read_stdin(&mut self, buf: &mut [u8]) -> usize {
+        let rest = &self.stdin[self.stdin_read..];
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.stdin_read += len;
+        len
+    }
+}
+
+static PROCESSES: TaskSlots<Process, MAX_PROCESSES> = TaskSlots::new();
+
+fn process_of(task: TaskId) -> Option<&'static mut Process> {
+    unsafe { PROCESSES.get(task)?.as_mut() }
+}
+
+fn current_process() -> Option<&'static mut Process> {
+    process_of(current_task()?)
+}
+
+/// Makes `process` the one `task` runs.  The task mustn't have started yet.
+///
+/// Panics if `MAX_PROCESSES` processes already exist.
+pub fn attach(task: TaskId, process: Process) {
+    let process = Box::into_raw(Box::new(process));
+    without_interrupts(|| PROCESSES.insert(task, process)).expect("too many processes");
+}
+
+/// Lets whoever's waiting know how a task that's ended ended, and hands its process to the
+/// executor's reaper to free.
+fn release(task: TaskId) {
+    if let Some(process) = PROCESSES.remove(task) {
+        let process = unsafe { Box::from_raw(process) };
+        let exit = process.exit.unwrap_or(Exit::Killed);
+        let _ = process.exit_cell.try_init_once(|| exit);
+        executor::reap(process);
+    }
+}
+
+/// Gives the task about to run its own `fs` base.
+fn switch_fs_base(_from: Option<TaskId>, to: TaskId) {
+    let base = process_of(to).map_or(0, |process| process.fs_base);
+    FsBase::write(VirtAddr::new(base));
+}
+
+/// Hooks processes into task switches and exits.  Must be called after `executor::init`.
+pub fn init() {
+    executor::register_switch_hook(switch_fs_base);
+    executor::register_exit_hook(release);
+}
+
+/// Notes that the running task's process is about to be ended for a fault.  Returns whether
+/// anything is waiting to hear about it.
+pub fn record_fault(kind: CrashKind, frame: &InterruptFrame) -> bool {
+    match current_process() {
+        Some(process) => {
+            if process.exit.is_none() {
+                process.exit = Some(Exit::Faulted {
+                    kind,
+                    frame: *frame,
+                });
+            }
+            process.waited_on()
+        }
+        None => false,
+    }
+}
+
+/// `len` bytes of the program's memory at `addr`, if they're all mapped and user accessible, and
+/// writable if `writable` is set.
+pub fn user_memory(addr: u64, len: u64, writable: bool) -> Result<&'static mut [u8], i64> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| end <= USER_END)
+        .ok_or(EFAULT)?;
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    required.set(PageTableFlags::WRITABLE, writable);
+
+    for page in (align_down(addr, PAGE_SIZE)..end).step_by(PAGE_SIZE as usize) {
+        let walk = diagnostics::walk(VirtAddr::new(page));
+        // every level has a say in what the program can do with the page
+        let accessible = walk.translation.is_some()
+            && walk
+                .steps
+                .iter()
+                .flatten()
+                .all(|step| step.flags.contains(required));
+        if !accessible {
+            return Err(EFAULT);
+        }
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+fn write_user(addr: u64, bytes: &[u8]) -> Result<(), i64> {
+    user_memory(addr, bytes.len() as u64, true)?.copy_from_slice(bytes);
+    Ok(())
+}
+
+/// The `(base, len)` pairs of an array of `iovec`s.
+fn iovecs(iov: u64, count: u64) -> Result<Vec<(u64, u64)>, i64> {
+    if count > IOV_MAX {
+        return Err(EINVAL);
+    }
+    let array = user_memory(iov, count * 16, false)?;
+    Ok(array
+        .chunks_exact(16)
+        .map(|iovec| {
+            let base = u64::from_le_bytes(iovec[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(iovec[8..].try_into().unwrap());
+            (base, len)
+        })
+        .collect())
+}
+
+/// Pids are task ids plus one, since 0 isn't a pid.
+fn pid() -> u64 {
+    current_task().map_or(1, |task| task.as_u64() + 1)
+}
+
+fn read(process: &mut Process, fd: u64, buf: u64, len: u64) -> SyscallResult {
+    if fd != 0 {
+        return Err(EBADF);
+    }
+    let buf = user_memory(buf, len, true)?;
+    Ok(process.read_stdin(buf) as u64)
+}
+
+fn readv(process: &mut Process, fd: u64, iov: u64, count: u64) -> SyscallResult {
+    if fd != 0 {
+        return Err(EBADF);
+    }
+    let mut total = 0;
+    for (base, len) in iovecs(iov, count)? {
+        let read = process.read_stdin(user_memory(base, len, true)?) as u64;
+        total += read;
+        if read < len {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+fn write(process: &Process, fd: u64, buf: u64, len: u64) -> SyscallResult {
+    if fd != 1 && fd != 2 {
+        return Err(EBADF);
+    }
+    let bytes = user_memory(buf, len, false)?;
+    if process.echo {
+        print!("{}", String::from_utf8_lossy(bytes));
+    }
+    Ok(len)
+}
+
+fn writev(process: &Process, fd: u64, iov: u64, count: u64) -> SyscallResult {
+    let mut total = 0;
+    for (base, len) in iovecs(iov, count)? {
+        total += write(process, fd, base, len)?;
+    }
+    Ok(total)
+}
+
+fn page_flags(prot: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    flags.set(PageTableFlags::WRITABLE, prot & PROT_WRITE != 0);
+    flags.set(PageTableFlags::NO_EXECUTE, prot & PROT_EXEC == 0);
+    flags
+}
+
+fn mmap(process: &mut Process, addr: u64, len: u64, prot: u64, flags: u64) -> SyscallResult {
+    if flags & MAP_ANONYMOUS == 0 {
+        // no files to map
+        return Err(EBADF);
+    }
+    if len == 0 || len > MMAP_TOP {
+        return Err(EINVAL);
+    }
+    let size = align_up(len, PAGE_SIZE);
+
+    let fixed = flags & MAP_FIXED != 0;
+    let start = if fixed {
+        if addr % PAGE_SIZE != 0 || addr.checked_add(size).map_or(true, |end| end > USER_END) {
+            return Err(EINVAL);
+        }
+        addr
+    } else {
+        let start = process.mmap_bottom.checked_sub(size).ok_or(ENOMEM)?;
+        if start < process.brk {
+            return Err(ENOMEM);
+        }
+        process.mmap_bottom = start;
+        start
+    };
+
+    executor::with_current_address_space(|address_space| {
+        if fixed {
+            address_space.unmap(VirtAddr::new(start), size);
+        }
+        address_space.map(VirtAddr::new(start), size, page_flags(prot))
+    })
+    .ok_or(ENOMEM)?
+    .map_err(|_| ENOMEM)?;
+    Ok(start)
+}
+
+fn munmap(addr: u64, len: u64) -> SyscallResult {
+    if addr % PAGE_SIZE != 0 || addr.checked_add(len).map_or(true, |end| end > USER_END) {
+        return Err(EINVAL);
+    }
+    executor::with_current_address_space(|address_space| {
+        address_space.unmap(VirtAddr::new(addr), align_up(len, PAGE_SIZE))
+    });
+    Ok(0)
+}
+
+/// Moves the end of the heap to `addr` if it can, and returns where the end is.
+fn brk(process: &mut Process, addr: u64) -> u64 {
+    if addr < process.brk_start || addr > process.mmap_bottom {
+        return process.brk;
+    }
+    let (old_end, new_end) = (align_up(process.brk, PAGE_SIZE), align_up(addr, PAGE_SIZE));
+    let moved = executor::with_current_address_space(|address_space| {
+        if new_end >= old_end {
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            address_space
+                .map(VirtAddr::new(old_end), new_end - old_end, flags)
+                .is_ok()
+        } else {
+            address_space.unmap(VirtAddr::new(new_end), old_end - new_end);
+            true
+        }
+    });
+    if moved == Some(true) {
+        process.brk = addr;
+    }
+    process.brk
+}
+
+fn arch_prctl(process: &mut Process, code: u64, addr: u64) -> SyscallResult {
+    match code {
+        ARCH_SET_FS if addr < USER_END => {
+            process.fs_base = addr;
+            FsBase::write(VirtAddr::new(addr));
+            Ok(0)
+        }
+        ARCH_SET_FS => Err(EPERM),
+        ARCH_GET_FS => write_user(addr, &process.fs_base.to_le_bytes()).map(|()| 0),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Clears the old signal action or mask at `addr`, if the program asked for it.  There are never
+/// any handlers, and nothing is ever blocked.
+fn no_old_signal_state(addr: u64, len: u64) -> SyscallResult {
+    if addr != 0 {
+        user_memory(addr, len, true)?.fill(0);
+    }
+    Ok(0)
+}
+
+fn uname(addr: u64) -> SyscallResult {
+    const FIELD_LEN: usize = 65;
+    let fields = [
+        "Linux",
+        "barefuzz",
+        "6.1.0",
+        "#1 barefuzz",
+        "x86_64",
+        "(none)",
+    ];
+    let mut utsname = [0; 6 * FIELD_LEN];
+    for (field, value) in utsname.chunks_exact_mut(FIELD_LEN).zip(fields) {
+        field[..value.len()].copy_from_slice(value.as_bytes());
+    }
+    write_user(addr, &utsname).map(|()| 0)
+}
+
+/// Writes the time since boot at `addr` as seconds and then `unit`s of a second.
+fn write_time(addr: u64, unit_ns: u64) -> SyscallResult {
+    let now = time::since_boot_ns();
+    let mut time = [0; 16];
+    time[..8].copy_from_slice(&(now / 1_000_000_000).to_le_bytes());
+    time[8..].copy_from_slice(&(now % 1_000_000_000 / unit_ns).to_le_bytes());
+    write_user(addr, &time).map(|()| 0)
+}
+
+fn getrandom(process: &mut Process, buf: u64, len: u64) -> SyscallResult {
+    let buf = user_memory(buf, len, true)?;
+    for chunk in buf.chunks_mut(8) {
+        let random = process.rng.next_u64().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    Ok(len)
+}
+
+/// Whether a signal's default action is to do nothing.
+fn ignored_by_default(signal: u64) -> bool {
+    // SIGCHLD, SIGCONT, SIGURG and SIGWINCH
+    matches!(signal, 17 | 18 | 23 | 28)
+}
+
+/// Ends the process with `exit` and switches to the next task.
+fn end_process(
+    process: &mut Process,
+    exit: Exit,
+    frame: &mut InterruptFrame,
+    ctx: &mut StandardContext,
+) {
+    if process.exit.is_none() {
+        process.exit = Some(exit);
+    }
+    let rbp = unsafe { frame.saved_rbp(false) };
+    executor::exit_current_task(frame, ctx, rbp);
+}
+
+/// Ends the running task's process with `exit`, from a system call or hypercall handler.
+pub fn end_current_process(exit: Exit, frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    if let Some(process) = current_process() {
+        end_process(process, exit, frame, ctx);
+    }
+}
+
+/// Handles a system call from the running task, called by the `syscall` entry point.
+pub fn handle_syscall(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    let process = match current_process() {
+        Some(process) => process,
+        None => {
+            ctx.rax = -ENOSYS as usize;
+            return;
+        }
+    };
+    let [a, b, c, d, _, _] =
+        [ctx.rdi, ctx.rsi, ctx.rdx, ctx.r10, ctx.r8, ctx.r9].map(|arg| arg as u64);
+
+    let result = match ctx.rax {
+        SYS_READ => read(process, a, b, c),
+        SYS_WRITE => write(process, a, b, c),
+        SYS_CLOSE if a <= 2 => Ok(0),
+        SYS_CLOSE => Err(EBADF),
+        SYS_LSEEK => Err(ESPIPE),
+        SYS_MMAP => mmap(process, a, b, c, d),
+        SYS_MPROTECT | SYS_MADVISE | SYS_SCHED_YIELD => Ok(0),
+        SYS_MUNMAP => munmap(a, b),
+        SYS_BRK => Ok(brk(process, a)),
+        // a `sigaction` is two words and the flags before the mask
+        SYS_RT_SIGACTION => no_old_signal_state(c, 24 + d.min(128)),
+        SYS_RT_SIGPROCMASK => no_old_signal_state(c, d.min(128)),
+        SYS_IOCTL => Err(ENOTTY),
+        SYS_READV => readv(process, a, b, c),
+        SYS_WRITEV => writev(process, a, b, c),
+        SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => Ok(pid()),
+        SYS_GETUID | SYS_GETGID | SYS_GETEUID | SYS_GETEGID => Ok(0),
+        SYS_UNAME => uname(a),
+        SYS_GETTIMEOFDAY if a == 0 => Ok(0),
+        SYS_GETTIMEOFDAY => write_time(a, 1000),
+        SYS_CLOCK_GETTIME => write_time(b, 1),
+        SYS_ARCH_PRCTL => arch_prctl(process, a, b),
+        SYS_GETRANDOM => getrandom(process, a, b),
+        SYS_EXIT | SYS_EXIT_GROUP => {
+            return end_process(process, Exit::Exited(a as i32), frame, ctx);
+        }
+        SYS_TKILL | SYS_TGKILL => {
+            let (tid, signal) = if ctx.rax == SYS_TKILL { (a, b) } else { (b, c) };
+            if tid != pid() {
+                Err(ESRCH)
+            } else if signal == 0 || ignored_by_default(signal) {
+                Ok(0)
+            } else {
+                let exit = Exit::Signalled {
+                    signal,
+                    frame: *frame,
+                };
+                return end_process(process, exit, frame, ctx);
+            }
+        }
+        number if guest::is_hypercall(number as u64) => {
+            return guest::handle_syscall(frame, ctx);
+        }
+        number => {
+            if process.echo {
+                println!(
+                    "[{:?}] unimplemented system call {}",
+                    current_task(),
+                    number
+                );
+            }
+            Err(ENOSYS)
+        }
+    };
+
+    ctx.rax = match result {
+        Ok(value) => value as usize,
+        Err(errno) => -errno as usize,
+    };
+}
+
+#[test_case]
+fn test_stdin_and_user_memory() {
+    let mut process = Process::new(0x1000, alloc::vec![1, 2, 3, 4, 5], false);
+    let mut buf = [0; 3];
+    assert_eq!(process.read_stdin(&mut buf), 3);
+    assert_eq!(process.read_stdin(&mut buf), 2);
+    assert_eq!(buf, [4, 5, 3]);
+    assert_eq!(process.read_stdin(&mut buf), 0);
+
+    // the kernel's memory is out of the program's reach
+    let kernel = &process as *const Process as u64;
+    assert_eq!(user_memory(kernel, 8, false).unwrap_err(), EFAULT);
+    assert_eq!(user_memory(u64::MAX - 4, 8, false).unwrap_err(), EFAULT);
+}
@@ -12,7 +12,8 @@ use core::sync::atomic::Ordering;
 use bootloader::{BootInfo, entry_point};
 
 use barefuzz::{
-    allocator, eprintln, INITIALISED, LOCKS, memory, println, serial, vga_buffer,
+    allocator, deterministic, eprintln, fuzz, host, INITIALISED, linux, LOCKS, memory, println,
+    serial, task, time, vga_buffer,
 };
 use barefuzz::interrupts::PICS;
 use barefuzz::memory::BootInfoFrameAllocator;
@@ -27,47 +28,64 @@ fn _kernel_entry(boot_info: &'static BootInfo) -> ! {
     barefuzz::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed");
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    memory::diagnostics::print_memory_map(&boot_info.memory_map);
+
+    allocator::init_heap().expect("heap initialisation failed");
+    memory::protect::enforce_wx();
+    LOCKS.lock().push(&memory::KERNEL_MEMORY.get().unwrap().semaphore);
+    LOCKS.lock().push(&memory::vma::REGIONS.semaphore);
     LOCKS.lock().push(&SERIAL1.semaphore);
     LOCKS.lock().push(&WRITER.semaphore);
     LOCKS.lock().push(&PICS.semaphore);
 
     executor::init();
+    linux::init();
+    allocator::fault_injection::init();
+    fuzz::coverage::init();
+    fuzz::cmplog::init();
+    fuzz::guest::init();
+    fuzz::crash::init();
+    time::init();
+    if deterministic::requested() {
+        match deterministic::enable() {
+            Ok(()) => println!(
+                "Deterministic mode: switching tasks every {} instructions",
+                deterministic::QUANTUM
+            ),
+            Err(error) => eprintln!("Can't run deterministically: {}", error),
+        }
+    }
+    fuzz::stats::init();
     INITIALISED.store(true, Ordering::SeqCst);
     // kernel_main()
     unwinding::panic::catch_unwind(kernel_main).unwrap()
 }
 
+/// How many tasks run the fuzz target at the same time.
+const FUZZ_WORKERS: usize = 2;
+
 fn kernel_main() -> ! {
-    {
+    if fuzz::replay::requested() {
+        let mut executor = executor::INSTANCE.get().unwrap().lock();
+        fuzz::replay::start(&mut executor);
+    } else {
+        let harness = fuzz::harness::select();
+        let program = task::loader::from_fw_cfg();
         let mut executor = executor::INSTANCE.get().unwrap().lock();
+        if let Some(program) = program {
+            println!("Started opt/barefuzz/program as task {:?}", program.spawn(&mut executor));
+        }
         executor.spawn(|| loop {
             serial::flush();
             vga_buffer::flush();
         });
+        executor.spawn(host::serve);
+        executor.spawn(fuzz::stats::monitor);
 
-        executor.spawn(|| {
-            let mut i = 10_000;
-            loop {
-                i += 1;
-                if i % 10000 == 0 {
-                    println!("t1 {}", i);
-                }
-            }
-        });
-
-        executor.spawn(|| {
-            let mut i = 0;
-            loop {
-                i += 1;
-                if i % 10000 == 0 {
-                    println!("t2 {}", i);
-                }
-            }
-        });
+        fuzz::runner::start(&mut executor, harness, FUZZ_WORKERS);
     }
     loop {
         // yield_();
@@ -77,6 +95,9 @@ fn kernel_main() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // a fuzz target panicking is just a crash to record, not the end of the kernel
+    fuzz::crash::recover_from_panic(info);
+
     unsafe {
         LOCKS.force_unlock();
     }
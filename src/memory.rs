@@ -1,10 +1,77 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use conquer_once::spin::OnceCell;
 use x86_64::{
+    align_up,
     PhysAddr,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB}, VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTable, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
 };
 
+use crate::concurrency::mutex::{Mutex, MutexGuard};
+
+pub mod address_space;
+pub mod diagnostics;
+pub mod mapping;
+pub mod protect;
+pub mod vma;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `memory::init` has been called, so that `phys_to_virt` can be used.
+pub fn physical_memory_mapped() -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the address physical memory at `addr` is mapped at by the bootloader.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// The level 4 table that was active when `memory::init` was called, which kernel tasks run on.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// The active page table and the frame allocator backing it.
+///
+/// Anything that needs to change kernel mappings after boot (e.g. growing the heap) goes through
+/// this.  Don't allocate on the kernel heap while holding the lock, since the heap may need it to
+/// grow.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+pub static KERNEL_MEMORY: OnceCell<Mutex<KernelMemory>> = OnceCell::uninit();
+
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    KERNEL_MEMORY.init_once(|| {
+        Mutex::new(KernelMemory {
+            mapper,
+            frame_allocator,
+        })
+    });
+}
+
+/// Locks the kernel's page tables and frame allocator, with interrupts off while they're locked,
+/// since handlers for interrupts from user mode map and free memory.
+pub fn kernel_memory() -> MutexGuard<'static, KernelMemory> {
+    KERNEL_MEMORY
+        .get()
+        .expect("kernel memory not initialised")
+        .lock_without_interrupts()
+}
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_PAGE_TABLE.store(level_4_table_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -29,36 +96,166 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Marks the end of a list of freed frames.
+const NO_FRAME: u64 = u64::MAX;
+
+/// Whether the CPU can map 1 GiB pages.  2 MiB pages are always available in long mode.
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    }
+}
+
+/// Hands out frames of every page size from the usable regions of the bootloader's memory map.
+///
+/// Frames are taken from a cursor that only moves forwards;  frames skipped over to reach a
+/// large frame's alignment go on the 4 KiB free list instead of being lost.  Each page size has
+/// its own free list, and once the memory map is used up 4 KiB frames are split off free large
+/// frames.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    /// Index of the memory map region the cursor is in.
+    region: usize,
+    /// Physical address of the first frame that has never been handed out.
+    next: u64,
+    /// Physical addresses of the most recently freed frame of each size.  Each freed frame holds
+    /// the address of the one freed before it in its first 8 bytes.
+    free_4kib: u64,
+    free_2mib: u64,
+    free_1gib: u64,
 }
 
 impl BootInfoFrameAllocator {
+    /// Requires `memory::init` to have been called, since freed frames are accessed through the
+    /// physical memory mapping.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
+            region: 0,
             next: 0,
+            free_4kib: NO_FRAME,
+            free_2mib: NO_FRAME,
+            free_1gib: NO_FRAME,
+        }
+    }
+
+    /// Takes `size` bytes aligned to `size` from past the cursor.
+    ///
+    /// Usable memory skipped over on the way is put on the 4 KiB free list.  If nothing large
+    /// enough is left the cursor doesn't move, so a failed large allocation costs nothing.
+    fn take_aligned(&mut self, size: u64) -> Option<PhysAddr> {
+        let memory_map: &'static [MemoryRegion] = self.memory_map;
+        let usable = |region: &&MemoryRegion| region.region_type == MemoryRegionType::Usable;
+
+        let (index, aligned) = memory_map
+            .iter()
+            .enumerate()
+            .skip(self.region)
+            .filter(|(_, region)| usable(region))
+            .find_map(|(index, region)| {
+                let aligned = align_up(self.next.max(region.range.start_addr()), size);
+                let fits = aligned.checked_add(size)? <= region.range.end_addr();
+                fits.then(|| (index, aligned))
+            })?;
+
+        for region in memory_map[self.region..index].iter().filter(usable) {
+            self.release_range(self.next.max(region.range.start_addr()), region.range.end_addr());
+        }
+        let region = &memory_map[index];
+        self.release_range(self.next.max(region.range.start_addr()), aligned);
+
+        self.region = index;
+        self.next = aligned + size;
+        Some(PhysAddr::new(aligned))
+    }
+
+    /// Puts every 4 KiB frame in `[start, end)` on the free list.
+    fn release_range(&mut self, start: u64, end: u64) {
+        for addr in (start..end).step_by(Size4KiB::SIZE as usize) {
+            unsafe { push_frame(&mut self.free_4kib, PhysAddr::new(addr)) };
+        }
+    }
+
+    /// Breaks a free large frame up into 4 KiB frames.
+    fn split_large_frame(&mut self) -> bool {
+        let large = unsafe { pop_frame(&mut self.free_2mib) }
+            .map(|frame| (frame, Size2MiB::SIZE))
+            .or_else(|| {
+                unsafe { pop_frame(&mut self.free_1gib) }.map(|frame| (frame, Size1GiB::SIZE))
+            });
+
+        match large {
+            Some((frame, size)) => {
+                self.release_range(frame.as_u64(), frame.as_u64() + size);
+                true
+            }
+            None => false,
         }
     }
+}
+
+unsafe fn push_frame(list: &mut u64, frame: PhysAddr) {
+    *phys_to_virt(frame).as_mut_ptr::<u64>() = *list;
+    *list = frame.as_u64();
+}
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+unsafe fn pop_frame(list: &mut u64) -> Option<PhysAddr> {
+    if *list == NO_FRAME {
+        return None;
     }
+    let frame = PhysAddr::new(*list);
+    *list = *phys_to_virt(frame).as_ptr::<u64>();
+    Some(frame)
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        loop {
+            if let Some(frame) = unsafe { pop_frame(&mut self.free_4kib) } {
+                return Some(PhysFrame::containing_address(frame));
+            }
+            if let Some(frame) = self.take_aligned(Size4KiB::SIZE) {
+                return Some(PhysFrame::containing_address(frame));
+            }
+            if !self.split_large_frame() {
+                return None;
+            }
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        unsafe { pop_frame(&mut self.free_2mib) }
+            .or_else(|| self.take_aligned(Size2MiB::SIZE))
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        unsafe { pop_frame(&mut self.free_1gib) }
+            .or_else(|| self.take_aligned(Size1GiB::SIZE))
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        push_frame(&mut self.free_4kib, frame.start_address());
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        push_frame(&mut self.free_2mib, frame.start_address());
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        push_frame(&mut self.free_1gib, frame.start_address());
     }
 }
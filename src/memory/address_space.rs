@@ -0,0 +1,276 @@
+//! Address spaces for programs running in user mode.
+//!
+//! Each address space has its own level 4 table that starts out as a copy of the kernel's, so the
+//! kernel stays mapped while a program runs, out of the program's reach since none of its pages
+//! are user accessible.  Mapping a page under a table the kernel already uses copies the tables on
+//! the way down instead of changing the kernel's, so executables linked at the usual low
+//! addresses load fine as long as none of their pages land on one of the kernel's.
+//!
+//! Level 4 entries an address space hasn't had to copy are refreshed from the kernel's table
+//! every time it's activated, so it keeps up with e.g. the heap growing.  Kernel mappings added
+//! under the tables it did copy don't show up in it.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::memory::{kernel_memory, kernel_page_table, phys_to_virt, KernelMemory};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Flags for entries pointing at tables an address space owns.  Whether a page is writable, user
+/// accessible or executable is up to the page's own entry.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: PhysFrame,
+    /// Tables this address space owns rather than shares with the kernel, `root` included.
+    tables: Vec<PhysFrame>,
+    /// Frames mapped for the program.
+    frames: Vec<PhysFrame>,
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+fn frame_of(entry: &PageTableEntry) -> PhysFrame {
+    PhysFrame::containing_address(entry.addr())
+}
+
+/// A zeroed frame from the frame allocator.
+fn allocate_zeroed(memory: &mut KernelMemory) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            PAGE_SIZE as usize,
+        )
+    };
+    Ok(frame)
+}
+
+/// A new table holding a copy of the entries in `source`.
+fn copy_table(
+    memory: &mut KernelMemory,
+    source: PhysFrame,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = allocate_zeroed(memory)?;
+    let (table, source) = unsafe { (table_mut(frame), table_mut(source)) };
+    for (entry, source) in table.iter_mut().zip(source.iter()) {
+        *entry = source.clone();
+    }
+    Ok(frame)
+}
+
+fn switch_to(root: PhysFrame) {
+    let (current, flags) = Cr3::read();
+    if current != root {
+        unsafe { Cr3::write(root, flags) };
+    }
+}
+
+/// Switches back to the kernel's own page table.
+pub fn activate_kernel() {
+    switch_to(kernel_page_table());
+}
+
+impl AddressSpace {
+    /// An address space with nothing but the kernel in it.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let root = without_interrupts(|| copy_table(&mut kernel_memory(), kernel_page_table()))?;
+        Ok(Self {
+            root,
+            tables: vec![root],
+            frames: Vec::new(),
+        })
+    }
+
+    /// The level 1 entry for `addr`, making the tables on the way down this address space's own.
+    fn leaf_entry(
+        &mut self,
+        memory: &mut KernelMemory,
+        addr: VirtAddr,
+    ) -> Result<&'static mut PageTableEntry, MapToError<Size4KiB>> {
+        let mut table = unsafe { table_mut(self.root) };
+        for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entry = &mut table[index];
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(MapToError::ParentEntryHugePage);
+            }
+
+            let next = if entry.is_unused() {
+                allocate_zeroed(memory)?
+            } else if self.tables.contains(&frame_of(entry)) {
+                table = unsafe { table_mut(frame_of(entry)) };
+                continue;
+            } else {
+                // the kernel's, which mustn't change
+                copy_table(memory, frame_of(entry))?
+            };
+            self.tables.push(next);
+            entry.set_frame(next, TABLE_FLAGS);
+            table = unsafe { table_mut(next) };
+        }
+        Ok(&mut table[addr.p1_index()])
+    }
+
+    fn map_page(
+        &mut self,
+        memory: &mut KernelMemory,
+        addr: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let entry = self.leaf_entry(memory, addr)?;
+        if entry.is_unused() {
+            let frame = allocate_zeroed(memory)?;
+            self.frames.push(frame);
+            entry.set_frame(frame, flags);
+        } else if self.frames.contains(&frame_of(entry)) {
+            let executable = !(entry.flags() & flags).contains(PageTableFlags::NO_EXECUTE);
+            let mut merged = entry.flags() | flags;
+            merged.set(PageTableFlags::NO_EXECUTE, !executable);
+            entry.set_flags(merged);
+        } else {
+            return Err(MapToError::PageAlreadyMapped(frame_of(entry)));
+        }
+        Ok(())
+    }
+
+    /// Maps `size` bytes of zeroed memory at `start`, which both have to be page aligned.  The
+    /// pages are user accessible whatever `flags` says.
+    ///
+    /// Pages that are already mapped keep their contents and get the permissions of both
+    /// mappings, since an executable's segments can share a page.  Fails with `PageAlreadyMapped`
+    /// if a page is one of the kernel's.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        without_interrupts(|| {
+            let mut memory = kernel_memory();
+            for offset in (0..size).step_by(PAGE_SIZE as usize) {
+                self.map_page(&mut memory, start + offset, flags)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The level 1 entry mapping `addr`, if there is one.
+    fn existing_entry(&self, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        let mut table = unsafe { table_mut(self.root) };
+        for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entry = &table[index];
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { table_mut(frame_of(entry)) };
+        }
+        let entry = &mut table[addr.p1_index()];
+        (!entry.is_unused()).then(|| entry)
+    }
+
+    fn translate(&self, addr: VirtAddr) -> Option<PhysFrame> {
+        self.existing_entry(addr).map(|entry| frame_of(entry))
+    }
+
+    /// Unmaps and frees the program's pages among the `size` bytes at `start`, which both have to
+    /// be page aligned.  Pages that aren't mapped, or are the kernel's, are left alone.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) {
+        without_interrupts(|| {
+            let mut memory = kernel_memory();
+            for offset in (0..size).step_by(PAGE_SIZE as usize) {
+                let addr = start + offset;
+                let entry = match self.existing_entry(addr) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let frame = frame_of(entry);
+                if let Some(index) = self.frames.iter().position(|&owned| owned == frame) {
+                    entry.set_unused();
+                    tlb::flush(addr);
+                    self.frames.swap_remove(index);
+                    unsafe {
+                        FrameDeallocator::<Size4KiB>::deallocate_frame(
+                            &mut memory.frame_allocator,
+                            frame,
+                        )
+                    };
+                }
+            }
+        });
+    }
+
+    /// Copies `bytes` to `addr` in this address space, whether or not it's the active one and
+    /// whatever the pages' permissions.  Panics if any of the range isn't mapped.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) {
+        let mut done = 0;
+        while done < bytes.len() {
+            let addr = addr + done as u64;
+            let offset = addr.as_u64() % PAGE_SIZE;
+            let len = ((PAGE_SIZE - offset) as usize).min(bytes.len() - done);
+            let frame = self
+                .translate(addr)
+                .unwrap_or_else(|| panic!("{:#x} isn't mapped", addr.as_u64()));
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    bytes[done..].as_ptr(),
+                    phys_to_virt(frame.start_address() + offset).as_mut_ptr::<u8>(),
+                    len,
+                )
+            };
+            done += len;
+        }
+    }
+
+    /// Brings the kernel's part of the address space up to date and switches to it.
+    pub fn activate(&self) {
+        let (root, kernel) = unsafe { (table_mut(self.root), table_mut(kernel_page_table())) };
+        for (entry, kernel_entry) in root.iter_mut().zip(kernel.iter()) {
+            if entry.is_unused() || !self.tables.contains(&frame_of(entry)) {
+                *entry = kernel_entry.clone();
+            }
+        }
+        switch_to(self.root);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the program's frames and this address space's own tables.  It mustn't be active.
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.root,
+            "dropping the active address space"
+        );
+        without_interrupts(|| {
+            let mut memory = kernel_memory();
+            for &frame in self.frames.iter().chain(&self.tables) {
+                unsafe {
+                    FrameDeallocator::<Size4KiB>::deallocate_frame(
+                        &mut memory.frame_allocator,
+                        frame,
+                    )
+                };
+            }
+        });
+    }
+}
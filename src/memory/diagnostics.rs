@@ -0,0 +1,252 @@
+//! Dumps of the physical memory map and the active page tables.
+//!
+//! The page-table walks read the tables directly through the physical memory mapping instead of
+//! going through `KERNEL_MEMORY`, so they can be used from exception handlers even if the lock is
+//! held.  They don't allocate either.
+
+use core::fmt;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{phys_to_virt, physical_memory_mapped};
+use crate::println;
+
+const LEVEL_NAMES: [&str; 4] = ["P4", "P3", "P2", "P1"];
+
+/// Flags that change as memory is used, which shouldn't split up a range.
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::ACCESSED
+    .union(PageTableFlags::DIRTY)
+    .union(PageTableFlags::HUGE_PAGE);
+
+/// Prints each region of the bootloader's memory map, followed by the total size of each type.
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    println!("Physical memory map:");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        println!(
+            "  {:#014x}-{:#014x} {:>10} KiB {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+    }
+
+    // the memory map is tiny, so finding each type's first occurrence is cheap enough
+    for (index, region) in memory_map.iter().enumerate() {
+        let region_type = region.region_type;
+        if memory_map[..index]
+            .iter()
+            .any(|r| r.region_type == region_type)
+        {
+            continue;
+        }
+        let total: u64 = memory_map
+            .iter()
+            .filter(|r| r.region_type == region_type)
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum();
+        println!("  {:>10} KiB total {:?}", total / 1024, region_type);
+    }
+
+    let usable: u64 = memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum();
+    println!("  {} MiB usable", usable / (1024 * 1024));
+}
+
+unsafe fn table_at(addr: PhysAddr) -> &'static PageTable {
+    &*phys_to_virt(addr).as_ptr::<PageTable>()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    pub index: u16,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+/// The entries followed to translate a virtual address, from the level 4 table down.
+#[derive(Debug, Clone, Copy)]
+pub struct Walk {
+    pub addr: VirtAddr,
+    pub root: PhysAddr,
+    pub steps: [Option<WalkStep>; 4],
+    /// The physical address and the size of the page it's in, if `addr` is mapped.
+    pub translation: Option<(PhysAddr, u64)>,
+}
+
+/// Walks the active page tables for `addr`, stopping at the first entry that isn't present.
+pub fn walk(addr: VirtAddr) -> Walk {
+    let (root, _) = Cr3::read();
+    let mut walk = Walk {
+        addr,
+        root: root.start_address(),
+        steps: [None; 4],
+        translation: None,
+    };
+    if !physical_memory_mapped() {
+        return walk;
+    }
+
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table = root.start_address();
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &unsafe { table_at(table) }[index];
+        let step = WalkStep {
+            index: u16::from(index),
+            addr: entry.addr(),
+            flags: entry.flags(),
+        };
+        walk.steps[level] = Some(step);
+
+        if !step.flags.contains(PageTableFlags::PRESENT) {
+            return walk;
+        }
+        // a level 3 or level 2 entry with HUGE_PAGE set maps a 1 GiB or 2 MiB page directly
+        let is_leaf = level == 3 || (level > 0 && step.flags.contains(PageTableFlags::HUGE_PAGE));
+        if is_leaf {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            let offset = addr.as_u64() & (page_size - 1);
+            walk.translation = Some((step.addr + offset, page_size));
+            return walk;
+        }
+        table = step.addr;
+    }
+    walk
+}
+
+/// Translates `addr` to a physical address using the active page tables.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    walk(addr).translation.map(|(phys, _)| phys)
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "walk of {:#x} from CR3 {:#x}:", self.addr, self.root)?;
+        if !physical_memory_mapped() {
+            return writeln!(f, "  page tables aren't accessible yet");
+        }
+
+        for (name, step) in LEVEL_NAMES.iter().zip(self.steps.iter()) {
+            if let Some(step) = step {
+                writeln!(
+                    f,
+                    "  {}[{:>3}] -> {:#014x} {:?}",
+                    name, step.index, step.addr, step.flags
+                )?;
+            }
+        }
+
+        match self.translation {
+            Some((phys, page_size)) => {
+                writeln!(f, "  = {:#x} ({} KiB page)", phys, page_size / 1024)
+            }
+            None => writeln!(f, "  not mapped"),
+        }
+    }
+}
+
+/// A run of pages which are mapped to contiguous physical memory with the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    fn extends_to(&self, start: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> bool {
+        self.start + self.size == start && self.phys + self.size == phys && self.flags == flags
+    }
+}
+
+/// Calls `f` for every mapped page in the active page tables, in address order, with its
+/// virtual address, physical address, size and flags.
+fn for_each_page(mut f: impl FnMut(VirtAddr, PhysAddr, u64, PageTableFlags)) {
+    fn visit(
+        table: PhysAddr,
+        level: usize,
+        base: u64,
+        f: &mut dyn FnMut(VirtAddr, PhysAddr, u64, PageTableFlags),
+    ) {
+        let entry_size = 1u64 << (12 + 9 * (3 - level));
+        for (index, entry) in unsafe { table_at(table) }.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            let virt = VirtAddr::new_truncate(base + index as u64 * entry_size);
+            if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                f(virt, entry.addr(), entry_size, flags);
+            } else {
+                visit(entry.addr(), level + 1, virt.as_u64(), f);
+            }
+        }
+    }
+
+    if physical_memory_mapped() {
+        let (root, _) = Cr3::read();
+        visit(root.start_address(), 0, 0, &mut f);
+    }
+}
+
+/// Calls `f` for every coalesced range of mappings in the active page tables.
+pub fn for_each_range(mut f: impl FnMut(&MappedRange)) {
+    let mut current: Option<MappedRange> = None;
+    for_each_page(|start, phys, size, flags| {
+        let flags = flags - VOLATILE_FLAGS;
+        if let Some(range) = current.as_mut() {
+            if range.extends_to(start, phys, flags) {
+                range.size += size;
+                return;
+            }
+        }
+
+        let next = MappedRange {
+            start,
+            phys,
+            size,
+            flags,
+        };
+        if let Some(range) = current.replace(next) {
+            f(&range);
+        }
+    });
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+/// Prints every coalesced range of mappings in the active page tables.
+pub fn print_page_tables() {
+    println!("Page tables (CR3 {:#x}):", Cr3::read().0.start_address());
+    for_each_range(|range| {
+        println!(
+            "  {:#018x}-{:#018x} -> {:#014x} {:>10} KiB {:?}",
+            range.start,
+            range.start + range.size,
+            range.phys,
+            range.size / 1024,
+            range.flags
+        );
+    });
+}
+
+/// Prints the full walk for `addr`.
+pub fn print_walk(addr: VirtAddr) {
+    println!("{}", walk(addr));
+}
@@ -0,0 +1,196 @@
+//! Maps and unmaps ranges of kernel address space using the largest pages alignment allows.
+//!
+//! Large mappings (the heap, snapshot areas, windows onto physical memory) are put together from
+//! 1 GiB and 2 MiB pages wherever both the virtual and physical addresses line up, and 4 KiB pages
+//! elsewhere.  That keeps the TLB from thrashing when big regions are touched over and over.
+
+use core::ptr;
+
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, MappedFrame, Mapper, OffsetPageTable, Page, PageSize,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{phys_to_virt, supports_1gib_pages, BootInfoFrameAllocator, KernelMemory};
+
+/// Where the frames behind a mapping come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frames {
+    /// Fresh frames from the frame allocator, left as they are.
+    Fresh,
+    /// Fresh frames from the frame allocator, zeroed before they're mapped.
+    Zeroed,
+    /// The physical range starting at the given address.
+    Physical(PhysAddr),
+}
+
+fn fits<S: PageSize>(virt: VirtAddr, frames: Frames, offset: u64, remaining: u64) -> bool {
+    let phys_aligned = match frames {
+        Frames::Physical(base) => (base + offset).is_aligned(S::SIZE),
+        Frames::Fresh | Frames::Zeroed => true,
+    };
+    virt.is_aligned(S::SIZE) && phys_aligned && remaining >= S::SIZE
+}
+
+/// Converts an error from mapping a page of any size into the 4 KiB flavour callers deal in.
+fn widen<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+/// Maps a single page of size `S` at `virt`.  Returns `Ok(false)` if no frame of that size could
+/// be allocated.
+fn map_page<S: PageSize>(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    frames: Frames,
+    offset: u64,
+    flags: PageTableFlags,
+) -> Result<bool, MapToError<Size4KiB>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    BootInfoFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    let KernelMemory {
+        mapper,
+        frame_allocator,
+    } = memory;
+
+    let frame = match frames {
+        Frames::Physical(base) => PhysFrame::<S>::containing_address(base + offset),
+        Frames::Fresh | Frames::Zeroed => {
+            match FrameAllocator::<S>::allocate_frame(frame_allocator) {
+                Some(frame) => frame,
+                None => return Ok(false),
+            }
+        }
+    };
+
+    if frames == Frames::Zeroed {
+        // through the physical memory mapping, since `flags` needn't include WRITABLE
+        unsafe {
+            ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                S::SIZE as usize,
+            )
+        };
+    }
+
+    let page = Page::<S>::containing_address(virt);
+    let flags = flags | PageTableFlags::PRESENT;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(true)
+        }
+        Err(e) => {
+            if !matches!(frames, Frames::Physical(_)) {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(widen(e))
+        }
+    }
+}
+
+/// Maps `size` bytes starting at `start`, which both have to be 4 KiB aligned.
+///
+/// Returns how many bytes were mapped, which is less than `size` only if the frame allocator ran
+/// out.  When no large frame is free, the range is mapped with smaller pages instead.
+pub fn map_range(
+    memory: &mut KernelMemory,
+    start: VirtAddr,
+    size: u64,
+    frames: Frames,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let huge_pages = supports_1gib_pages();
+
+    let mut offset = 0;
+    while offset < size {
+        let virt = start + offset;
+        let remaining = size - offset;
+
+        let mapped = if huge_pages
+            && fits::<Size1GiB>(virt, frames, offset, remaining)
+            && map_page::<Size1GiB>(memory, virt, frames, offset, flags)?
+        {
+            Size1GiB::SIZE
+        } else if fits::<Size2MiB>(virt, frames, offset, remaining)
+            && map_page::<Size2MiB>(memory, virt, frames, offset, flags)?
+        {
+            Size2MiB::SIZE
+        } else if map_page::<Size4KiB>(memory, virt, frames, offset, flags)? {
+            Size4KiB::SIZE
+        } else {
+            break;
+        };
+        offset += mapped;
+    }
+
+    Ok(offset)
+}
+
+fn unmap_page<S: PageSize>(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    free_frames: bool,
+) -> Result<(), UnmapError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    BootInfoFrameAllocator: FrameDeallocator<S>,
+{
+    let (frame, flush) = memory.mapper.unmap(Page::<S>::containing_address(virt))?;
+    flush.flush();
+    if free_frames {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    }
+    Ok(())
+}
+
+/// Unmaps whatever is mapped in `[start, start + size)`, whatever size its pages are, and gives
+/// the frames back to the frame allocator if `free_frames` is set.
+///
+/// Fails with `ParentEntryHugePage` if a large page sticks out of the range, since it can't be
+/// unmapped only partially.
+pub fn unmap_range(
+    memory: &mut KernelMemory,
+    start: VirtAddr,
+    size: u64,
+    free_frames: bool,
+) -> Result<(), UnmapError> {
+    let end = start + size;
+    let mut virt = start;
+    while virt < end {
+        let (frame, offset) = match memory.mapper.translate(virt) {
+            TranslateResult::Mapped { frame, offset, .. } => (frame, offset),
+            TranslateResult::NotMapped => {
+                virt += Size4KiB::SIZE;
+                continue;
+            }
+            TranslateResult::InvalidFrameAddress(addr) => {
+                return Err(UnmapError::InvalidFrameAddress(addr))
+            }
+        };
+
+        let page_start = virt - offset;
+        if page_start < start || page_start + frame.size() > end {
+            return Err(UnmapError::ParentEntryHugePage);
+        }
+
+        match frame {
+            MappedFrame::Size4KiB(_) => unmap_page::<Size4KiB>(memory, virt, free_frames)?,
+            MappedFrame::Size2MiB(_) => unmap_page::<Size2MiB>(memory, virt, free_frames)?,
+            MappedFrame::Size1GiB(_) => unmap_page::<Size1GiB>(memory, virt, free_frames)?,
+        }
+        virt = page_start + frame.size();
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Remaps the kernel image so that no page is both writable and executable.
+//!
+//! The permissions come from the kernel's own program headers, which the linker loads at the start
+//! of the image (at `__ehdr_start`):  each loadable segment gets exactly what its flags ask for,
+//! so every section the linker emits is covered, whatever it's called.  The part of the writable
+//! segment that `PT_GNU_RELRO` marks (`.data.rel.ro`, `.got` and the like) is made read-only, since
+//! the kernel isn't relocated at run time.  A page shared by two segments gets the union of their
+//! permissions, which is reported if that makes it writable and executable.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB, Translate};
+use x86_64::VirtAddr;
+
+use crate::elf::{self, Segment, PF_W, PF_X, PT_GNU_RELRO, PT_LOAD};
+use crate::memory::kernel_memory;
+use crate::{eprintln, println};
+
+extern "C" {
+    // Defined by the linker at the kernel's ELF file header
+    static __ehdr_start: u8;
+    // Symbols defined in eh_frame.ld
+    static __text_start: u8;
+    static __text_end: u8;
+    static __bss_end: u8;
+}
+
+/// How far from the stack pointer to look for the end of the boot stack.
+const MAX_STACK_PAGES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Access {
+    writable: bool,
+    executable: bool,
+}
+
+impl Access {
+    fn flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// The kernel's program headers.
+///
+/// Panics if the loadable segments don't cover everything from the file header to the end of
+/// `.bss`, or `.text` isn't executable, which would mean the headers weren't read right and part
+/// of the image would keep the permissions the bootloader gave it.
+fn kernel_segments() -> Vec<Segment> {
+    let segments = unsafe { elf::loaded_segments(&__ehdr_start) };
+
+    let (image_start, image_end) = unsafe {
+        (
+            &__ehdr_start as *const u8 as u64,
+            &__bss_end as *const u8 as u64,
+        )
+    };
+    let loadable: Vec<&Segment> = segments
+        .iter()
+        .filter(|segment| segment.kind == PT_LOAD)
+        .collect();
+    let mut page = image_start & !0xfff;
+    while page < image_end {
+        assert!(
+            loadable.iter().any(|segment| {
+                segment.vaddr & !0xfff <= page && page < segment.vaddr + segment.memory_size
+            }),
+            "W^X: no kernel segment covers {:#x}",
+            page
+        );
+        page += 4096;
+    }
+
+    let (text_start, text_end) = unsafe {
+        (
+            &__text_start as *const u8 as u64,
+            &__text_end as *const u8 as u64,
+        )
+    };
+    assert!(
+        loadable.iter().any(|segment| segment.flags & PF_X != 0
+            && segment.contains(text_start)
+            && segment.contains(text_end - 1)),
+        "W^X: .text isn't in an executable segment"
+    );
+    segments
+}
+
+/// The pages of the stack we're currently running on, found by walking outwards from the stack
+/// pointer until hitting an unmapped page.
+fn boot_stack_pages(mapper: &impl Translate) -> (Page, Page) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let is_mapped = |page: Page| mapper.translate_addr(page.start_address()).is_some();
+
+    let mut low = current;
+    let mut high = current;
+    for _ in 0..MAX_STACK_PAGES {
+        if !is_mapped(low - 1u64) {
+            break;
+        }
+        low -= 1u64;
+    }
+    for _ in 0..MAX_STACK_PAGES {
+        if !is_mapped(high + 1u64) {
+            break;
+        }
+        high += 1u64;
+    }
+    (low, high)
+}
+
+/// Turns on NX and supervisor write protection, and remaps the kernel's segments and boot stack
+/// with the least permissions they need.
+///
+/// Requires the heap, and must be called before anything depends on writing to read-only
+/// sections.
+pub fn enforce_wx() {
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+
+    let segments = kernel_segments();
+    let mut pages: BTreeMap<Page, Access> = BTreeMap::new();
+    for segment in segments.iter().filter(|segment| segment.kind == PT_LOAD) {
+        if segment.memory_size == 0 {
+            continue;
+        }
+        let first = Page::containing_address(VirtAddr::new(segment.vaddr));
+        let last = Page::containing_address(VirtAddr::new(segment.vaddr + segment.memory_size - 1));
+        for page in Page::range_inclusive(first, last) {
+            let merged = pages.entry(page).or_default();
+            merged.writable |= segment.flags & PF_W != 0;
+            merged.executable |= segment.flags & PF_X != 0;
+        }
+    }
+    // only whole pages, since the rest of a partly covered page is ordinary writable data
+    for relro in segments
+        .iter()
+        .filter(|segment| segment.kind == PT_GNU_RELRO)
+    {
+        let start = VirtAddr::new(relro.vaddr).align_up(4096u64);
+        let end = VirtAddr::new(relro.vaddr + relro.memory_size).align_down(4096u64);
+        if start >= end {
+            continue;
+        }
+        let last = Page::containing_address(end - 1u64);
+        for page in Page::range_inclusive(Page::containing_address(start), last) {
+            if let Some(access) = pages.get_mut(&page) {
+                access.writable = false;
+            }
+        }
+    }
+
+    let (stack_low, stack_high) = boot_stack_pages(&kernel_memory().mapper);
+    for page in Page::range_inclusive(stack_low, stack_high) {
+        pages.entry(page).or_insert(Access {
+            writable: true,
+            executable: false,
+        });
+    }
+
+    // nothing below may allocate, since growing the heap needs the kernel memory lock
+    let mut memory = kernel_memory();
+    let mut remapped = 0;
+    for (page, access) in pages {
+        if access.writable && access.executable {
+            eprintln!(
+                "WARNING: page {:?} holds both code and writable data;  leaving it W+X",
+                page.start_address()
+            );
+        }
+
+        match unsafe { memory.mapper.update_flags(page, access.flags()) } {
+            Ok(flush) => {
+                flush.flush();
+                remapped += 1;
+            }
+            Err(e) => {
+                eprintln!(
+                    "WARNING: could not remap {:?}: {:?}",
+                    page.start_address(),
+                    e
+                );
+            }
+        }
+    }
+
+    println!("W^X: remapped {} kernel pages", remapped);
+}
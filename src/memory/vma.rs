@@ -0,0 +1,249 @@
+//! Hands out kernel virtual address ranges and maps them.
+//!
+//! Instead of picking addresses by hand, code asks for a region of some size with the flags it
+//! needs and gets back where it was put.  Regions never overlap and are separated by an unmapped
+//! guard page, so running off the end of one faults instead of corrupting the next.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use conquer_once::spin::Lazy;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::{align_up, PhysAddr, VirtAddr};
+
+use crate::concurrency::mutex::Mutex;
+use crate::memory::mapping::{self, Frames};
+use crate::memory::{kernel_memory, KernelMemory};
+use crate::println;
+
+/// The part of the address space regions are allocated from.
+pub const VMA_START: u64 = 0x_5000_0000_0000;
+pub const VMA_END: u64 = 0x_6000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+const GUARD_SIZE: u64 = PAGE_SIZE;
+
+/// What a region's pages are backed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames from the frame allocator, which are freed again on release.
+    Anonymous,
+    /// A fixed physical range, e.g. device registers.  Not freed on release.
+    Physical(PhysAddr),
+    /// Address space set aside for someone who maps it themselves, like the heap.
+    Reserved,
+}
+
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    pub name: &'static str,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// No gap in the address space is large enough.
+    NoSpace,
+    /// The requested range overlaps an existing region.
+    Overlap,
+    /// No region contains the given address.
+    NotFound,
+    MapFailed(MapToError<Size4KiB>),
+    UnmapFailed(UnmapError),
+}
+
+pub static REGIONS: Lazy<Mutex<BTreeMap<u64, Region>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Regions are aligned to the largest page size they can hold at least one of, so that they can
+/// be mapped with large pages.
+fn alignment_for(size: u64) -> u64 {
+    if size >= Size1GiB::SIZE {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+/// Returns the first suitably aligned address in the VMA window where `size` bytes (plus a guard
+/// page) fit.
+fn find_gap(regions: &BTreeMap<u64, Region>, size: u64) -> Option<u64> {
+    let align = alignment_for(size);
+    let mut candidate = VMA_START;
+    for region in regions.range(VMA_START..VMA_END).map(|(_, region)| region) {
+        if candidate + size + GUARD_SIZE <= region.start.as_u64() {
+            return Some(candidate);
+        }
+        candidate = align_up(candidate.max(region.end().as_u64() + GUARD_SIZE), align);
+    }
+
+    if candidate + size <= VMA_END {
+        Some(candidate)
+    } else {
+        None
+    }
+}
+
+fn overlaps(regions: &BTreeMap<u64, Region>, start: u64, size: u64) -> bool {
+    regions
+        .values()
+        .any(|region| start < region.end().as_u64() && region.start.as_u64() < start + size)
+}
+
+fn map_region(memory: &mut KernelMemory, region: &Region) -> Result<(), MapToError<Size4KiB>> {
+    let frames = match region.backing {
+        Backing::Anonymous => Frames::Zeroed,
+        Backing::Physical(base) => Frames::Physical(base),
+        Backing::Reserved => return Ok(()),
+    };
+
+    let mapped = mapping::map_range(memory, region.start, region.size, frames, region.flags)?;
+    if mapped < region.size {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    Ok(())
+}
+
+/// Unmaps whatever part of `region` is mapped, freeing anonymous frames.
+fn unmap_region(memory: &mut KernelMemory, region: &Region) -> Result<(), UnmapError> {
+    if region.backing == Backing::Reserved {
+        return Ok(());
+    }
+
+    let free_frames = region.backing == Backing::Anonymous;
+    mapping::unmap_range(memory, region.start, region.size, free_frames)
+}
+
+fn place(
+    size: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+    name: &'static str,
+) -> Result<VirtAddr, VmaError> {
+    let size = align_up(size, PAGE_SIZE);
+    let mut regions = REGIONS.lock();
+    let start = VirtAddr::new(find_gap(&regions, size).ok_or(VmaError::NoSpace)?);
+    let region = Region {
+        start,
+        size,
+        flags,
+        backing,
+        name,
+    };
+    regions.insert(start.as_u64(), region.clone());
+
+    // taking the kernel memory lock with interrupts enabled could deadlock against an interrupt
+    // handler which needs to grow the heap
+    let mapped = without_interrupts(|| map_region(&mut kernel_memory(), &region));
+    if let Err(e) = mapped {
+        without_interrupts(|| unmap_region(&mut kernel_memory(), &region)).ok();
+        regions.remove(&start.as_u64());
+        return Err(VmaError::MapFailed(e));
+    }
+
+    Ok(start)
+}
+
+/// Maps `size` bytes of zeroed memory somewhere in the VMA window.
+pub fn allocate(
+    size: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<VirtAddr, VmaError> {
+    place(size, flags, Backing::Anonymous, name)
+}
+
+/// Maps the physical range `[phys, phys + size)` somewhere in the VMA window, returning the
+/// address `phys` itself ended up at.  `phys` doesn't have to be page aligned.
+pub fn map_physical(
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<VirtAddr, VmaError> {
+    let base = phys.align_down(PAGE_SIZE);
+    let offset = phys - base;
+    let start = place(offset + size, flags, Backing::Physical(base), name)?;
+    Ok(start + offset)
+}
+
+/// Maps device registers:  writable, uncached and never executable.
+pub fn map_mmio(phys: PhysAddr, size: u64, name: &'static str) -> Result<VirtAddr, VmaError> {
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    map_physical(phys, size, flags, name)
+}
+
+/// Records that `[start, start + size)` is in use by someone who maps it themselves.  The range
+/// may lie outside the VMA window.
+pub fn reserve(start: VirtAddr, size: u64, name: &'static str) -> Result<(), VmaError> {
+    let mut regions = REGIONS.lock();
+    if overlaps(&regions, start.as_u64(), size) {
+        return Err(VmaError::Overlap);
+    }
+
+    regions.insert(
+        start.as_u64(),
+        Region {
+            start,
+            size,
+            flags: PageTableFlags::empty(),
+            backing: Backing::Reserved,
+            name,
+        },
+    );
+    Ok(())
+}
+
+/// Unmaps the region containing `addr` and gives its address range (and, for anonymous regions,
+/// its frames) back.
+///
+/// Any address inside the region works, so the pointer `map_physical` returned can be passed
+/// straight back even if it wasn't page aligned.
+pub fn release(addr: VirtAddr) -> Result<(), VmaError> {
+    let mut regions = REGIONS.lock();
+    let key = regions
+        .range(..=addr.as_u64())
+        .next_back()
+        .filter(|(_, region)| addr < region.end())
+        .map(|(&key, _)| key)
+        .ok_or(VmaError::NotFound)?;
+
+    let region = regions.get(&key).unwrap().clone();
+    without_interrupts(|| unmap_region(&mut kernel_memory(), &region))
+        .map_err(VmaError::UnmapFailed)?;
+    regions.remove(&key);
+    Ok(())
+}
+
+/// Returns a snapshot of every region, ordered by address.
+pub fn regions() -> Vec<Region> {
+    REGIONS.lock().values().cloned().collect()
+}
+
+pub fn print_regions() {
+    for region in regions() {
+        println!(
+            "{:#018x}-{:#018x} {:>10} {:?} {:?} {}",
+            region.start.as_u64(),
+            region.end().as_u64(),
+            region.size,
+            region.backing,
+            region.flags,
+            region.name
+        );
+    }
+}
@@ -0,0 +1,44 @@
+/// A small seedable pseudo-random number generator (wyrand).
+///
+/// Fast and good enough for fuzzing decisions, but not suitable for anything that needs real
+/// randomness.  Two generators created with the same seed always produce the same sequence.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0xa076_1d64_78bd_642f);
+        let t = (self.state as u128).wrapping_mul((self.state ^ 0xe703_7ed1_a0b4_28db) as u128);
+        ((t >> 64) ^ t) as u64
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Returns a number in `0..bound`.  `bound` must not be 0.
+    pub fn below(&mut self, bound: usize) -> usize {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as usize
+    }
+
+    /// Returns `true` with a probability of `parts_per_million / 1_000_000`.
+    pub fn chance_ppm(&mut self, parts_per_million: u32) -> bool {
+        self.below(1_000_000) < parts_per_million as usize
+    }
+}
+
+#[test_case]
+fn test_rng_is_deterministic() {
+    let mut a = Rng::new(1234);
+    let mut b = Rng::new(1234);
+    for _ in 0..100 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+    assert!((0..100).all(|_| a.below(10) < 10));
+}
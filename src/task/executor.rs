@@ -3,6 +3,8 @@ use alloc::boxed::Box;
 use alloc::collections::LinkedList;
 use core::arch::asm;
 use core::pin::Pin;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use core::sync::atomic::Ordering::SeqCst;
 use core::task::Waker;
 
@@ -11,12 +13,14 @@ use crossbeam_queue::ArrayQueue;
 
 use crate::{INITIALISED, println};
 use crate::concurrency::mutex::{Mutex, MutexGuard};
+use crate::deterministic;
 use crate::interrupts::{
     attach_new_interrupt_handler, InterruptFrame, InterruptIndex, PICS, StandardContext,
 };
-use crate::task::PreemptiveTask;
+use crate::memory::address_space::{self, AddressSpace};
+use crate::task::{ContextState, PreemptiveTask};
 
-use super::TaskId;
+use super::{TaskId, NO_TASK};
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Pin<Box<PreemptiveTask>>>,
@@ -27,10 +31,16 @@ pub struct Executor {
 const TASK_DONE_INTERRUPT: u8 = 0;
 const YIELD_INTERRUPT: u8 = 1;
 
-fn _on_task_done(_: &mut StandardContext) {
+fn _on_task_done(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    if frame.from_user_mode() {
+        let rbp = unsafe { frame.saved_rbp(false) };
+        exit_current_task(frame, ctx, rbp);
+        return;
+    }
+
     let mut guard = INSTANCE.get().unwrap().lock();
     if let Some(task) = guard.active_task {
-        guard.tasks.remove(&task);
+        guard.remove(task);
     }
 
     x86_64::instructions::hlt();
@@ -69,29 +79,195 @@ pub extern "C" fn timer_interrupt_handler(
         return;
     }
 
+    preempt(interrupt_frame, ctx);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    }
+}
+
+/// Switches to the next task in the queue, putting the running one at the back, unless preemption
+/// is off or the executor is busy.  What the timer does on each tick, and what `deterministic` mode
+/// does every so many instructions instead.
+pub fn preempt(interrupt_frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+    // without preemption, whichever task runs first keeps running
+    if !PREEMPTION.load(SeqCst) && current_task().is_some() {
+        return;
+    }
+
     if let Some(Some(mut guard)) = INSTANCE.get().map(|x| x.try_lock()) {
+        let rbp = unsafe { interrupt_frame.saved_rbp(false) };
         if let Some(current_task) = guard.active_task.take() {
             if let Some(current_task_) = guard.tasks.get_mut(&current_task) {
                 current_task_.cont = Some((*interrupt_frame, *ctx));
+                current_task_.rbp = *rbp;
                 guard.task_queue.push_back(current_task);
             }
         }
 
-        guard.scheduler_loop(interrupt_frame, ctx);
+        guard.scheduler_loop(interrupt_frame, ctx, rbp);
     };
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+pub static INSTANCE: OnceCell<Mutex<Executor>> = OnceCell::uninit();
+
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+
+/// The task that is currently running, or `None` before the scheduler has picked one.
+///
+/// Doesn't take the executor lock, so it can be used from interrupt handlers and the allocator.
+pub fn current_task() -> Option<TaskId> {
+    match CURRENT_TASK.load(SeqCst) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
     }
 }
 
-pub static INSTANCE: OnceCell<Mutex<Executor>> = OnceCell::uninit();
+static PREEMPTION: AtomicBool = AtomicBool::new(true);
+
+/// Turns switching tasks on the timer interrupt on or off.  While it's off, the running task runs
+/// until it turns it back on.
+pub fn set_preemption(enabled: bool) {
+    PREEMPTION.store(enabled, SeqCst);
+}
+
+/// Whether tasks are switched on the timer interrupt.
+pub fn preemption() -> bool {
+    PREEMPTION.load(SeqCst)
+}
+
+/// Waits a moment for other tasks, e.g. in a loop waiting for one of them to finish:  halts until
+/// the next interrupt, or spins in `deterministic` mode, where a halted CPU retires no instructions
+/// and so never gets switched away from.
+pub fn idle() {
+    match deterministic::enabled() {
+        true => core::hint::spin_loop(),
+        false => x86_64::instructions::hlt(),
+    }
+}
+
+/// How much garbage can wait for the reaper.
+const MAX_GARBAGE: usize = 64;
+
+/// What's left of ended tasks, waiting for `reaper` to drop it.
+static GARBAGE: OnceCell<ArrayQueue<Box<dyn Send>>> = OnceCell::uninit();
+
+/// Has the reaper task drop `garbage`.  For what tasks leave behind when they end, which would
+/// otherwise be freed in the interrupt handler that ended them, on the stack of the task itself or
+/// the shared user-mode one, with interrupts off.  Doesn't allocate.
+///
+/// Drops `garbage` straight away if the reaper is behind, or hasn't started yet.
+pub fn reap(garbage: Box<dyn Send>) {
+    if let Some(queue) = GARBAGE.get() {
+        // a full queue hands the garbage back
+        let _ = queue.push(garbage);
+    }
+}
+
+/// The reaper task:  drops the garbage `reap` is given, whenever it gets a turn.
+fn reaper() {
+    let queue = GARBAGE.get().unwrap();
+    loop {
+        while let Some(garbage) = queue.pop() {
+            drop(garbage);
+        }
+        idle();
+    }
+}
+
+/// Ends the running task and switches to the next one, from a handler for an interrupt that came
+/// from user mode.  `rbp` is where the trampoline saved the interrupted `rbp`.
+///
+/// Only for tasks interrupted in user mode, whose interrupt frames are on the TSS stack:  a kernel
+/// task's frame is on its own stack, which ending it frees.
+pub fn exit_current_task(frame: &mut InterruptFrame, ctx: &mut StandardContext, rbp: &mut u64) {
+    let mut guard = INSTANCE.get().unwrap().lock();
+    if let Some(task) = guard.active_task.take() {
+        // the reaper can't free its address space while it's active
+        address_space::activate_kernel();
+        guard.remove(task);
+    }
+    CURRENT_TASK.store(NO_TASK, SeqCst);
+
+    if !guard.scheduler_loop(frame, ctx, rbp) {
+        panic!("no task left to run");
+    }
+}
+
+/// Called from the timer interrupt just before a task is resumed, with the task that was running
+/// until now (if any) and the one about to run.  They may be the same task.
+///
+/// Hooks run with interrupts disabled and the executor locked, so they mustn't block.
+pub type SwitchHook = fn(Option<TaskId>, TaskId);
+
+const MAX_SWITCH_HOOKS: usize = 8;
+const NO_HOOK: AtomicUsize = AtomicUsize::new(0);
+static SWITCH_HOOKS: [AtomicUsize; MAX_SWITCH_HOOKS] = [NO_HOOK; MAX_SWITCH_HOOKS];
+
+/// Registers a hook to be run on every task switch.
+///
+/// Panics if `MAX_SWITCH_HOOKS` hooks are already registered.
+pub fn register_switch_hook(hook: SwitchHook) {
+    let registered = SWITCH_HOOKS
+        .iter()
+        .any(|slot| slot.compare_exchange(0, hook as usize, SeqCst, SeqCst).is_ok());
+    assert!(registered, "too many task switch hooks");
+}
+
+fn run_switch_hooks(from: Option<TaskId>, to: TaskId) {
+    for slot in SWITCH_HOOKS.iter() {
+        match slot.load(SeqCst) {
+            0 => break,
+            hook => unsafe { mem::transmute::<usize, SwitchHook>(hook)(from, to) },
+        }
+    }
+}
+
+/// Runs `f` on the running task's address space, if it's running a program in user mode.
+///
+/// Locks the executor, so it's for handlers of interrupts and system calls from user mode, which
+/// can't come while a kernel task has it locked.
+pub fn with_current_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let mut guard = INSTANCE.get()?.lock();
+    let task = guard.active_task?;
+    let address_space = guard.tasks.get_mut(&task)?.address_space.as_mut()?;
+    Some(f(address_space))
+}
+
+/// Called with the id of every task that ends, however it ends, once it's been removed.
+///
+/// Hooks run with the executor locked, often from interrupt handlers, so they mustn't block.
+pub type ExitHook = fn(TaskId);
+
+const MAX_EXIT_HOOKS: usize = 8;
+static EXIT_HOOKS: [AtomicUsize; MAX_EXIT_HOOKS] = [NO_HOOK; MAX_EXIT_HOOKS];
+
+/// Registers a hook to be run whenever a task ends.
+///
+/// Panics if `MAX_EXIT_HOOKS` hooks are already registered.
+pub fn register_exit_hook(hook: ExitHook) {
+    let registered = EXIT_HOOKS
+        .iter()
+        .any(|slot| slot.compare_exchange(0, hook as usize, SeqCst, SeqCst).is_ok());
+    assert!(registered, "too many task exit hooks");
+}
+
+fn run_exit_hooks(task: TaskId) {
+    for slot in EXIT_HOOKS.iter() {
+        match slot.load(SeqCst) {
+            0 => break,
+            hook => unsafe { mem::transmute::<usize, ExitHook>(hook)(task) },
+        }
+    }
+}
 
 pub fn init() {
     INSTANCE.get_or_init(|| Mutex::new(Executor::new()));
-    extern "C" fn handle(_: &mut InterruptFrame, ctx: &mut StandardContext) {
-        _on_task_done(ctx);
+    GARBAGE.init_once(|| ArrayQueue::new(MAX_GARBAGE));
+    INSTANCE.get().unwrap().lock().spawn(reaper);
+    extern "C" fn handle(frame: &mut InterruptFrame, ctx: &mut StandardContext) {
+        _on_task_done(frame, ctx);
     }
     attach_new_interrupt_handler(TASK_DONE_INTERRUPT, handle);
     attach_new_interrupt_handler(YIELD_INTERRUPT, timer_interrupt_handler);
@@ -124,18 +300,63 @@ impl Executor {
         Some(id)
     }
 
+    /// Adds a task that runs a program in user mode, starting from `entry`.
+    pub fn spawn_user(
+        &mut self,
+        address_space: AddressSpace,
+        entry: ContextState,
+    ) -> Option<TaskId> {
+        let id = TaskId::new();
+        self.tasks
+            .insert(id, Box::pin(PreemptiveTask::new_user(address_space, entry)));
+        self.task_queue.push_back(id);
+        Some(id)
+    }
+
+    /// Ends a task that isn't the running one, e.g. a program that's taking too long.
+    pub fn kill(&mut self, id: TaskId) {
+        assert_ne!(Some(id), self.active_task, "killing the running task");
+        self.task_queue = mem::take(&mut self.task_queue)
+            .into_iter()
+            .filter(|&queued| queued != id)
+            .collect();
+        self.remove(id);
+    }
+
+    /// Forgets a task, and hands it to the reaper to free.
+    fn remove(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.remove(&id) {
+            reap(Pin::into_inner(task));
+            run_exit_hooks(id);
+        }
+    }
+
+    /// Switches to the next task in the queue by overwriting the interrupted context, including
+    /// the `rbp` the trampoline saved.  Returns whether there was one.
     pub fn scheduler_loop(
         mut self: MutexGuard<Self>,
         ictx: &mut InterruptFrame,
         sctx: &mut StandardContext,
-    ) {
+        rbp: &mut u64,
+    ) -> bool {
         if let Some(next_task) = self.task_queue.pop_front() &&
         let Some(task) = self.tasks.get_mut(&next_task) &&
         let Some(ctx) = task.poll()
         {
+            match &task.address_space {
+                Some(address_space) => address_space.activate(),
+                None => address_space::activate_kernel(),
+            }
+            *rbp = task.rbp;
+
+            let previous = current_task();
             self.active_task = Some(next_task);
+            CURRENT_TASK.store(next_task.0, SeqCst);
+            run_switch_hooks(previous, next_task);
             (*ictx, *sctx) = ctx;
+            return true;
         }
+        false
     }
 }
 
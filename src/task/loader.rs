@@ -0,0 +1,317 @@
+//! Loads statically linked x86_64 ELF executables and runs them as tasks in user mode.
+//!
+//! Each program gets its own `AddressSpace`, with its `PT_LOAD` segments mapped with the
+//! permissions their program headers ask for and a stack laid out the way the SysV ABI describes
+//! it at process entry:  `argc`, `argv`, an empty environment and the auxiliary vector.
+//! Static-PIE executables are loaded at `PIE_BASE` with their relative relocations applied, and
+//! anything else where it was linked.
+//!
+//! Programs come from the `opt/barefuzz/program` fw_cfg file, with arguments from
+//! `opt/barefuzz/program-args`, or from the host tool's `run` command.  They run as `linux`
+//! processes, making Linux system calls, and end themselves with `exit` (or `int 0x80` and
+//! `rax = 0`).  The kernel ends a program that faults.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{fmt, iter};
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+use x86_64::{align_down, align_up, VirtAddr};
+
+use crate::elf::{Elf, ElfError, PF_W, PF_X, R_X86_64_NONE, R_X86_64_RELATIVE};
+use crate::interrupts::{InterruptFrame, StandardContext};
+use crate::linux::{self, Process};
+use crate::memory::address_space::AddressSpace;
+use crate::rng::Rng;
+use crate::task::executor::Executor;
+use crate::task::{ContextState, TaskId};
+use crate::{fw_cfg, gdt, println, time};
+
+/// Where static-PIE executables are loaded.
+pub const PIE_BASE: u64 = 0x_2000_0000_0000;
+/// The end of the stack, which grows down from here.
+pub const STACK_TOP: u64 = 0x_7fff_ffff_f000;
+pub const STACK_SIZE: u64 = 128 * 1024;
+/// How much of the stack the arguments and auxiliary vector may take up.
+const MAX_ARGUMENTS_SIZE: u64 = 32 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+// auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The executable has no loadable segments.
+    NothingToLoad,
+    /// A segment, the entry point or a relocation is outside the part of the address space
+    /// programs get, or outside what's loaded.
+    OutOfRange(u64),
+    /// A static-PIE relocation other than `R_X86_64_RELATIVE`.
+    UnsupportedRelocation(u32),
+    ArgumentsTooLong,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        LoadError::Map(error)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Elf(error) => write!(f, "{}", error),
+            LoadError::NothingToLoad => write!(f, "executable has no loadable segments"),
+            LoadError::OutOfRange(addr) => write!(f, "{:#x} is out of range", addr),
+            LoadError::UnsupportedRelocation(kind) => {
+                write!(f, "relocation type {} isn't supported", kind)
+            }
+            LoadError::ArgumentsTooLong => write!(f, "arguments are too long"),
+            LoadError::Map(MapToError::FrameAllocationFailed) => write!(f, "out of memory"),
+            LoadError::Map(MapToError::PageAlreadyMapped(_)) => {
+                write!(f, "executable overlaps the kernel")
+            }
+            LoadError::Map(error) => write!(f, "couldn't map the executable: {:?}", error),
+        }
+    }
+}
+
+/// A program that's been loaded and is ready to run.
+#[derive(Debug)]
+pub struct Program {
+    address_space: AddressSpace,
+    entry: ContextState,
+    /// The end of the executable, where its heap starts.
+    brk: u64,
+}
+
+impl Program {
+    /// Adds a task running the program, with nothing on its standard input and its output printed.
+    pub fn spawn(self, executor: &mut Executor) -> TaskId {
+        let process = self.process(Vec::new(), true);
+        self.spawn_as(executor, process)
+    }
+
+    /// The program's memory, e.g. for patching its code before it runs.
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// A process to run the program as, reading `stdin` and printing its output if `echo` is set.
+    pub fn process(&self, stdin: Vec<u8>, echo: bool) -> Process {
+        Process::new(self.brk, stdin, echo)
+    }
+
+    /// Adds a task running the program as `process`.
+    pub fn spawn_as(self, executor: &mut Executor, process: Process) -> TaskId {
+        let task = executor
+            .spawn_user(self.address_space, self.entry)
+            .expect("couldn't spawn a user task");
+        // the executor is locked, so the task can't start before it has its process
+        linux::attach(task, process);
+        task
+    }
+}
+
+fn page_flags(segment_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    flags.set(PageTableFlags::WRITABLE, segment_flags & PF_W != 0);
+    flags.set(PageTableFlags::NO_EXECUTE, segment_flags & PF_X == 0);
+    flags
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut rng = Rng::new(time::ticks());
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&rng.next_u64().to_le_bytes());
+    bytes[8..].copy_from_slice(&rng.next_u64().to_le_bytes());
+    bytes
+}
+
+/// Puts `args` and `auxv` on the stack as a program expects to find them, and returns the stack
+/// pointer to start it with.
+fn build_stack(
+    address_space: &AddressSpace,
+    args: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<u64, LoadError> {
+    // argc, argv and the environment, each ended by a null, then auxv plus AT_RANDOM and AT_NULL
+    let words = 1 + args.len() + 1 + 1 + 2 * (auxv.len() + 2);
+    let strings: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    if (words * 8 + strings + 16 + 16) as u64 > MAX_ARGUMENTS_SIZE {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let mut top = STACK_TOP;
+    let mut push = |bytes: &[u8]| {
+        top -= bytes.len() as u64;
+        address_space.write(VirtAddr::new(top), bytes);
+        top
+    };
+    let random = push(&random_bytes());
+    let argv: Vec<u64> = args
+        .iter()
+        .map(|arg| {
+            push(&[0]);
+            push(arg.as_bytes())
+        })
+        .collect();
+
+    let mut vector = vec![args.len() as u64];
+    vector.extend(&argv);
+    vector.push(0);
+    // no environment
+    vector.push(0);
+    for &(kind, value) in auxv.iter().chain(&[(AT_RANDOM, random), (AT_NULL, 0)]) {
+        vector.extend([kind, value]);
+    }
+
+    let stack_pointer = align_down(top - vector.len() as u64 * 8, 16);
+    let bytes: Vec<u8> = vector.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(stack_pointer), &bytes);
+    Ok(stack_pointer)
+}
+
+/// What `load` adds to the addresses in `elf` to get where they end up:  0 unless it's a
+/// static-PIE executable.
+pub fn load_bias(elf: &Elf) -> Result<u64, LoadError> {
+    let (start, _) = elf.address_range().ok_or(LoadError::NothingToLoad)?;
+    match elf.position_independent {
+        true => PIE_BASE
+            .checked_sub(align_down(start, PAGE_SIZE))
+            .ok_or(LoadError::OutOfRange(start)),
+        false => Ok(0),
+    }
+}
+
+/// Loads the executable in `image` into a new address space, to be started with `args` as its
+/// `argv`.
+pub fn load(image: &[u8], args: &[&str]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(image)?;
+    let (_, end) = elf.address_range().ok_or(LoadError::NothingToLoad)?;
+    let bias = load_bias(&elf)?;
+    // the stack goes above everything else
+    if bias
+        .checked_add(end)
+        .map_or(true, |end| end > STACK_TOP - STACK_SIZE)
+    {
+        return Err(LoadError::OutOfRange(end));
+    }
+    let loaded = |vaddr: u64, len: u64| match vaddr.checked_add(len - 1) {
+        Some(last) => elf
+            .loadable()
+            .any(|segment| segment.contains(vaddr) && segment.contains(last)),
+        None => false,
+    };
+
+    let mut address_space = AddressSpace::new()?;
+    for segment in elf.loadable() {
+        let vaddr = bias + segment.vaddr;
+        let pages = align_down(vaddr, PAGE_SIZE)..align_up(vaddr + segment.memory_size, PAGE_SIZE);
+        address_space.map(
+            VirtAddr::new(pages.start),
+            pages.end - pages.start,
+            page_flags(segment.flags),
+        )?;
+        address_space.write(VirtAddr::new(vaddr), elf.segment_data(segment));
+    }
+
+    if elf.position_independent {
+        for relocation in elf.relocations()? {
+            match relocation.kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE if loaded(relocation.offset, 8) => {
+                    let value = bias.wrapping_add(relocation.addend as u64);
+                    address_space.write(
+                        VirtAddr::new(bias + relocation.offset),
+                        &value.to_le_bytes(),
+                    );
+                }
+                R_X86_64_RELATIVE => return Err(LoadError::OutOfRange(relocation.offset)),
+                kind => return Err(LoadError::UnsupportedRelocation(kind)),
+            }
+        }
+    }
+
+    if !loaded(elf.entry, 1) {
+        return Err(LoadError::OutOfRange(elf.entry));
+    }
+    let entry = bias + elf.entry;
+
+    address_space.map(
+        VirtAddr::new(STACK_TOP - STACK_SIZE),
+        STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let program_headers = elf.program_headers_vaddr().map_or(0, |vaddr| bias + vaddr);
+    let auxv = [
+        (AT_PHDR, program_headers),
+        (AT_PHENT, elf.program_header_size),
+        (AT_PHNUM, elf.segments.len() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0),
+    ];
+    let stack_pointer = build_stack(&address_space, args, &auxv)?;
+
+    let selectors = gdt::selectors();
+    let frame = InterruptFrame {
+        instruction_pointer: entry,
+        code_segment: selectors.user_code_selector.0 as u64,
+        stack_pointer,
+        stack_segment: selectors.user_data_selector.0 as u64,
+        ..Default::default()
+    };
+    // rdx would be a function for the program to register with atexit, if there was one
+    let ctx = StandardContext::default();
+    Ok(Program {
+        address_space,
+        entry: (frame, ctx),
+        brk: bias + end,
+    })
+}
+
+/// Loads the program in the `opt/barefuzz/program` fw_cfg file, if there is one, with the
+/// whitespace separated arguments in `opt/barefuzz/program-args`.
+pub fn from_fw_cfg() -> Option<Program> {
+    let image = fw_cfg::read_file("opt/barefuzz/program")?;
+    let args = fw_cfg::read_string("opt/barefuzz/program-args").unwrap_or_default();
+    let args: Vec<&str> = iter::once("program")
+        .chain(args.split_whitespace())
+        .collect();
+
+    match load(&image, &args) {
+        Ok(program) => Some(program),
+        Err(error) => {
+            println!("Couldn't load opt/barefuzz/program: {}", error);
+            None
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::interrupts::{InterruptFrame, StandardContext};
+use crate::memory::address_space::AddressSpace;
 use crate::println;
 use crate::task::executor::end_curr_task;
 use alloc::boxed::Box;
@@ -16,6 +17,8 @@ use core::{
 
 pub mod executor;
 pub mod keyboard;
+pub mod loader;
+pub mod slots;
 
 const STACK_SIZE: usize = 8192;
 
@@ -29,7 +32,11 @@ pub struct PreemptiveTask {
     stack: Pin<Box<UnsafeCell<[u8; STACK_SIZE]>>>,
 
     cont: Option<ContextState>,
+    /// The task's `rbp` while it isn't running, which the trampolines keep apart from `cont`.
+    rbp: u64,
     entrypoint: fn(),
+    /// For tasks running a program in user mode.
+    address_space: Option<AddressSpace>,
 }
 
 extern "C" fn run_task(task: Pin<&PreemptiveTask>) {
@@ -83,6 +90,18 @@ impl PreemptiveTask {
             entrypoint,
             stack: pinned_array_of_default::<u8, STACK_SIZE>(),
             cont: None,
+            rbp: 0,
+            address_space: None,
+        }
+    }
+
+    /// A task running a program in user mode, which starts from `entry` rather than an
+    /// entrypoint.
+    fn new_user(address_space: AddressSpace, entry: ContextState) -> Self {
+        Self {
+            cont: Some(entry),
+            address_space: Some(address_space),
+            ..Self::new(|| unreachable!("user tasks start from their entry context"))
         }
     }
 
@@ -108,4 +127,11 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
 }
+
+/// Stands in for a task's id where there is no task, e.g. in an `AtomicU64`.
+const NO_TASK: u64 = u64::MAX;
@@ -0,0 +1,101 @@
+//! Per-task state that has to be found without taking a lock.
+//!
+//! Coverage maps, comparison logs, recovery points, guests and processes all belong to a task, and
+//! are looked up from places that can't lock:  instrumentation callbacks, task switch hooks and
+//! interrupt handlers.  A `TaskSlots` is a fixed array of slots, each tying a task to a pointer,
+//! that is searched and claimed with atomics alone.  It doesn't own what it points to:  whoever
+//! fills a slot frees its value, typically from an exit hook so nothing outlives its task.
+
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use super::{TaskId, NO_TASK};
+
+struct Slot {
+    task: AtomicU64,
+    value: AtomicPtr<()>,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    task: AtomicU64::new(NO_TASK),
+    value: AtomicPtr::new(ptr::null_mut()),
+};
+
+/// Up to `N` tasks' pointers to a `T`.
+pub struct TaskSlots<T, const N: usize> {
+    slots: [Slot; N],
+    _value: PhantomData<*mut T>,
+}
+
+// only pointers are shared, and whoever dereferences them answers for it
+unsafe impl<T, const N: usize> Sync for TaskSlots<T, N> {}
+
+impl<T, const N: usize> TaskSlots<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [EMPTY_SLOT; N],
+            _value: PhantomData,
+        }
+    }
+
+    fn slot_for(&self, task: TaskId) -> Option<&Slot> {
+        self.slots
+            .iter()
+            .find(|slot| slot.task.load(Ordering::SeqCst) == task.as_u64())
+    }
+
+    /// `task`'s value, if it has one.
+    pub fn get(&self, task: TaskId) -> Option<*mut T> {
+        let value = self.slot_for(task)?.value.load(Ordering::SeqCst);
+        (!value.is_null()).then(|| value as *mut T)
+    }
+
+    /// Puts `value` in a free slot for `task`, which mustn't have one already.  Hands `value` back
+    /// if all `N` slots are taken.
+    pub fn insert(&self, task: TaskId, value: *mut T) -> Result<(), *mut T> {
+        let slot = self.slots.iter().find(|slot| {
+            slot.task
+                .compare_exchange(NO_TASK, task.as_u64(), Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        match slot {
+            Some(slot) => {
+                slot.value.store(value as *mut (), Ordering::SeqCst);
+                Ok(())
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Empties `task`'s slot, returning its value.
+    pub fn remove(&self, task: TaskId) -> Option<*mut T> {
+        let slot = self.slot_for(task)?;
+        let value = slot.value.swap(ptr::null_mut(), Ordering::SeqCst);
+        slot.task.store(NO_TASK, Ordering::SeqCst);
+        (!value.is_null()).then(|| value as *mut T)
+    }
+}
+
+#[test_case]
+fn test_task_slots() {
+    let slots: TaskSlots<u32, 2> = TaskSlots::new();
+    let (a, b, c) = (TaskId(1000), TaskId(1001), TaskId(1002));
+    let (mut one, mut two, mut three) = (1u32, 2u32, 3u32);
+    let (one, two, three) = (
+        &mut one as *mut u32,
+        &mut two as *mut u32,
+        &mut three as *mut u32,
+    );
+
+    assert_eq!(slots.insert(a, one), Ok(()));
+    assert_eq!(slots.insert(b, two), Ok(()));
+    assert_eq!(slots.insert(c, three), Err(three));
+    assert_eq!(slots.get(b), Some(two));
+
+    assert_eq!(slots.remove(a), Some(one));
+    assert_eq!(slots.get(a), None);
+    assert_eq!(slots.remove(a), None);
+    assert_eq!(slots.insert(c, three), Ok(()));
+    assert_eq!(slots.get(c), Some(three));
+}
@@ -0,0 +1,95 @@
+//! Keeping time with the TSC.
+//!
+//! `init` measures the TSC's frequency against the PIT, and marks the point `since_boot_ns`
+//! counts from.  The TSC is assumed to be invariant, which it is on anything recent and in QEMU.
+//!
+//! In `deterministic` mode the clock is virtual instead:  a tick is a retired instruction, and
+//! ticks are taken to come at `VIRTUAL_HZ`.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::deterministic;
+
+/// The PIT's input clock.
+const PIT_HZ: u64 = 1_193_182;
+/// How long the TSC is measured for by `init`.
+const CALIBRATION_MS: u64 = 10;
+/// How fast the virtual clock pretends to run:  an instruction a nanosecond.
+const VIRTUAL_HZ: u64 = 1_000_000_000;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static BOOT: AtomicU64 = AtomicU64::new(0);
+static VIRTUAL: AtomicBool = AtomicBool::new(false);
+
+/// Measures the TSC frequency and starts the clock.  Must be called before anything measures
+/// time.
+pub fn init() {
+    TSC_HZ.store(measure_tsc_hz(), Ordering::SeqCst);
+    BOOT.store(ticks(), Ordering::SeqCst);
+}
+
+/// Counts TSC ticks while PIT channel 2 counts down `CALIBRATION_MS`.
+fn measure_tsc_hz() -> u64 {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    let count = (PIT_HZ * CALIBRATION_MS / 1000) as u16;
+
+    unsafe {
+        // gate channel 2 on, with the speaker off
+        let control = gate.read() & !0x02;
+        gate.write(control & !0x01);
+        // channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        gate.write(control | 0x01);
+        let start = _rdtsc();
+        // bit 5 is channel 2's output, which goes high when the count reaches zero
+        while gate.read() & 0x20 == 0 {}
+        let end = _rdtsc();
+        gate.write(control);
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+/// Switches to the virtual clock, counting from zero, when `deterministic` mode is turned on.
+pub fn use_virtual_clock() {
+    TSC_HZ.store(VIRTUAL_HZ, Ordering::SeqCst);
+    BOOT.store(0, Ordering::SeqCst);
+    VIRTUAL.store(true, Ordering::SeqCst);
+}
+
+/// The TSC, or the number of instructions retired on the virtual clock.
+pub fn ticks() -> u64 {
+    match VIRTUAL.load(Ordering::Relaxed) {
+        true => deterministic::instructions_retired(),
+        false => unsafe { _rdtsc() },
+    }
+}
+
+/// Converts a number of TSC ticks to milliseconds.
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => 0,
+        hz => ticks / (hz / 1000).max(1),
+    }
+}
+
+/// Converts a number of TSC ticks to nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => 0,
+        hz => (ticks as u128 * 1_000_000_000 / hz as u128) as u64,
+    }
+}
+
+/// Nanoseconds since `init`.
+pub fn since_boot_ns() -> u64 {
+    ticks_to_ns(ticks().saturating_sub(BOOT.load(Ordering::Relaxed)))
+}
@@ -3,7 +3,10 @@ use core::{
     sync::atomic::{AtomicPtr, Ordering},
 };
 
+use x86_64::PhysAddr;
+
 use super::LineStsFlags;
+use crate::memory::vma::{self, VmaError};
 
 pub struct MmioSerialPort {
     data: AtomicPtr<u8>,
@@ -27,6 +30,15 @@ impl MmioSerialPort {
         }
     }
 
+    /// Maps the registers at physical address `phys_base` through the VMA manager and creates a
+    /// port on top of them.
+    ///
+    /// Unsafe for the same reasons as `new`:  there must be a UART at that address.
+    pub unsafe fn map(phys_base: PhysAddr) -> Result<Self, VmaError> {
+        let base = vma::map_mmio(phys_base, 8, "uart")?;
+        Ok(Self::new(base.as_u64() as usize))
+    }
+
     pub fn init(&mut self) {
         let self_int_en = self.int_en.load(Ordering::Relaxed);
         let self_line_ctrl = self.line_ctrl.load(Ordering::Relaxed);
@@ -91,6 +103,17 @@ impl MmioSerialPort {
             self_data.read()
         }
     }
+    /// Returns the next received byte, if there is one, without waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        let self_data = self.data.load(Ordering::Relaxed);
+        unsafe {
+            if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+                Some(self_data.read())
+            } else {
+                None
+            }
+        }
+    }
 }
 
 impl fmt::Write for MmioSerialPort {
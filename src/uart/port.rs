@@ -89,6 +89,16 @@ impl SerialPort {
             self.data.read()
         }
     }
+    /// Returns the next received byte, if there is one, without waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+                Some(self.data.read())
+            } else {
+                None
+            }
+        }
+    }
 }
 
 impl fmt::Write for SerialPort {
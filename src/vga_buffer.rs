@@ -18,6 +18,7 @@ use crate::INITIALISED;
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::Yellow, Color::Black),
+    reserved_rows: 0,
     buffer: 0xb8000 as *mut Buffer,
 });
 
@@ -65,9 +66,9 @@ struct ScreenChar {
 }
 
 /// The height of the text buffer (normally 25 lines).
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 /// The width of the text buffer (normally 80 columns).
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
 
 /// A structure representing the VGA text buffer.
 #[repr(transparent)]
@@ -78,10 +79,12 @@ struct Buffer {
 /// A writer type that allows writing ASCII bytes and strings to an underlying `Buffer`.
 ///
 /// Wraps lines at `BUFFER_WIDTH`. Supports newline characters and implements the
-/// `core::fmt::Write` trait.
+/// `core::fmt::Write` trait.  The top `reserved_rows` rows don't scroll, and are only written with
+/// `write_at`.
 pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
+    reserved_rows: usize,
     buffer: *mut Buffer,
 }
 
@@ -130,9 +133,41 @@ impl Writer {
         }
     }
 
-    /// Shifts all lines one line up and clears the last row.
+    /// Writes `s` starting at `row` and `col`, without moving the cursor or scrolling.  Anything
+    /// past the end of the row is cut off.
+    pub fn write_at(
+        &mut self,
+        row: usize,
+        col: usize,
+        s: &str,
+        foreground: Color,
+        background: Color,
+    ) {
+        let color_code = ColorCode::new(foreground, background);
+        for (col, byte) in (col..BUFFER_WIDTH).zip(s.bytes()) {
+            let ascii_character = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            unsafe {
+                (*self.buffer).chars[row][col].write(ScreenChar {
+                    ascii_character,
+                    color_code,
+                });
+            }
+        }
+    }
+
+    /// Keeps the top `rows` rows out of scrolling, e.g. for a status panel.  The rest of the
+    /// screen scrolls as before.
+    pub fn reserve_rows(&mut self, rows: usize) {
+        assert!(rows < BUFFER_HEIGHT, "can't reserve the whole screen");
+        self.reserved_rows = rows;
+    }
+
+    /// Shifts all unreserved lines one line up and clears the last row.
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        for row in self.reserved_rows + 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 unsafe {
                     let character = (*self.buffer).chars[row][col].read();
@@ -236,6 +271,7 @@ pub fn _eprint(args: fmt::Arguments) {
         let mut writer = Writer {
             column_position: 0,
             color_code: ColorCode::new(Color::Yellow, Color::Black),
+            reserved_rows: 0,
             buffer: 0xb8000 as *mut Buffer,
         };
         writer.color_code = ERR_COLOUR;
@@ -282,3 +318,23 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_reserved_rows_dont_scroll() {
+    use x86_64::instructions::interrupts;
+
+    let s = "reserved";
+    interrupts::without_interrupts(|| unsafe {
+        let mut writer = WRITER.lock();
+        writer.reserve_rows(1);
+        writer.write_at(0, 0, s, Color::White, Color::Blue);
+        for _ in 0..BUFFER_HEIGHT {
+            writer.write_string("scrolled\n");
+        }
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = (*writer.buffer).chars[0][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+        writer.reserve_rows(0);
+    });
+}